@@ -1,15 +1,32 @@
-/// Represents all values in rlox
-pub type Value = f64;
+use crate::object::ObjHeader;
+use crate::value::Value;
+use crate::vm::REGISTER_MAX;
+use alloc::{format, string::String, vec::Vec};
+use core::fmt::Write;
+use core::ptr::NonNull;
+use hashbrown::HashMap;
 
-/// ID of a constant. Used as index into the constant data section
-pub type ConstantId = u16;
+mod optimize;
+mod serialize;
+mod verify;
+pub use serialize::ChunkDecodeError;
+pub use verify::VerifyError;
+
+/// ID of a constant. Used as index into the constant data section. `LoadConst` can only encode the
+/// first `u16::MAX` ids, the remaining ones are loaded with `LoadConstWide`
+pub type ConstantId = u32;
+
+/// Combine the operands of a `LoadConstWide` and its trailing `ExtraArg` into a `ConstantId`
+pub fn wide_constant_id(hi: u16, lo: u16) -> ConstantId {
+    (hi as ConstantId) << 16 | lo as ConstantId
+}
 
 /// Register in the VM, represented as a `u8`
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct Register(u8);
 
 impl Register {
-    pub fn new(r: u8) -> Self {
+    pub const fn new(r: u8) -> Self {
         Register(r)
     }
 
@@ -17,13 +34,13 @@ impl Register {
         self.0 as usize
     }
 
-    pub fn ret() -> Self {
+    pub const fn ret() -> Self {
         Register(0)
     }
 }
 
-impl std::fmt::Display for Register {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for Register {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(f, "%r{}", self.0)
     }
 }
@@ -31,11 +48,18 @@ impl std::fmt::Display for Register {
 /// Bytecode instruction for rlox VM
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BcInstr {
-    Ret,
+    /// Return `src` to the caller, or finish the program when returning from the top-level script
+    Ret {
+        src: Register,
+    },
     Neg {
         dest: Register,
         a: Register,
     },
+    Not {
+        dest: Register,
+        a: Register,
+    },
     Add {
         dest: Register,
         a: Register,
@@ -56,133 +80,1437 @@ pub enum BcInstr {
         a: Register,
         b: Register,
     },
+    Mod {
+        dest: Register,
+        a: Register,
+        b: Register,
+    },
     LoadConst {
         dest: Register,
+        id: u16,
+    },
+    /// Load the constant whose id has `hi` as its upper 16 bits. Always followed by an `ExtraArg`
+    /// holding the lower 16 bits of the id
+    LoadConstWide {
+        dest: Register,
+        hi: u16,
+    },
+    /// Extra operand of the preceding instruction. Never executed on its own
+    ExtraArg {
+        lo: u16,
+    },
+    Move {
+        dest: Register,
+        src: Register,
+    },
+    LoadTrue {
+        dest: Register,
+    },
+    LoadFalse {
+        dest: Register,
+    },
+    LoadNil {
+        dest: Register,
+    },
+    Print {
+        src: Register,
+    },
+    /// Jump `offset` instructions relative to the instruction following the jump
+    Jump {
+        offset: i16,
+    },
+    /// Jump `offset` instructions relative to the instruction following the jump if `cond` is
+    /// falsey
+    JumpIfFalse {
+        cond: Register,
+        offset: i16,
+    },
+    /// Jump `offset` instructions backwards relative to the instruction following the loop
+    Loop {
+        offset: u16,
+    },
+    Eq {
+        dest: Register,
+        a: Register,
+        b: Register,
+    },
+    Ne {
+        dest: Register,
+        a: Register,
+        b: Register,
+    },
+    Lt {
+        dest: Register,
+        a: Register,
+        b: Register,
+    },
+    Le {
+        dest: Register,
+        a: Register,
+        b: Register,
+    },
+    Gt {
+        dest: Register,
+        a: Register,
+        b: Register,
+    },
+    Ge {
+        dest: Register,
+        a: Register,
+        b: Register,
+    },
+    /// Call the function in `callee` with the `arg_count` arguments in the registers following it.
+    /// The registers of the callee start at `callee`, so it finds itself in `%r0` and its arguments
+    /// from `%r1`, and every register of the caller after `callee` is clobbered. The returned value
+    /// replaces the function in `callee`
+    Call {
+        callee: Register,
+        arg_count: u8,
+    },
+    /// Create or overwrite the global variable named by the string constant `name`. Like
+    /// `LoadConst`, only the first `u16::MAX` constants can be used as names
+    DefineGlobal {
+        name: u16,
+        src: Register,
+    },
+    /// Read the global variable named by the string constant `name`, which must be defined
+    GetGlobal {
+        dest: Register,
+        name: u16,
+    },
+    /// Assign to the global variable named by the string constant `name`, which must be defined
+    SetGlobal {
+        name: u16,
+        src: Register,
+    },
+    /// `Add` of `a` and the constant `id`, fusing a `LoadConst` into the addition following it.
+    /// Only the first 256 constants fit, to keep instructions within 4 bytes
+    AddConst {
+        dest: Register,
+        a: Register,
+        id: u8,
+    },
+    /// Save `src` in the spill slot `slot` of the running function, so the register can hold
+    /// another value. Used by expressions needing more than `REGISTER_MAX` registers at once
+    Spill {
+        slot: u16,
+        src: Register,
+    },
+    /// Load the value saved in the spill slot `slot` of the running function into `dest`
+    Unspill {
+        dest: Register,
+        slot: u16,
+    },
+    /// Add 1 to `dest` in place. Integers stay integers, failing on overflow, as in `Add`
+    Inc {
+        dest: Register,
+    },
+    /// Subtract 1 from `dest` in place. Integers stay integers, failing on overflow, as in `Sub`
+    Dec {
+        dest: Register,
+    },
+    /// Bitwise and of the integers `a` and `b`. Numbers can't be operands of bitwise instructions,
+    /// even when they hold a whole value
+    BitAnd {
+        dest: Register,
+        a: Register,
+        b: Register,
+    },
+    BitOr {
+        dest: Register,
+        a: Register,
+        b: Register,
+    },
+    BitXor {
+        dest: Register,
+        a: Register,
+        b: Register,
+    },
+    /// Shift the integer `a` left by `b` bits. Shifting by less than 0 or more than 63 bits is an
+    /// error rather than masked, as is shifting bits out of the range of integers
+    Shl {
+        dest: Register,
+        a: Register,
+        b: Register,
+    },
+    /// Arithmetic shift of the integer `a` right by `b` bits, keeping its sign. Shifting by less
+    /// than 0 or more than 63 bits is an error, as for `Shl`
+    Shr {
+        dest: Register,
+        a: Register,
+        b: Register,
+    },
+    /// Raise `a` to the power `b`. Always produces a number, even between two integers
+    Pow {
+        dest: Register,
+        a: Register,
+        b: Register,
+    },
+    /// Add the product of `a` and `b` to `dest`, rounding only once. Unlike a `{ dest, a, b, c }`
+    /// form with an addend of its own, the addend is deliberately `dest` itself, as a fourth
+    /// register wouldn't fit in 4 bytes. Integer products are added as in `Add` to an integer, and
+    /// only rounded along with the sum when added to a float
+    MulAdd {
+        dest: Register,
+        a: Register,
+        b: Register,
+    },
+}
+
+/// Error returned when a jump target is further away than a jump offset can encode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JumpTooFar {
+    pub distance: usize,
+}
+
+/// Error returned by `Chunk::append` when the code of the appended chunk can't use the ids its
+/// constants get in the merged pool. Carries the offset of the offending instruction in the
+/// appended chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppendError {
+    /// The name of a global is past the first 65536 constants, which is all its operand can refer
+    /// to
+    GlobalNameOutOfRange { offset: usize, id: ConstantId },
+    /// The constant of an `AddConst` is past the first 256 constants, and no register is free to
+    /// load it into for an `Add` instead
+    NoFreeRegister { offset: usize },
+}
+
+/// How `Chunk::append` writes an instruction of the appended chunk
+#[derive(Debug, Clone, Copy)]
+enum AppendedInstr {
+    /// A load of the constant `id` of the merged pool, in the narrow or wide form
+    Load { dest: Register, id: ConstantId },
+    /// The `ExtraArg` of a wide load, written along with the load
+    Operand,
+    /// An `AddConst` whose constant no longer fits in its operand, split back into a load of the
+    /// constant into `scratch` and an `Add`
+    Unfused {
+        dest: Register,
+        a: Register,
+        scratch: Register,
         id: ConstantId,
     },
+    /// The instruction, with its constant operand remapped
+    Copy(BcInstr),
+}
+
+impl AppendedInstr {
+    /// Number of instructions written
+    fn len(&self) -> usize {
+        match *self {
+            AppendedInstr::Load { id, .. } if id > u16::MAX as ConstantId => 2,
+            AppendedInstr::Load { .. } | AppendedInstr::Copy(_) => 1,
+            AppendedInstr::Unfused { scratch, id, .. } => {
+                AppendedInstr::Load { dest: scratch, id }.len() + 1
+            }
+            AppendedInstr::Operand => 0,
+        }
+    }
+}
+
+/// Fixed-capacity list of the registers an instruction reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterList {
+    regs: [Register; RegisterList::CAPACITY],
+    len: u8,
+}
+
+impl RegisterList {
+    pub const CAPACITY: usize = REGISTER_MAX;
+
+    fn new(regs: &[Register]) -> Self {
+        let mut list = RegisterList {
+            regs: [Register::ret(); RegisterList::CAPACITY],
+            len: regs.len() as u8,
+        };
+        list.regs[..regs.len()].copy_from_slice(regs);
+        list
+    }
+
+    /// List of `count` consecutive registers starting at `first`, truncated to the capacity
+    fn range(first: Register, count: usize) -> Self {
+        let mut list = RegisterList::new(&[]);
+        for (i, r) in (first.0..=u8::MAX)
+            .take(count.min(Self::CAPACITY))
+            .enumerate()
+        {
+            list.regs[i] = Register(r);
+            list.len += 1;
+        }
+        list
+    }
+}
+
+impl core::ops::Deref for RegisterList {
+    type Target = [Register];
+
+    fn deref(&self) -> &[Register] {
+        &self.regs[..self.len as usize]
+    }
+}
+
+impl BcInstr {
+    /// Number of kinds of instructions
+    pub const COUNT: usize = 41;
+
+    /// Name of each kind of instruction, indexed by `opcode`
+    pub const NAMES: [&'static str; BcInstr::COUNT] = [
+        "Ret",
+        "Neg",
+        "Not",
+        "Add",
+        "Sub",
+        "Mul",
+        "Div",
+        "Mod",
+        "LoadConst",
+        "LoadConstWide",
+        "ExtraArg",
+        "Move",
+        "LoadTrue",
+        "LoadFalse",
+        "LoadNil",
+        "Print",
+        "Jump",
+        "JumpIfFalse",
+        "Loop",
+        "Eq",
+        "Ne",
+        "Lt",
+        "Le",
+        "Gt",
+        "Ge",
+        "Call",
+        "DefineGlobal",
+        "GetGlobal",
+        "SetGlobal",
+        "AddConst",
+        "Spill",
+        "Unspill",
+        "Inc",
+        "Dec",
+        "BitAnd",
+        "BitOr",
+        "BitXor",
+        "Shl",
+        "Shr",
+        "Pow",
+        "MulAdd",
+    ];
+
+    /// Index of the kind of this instruction, in the order the variants are declared
+    pub fn opcode(&self) -> usize {
+        match self {
+            BcInstr::Ret { .. } => 0,
+            BcInstr::Neg { .. } => 1,
+            BcInstr::Not { .. } => 2,
+            BcInstr::Add { .. } => 3,
+            BcInstr::Sub { .. } => 4,
+            BcInstr::Mul { .. } => 5,
+            BcInstr::Div { .. } => 6,
+            BcInstr::Mod { .. } => 7,
+            BcInstr::LoadConst { .. } => 8,
+            BcInstr::LoadConstWide { .. } => 9,
+            BcInstr::ExtraArg { .. } => 10,
+            BcInstr::Move { .. } => 11,
+            BcInstr::LoadTrue { .. } => 12,
+            BcInstr::LoadFalse { .. } => 13,
+            BcInstr::LoadNil { .. } => 14,
+            BcInstr::Print { .. } => 15,
+            BcInstr::Jump { .. } => 16,
+            BcInstr::JumpIfFalse { .. } => 17,
+            BcInstr::Loop { .. } => 18,
+            BcInstr::Eq { .. } => 19,
+            BcInstr::Ne { .. } => 20,
+            BcInstr::Lt { .. } => 21,
+            BcInstr::Le { .. } => 22,
+            BcInstr::Gt { .. } => 23,
+            BcInstr::Ge { .. } => 24,
+            BcInstr::Call { .. } => 25,
+            BcInstr::DefineGlobal { .. } => 26,
+            BcInstr::GetGlobal { .. } => 27,
+            BcInstr::SetGlobal { .. } => 28,
+            BcInstr::AddConst { .. } => 29,
+            BcInstr::Spill { .. } => 30,
+            BcInstr::Unspill { .. } => 31,
+            BcInstr::Inc { .. } => 32,
+            BcInstr::Dec { .. } => 33,
+            BcInstr::BitAnd { .. } => 34,
+            BcInstr::BitOr { .. } => 35,
+            BcInstr::BitXor { .. } => 36,
+            BcInstr::Shl { .. } => 37,
+            BcInstr::Shr { .. } => 38,
+            BcInstr::Pow { .. } => 39,
+            BcInstr::MulAdd { .. } => 40,
+        }
+    }
+
+    /// The register written by this instruction, if any
+    pub fn defs(&self) -> Option<Register> {
+        match *self {
+            BcInstr::Neg { dest, .. }
+            | BcInstr::Not { dest, .. }
+            | BcInstr::Add { dest, .. }
+            | BcInstr::AddConst { dest, .. }
+            | BcInstr::Sub { dest, .. }
+            | BcInstr::Mul { dest, .. }
+            | BcInstr::Div { dest, .. }
+            | BcInstr::Mod { dest, .. }
+            | BcInstr::BitAnd { dest, .. }
+            | BcInstr::BitOr { dest, .. }
+            | BcInstr::BitXor { dest, .. }
+            | BcInstr::Shl { dest, .. }
+            | BcInstr::Shr { dest, .. }
+            | BcInstr::Pow { dest, .. }
+            | BcInstr::MulAdd { dest, .. }
+            | BcInstr::LoadConst { dest, .. }
+            | BcInstr::LoadConstWide { dest, .. }
+            | BcInstr::Move { dest, .. }
+            | BcInstr::LoadTrue { dest }
+            | BcInstr::LoadFalse { dest }
+            | BcInstr::LoadNil { dest }
+            | BcInstr::Eq { dest, .. }
+            | BcInstr::Ne { dest, .. }
+            | BcInstr::Lt { dest, .. }
+            | BcInstr::Le { dest, .. }
+            | BcInstr::Gt { dest, .. }
+            | BcInstr::Ge { dest, .. }
+            | BcInstr::Unspill { dest, .. }
+            | BcInstr::Inc { dest }
+            | BcInstr::Dec { dest } => Some(dest),
+            BcInstr::Call { callee, .. } => Some(callee),
+            BcInstr::GetGlobal { dest, .. } => Some(dest),
+            BcInstr::Ret { .. }
+            | BcInstr::DefineGlobal { .. }
+            | BcInstr::SetGlobal { .. }
+            | BcInstr::ExtraArg { .. }
+            | BcInstr::Print { .. }
+            | BcInstr::Spill { .. }
+            | BcInstr::Jump { .. }
+            | BcInstr::JumpIfFalse { .. }
+            | BcInstr::Loop { .. } => None,
+        }
+    }
+
+    /// The offset of the jump target relative to the next instruction, if this is a jump
+    pub fn jump_offset(&self) -> Option<isize> {
+        match *self {
+            BcInstr::Jump { offset } | BcInstr::JumpIfFalse { offset, .. } => Some(offset as isize),
+            BcInstr::Loop { offset } => Some(-(offset as isize)),
+            _ => None,
+        }
+    }
+
+    /// The constant used as an operand by this instruction, if any: the name of the global
+    /// variable it accesses, or the constant it adds. Constant loads are not included
+    pub fn constant_operand(&self) -> Option<ConstantId> {
+        match *self {
+            BcInstr::DefineGlobal { name, .. }
+            | BcInstr::GetGlobal { name, .. }
+            | BcInstr::SetGlobal { name, .. } => Some(name as ConstantId),
+            BcInstr::AddConst { id, .. } => Some(id as ConstantId),
+            _ => None,
+        }
+    }
+
+    /// The registers read by this instruction
+    pub fn uses(&self) -> RegisterList {
+        match *self {
+            BcInstr::Ret { src } => RegisterList::new(&[src]),
+            BcInstr::Neg { a, .. } | BcInstr::Not { a, .. } | BcInstr::AddConst { a, .. } => {
+                RegisterList::new(&[a])
+            }
+            BcInstr::Add { a, b, .. }
+            | BcInstr::Sub { a, b, .. }
+            | BcInstr::Mul { a, b, .. }
+            | BcInstr::Div { a, b, .. }
+            | BcInstr::Mod { a, b, .. }
+            | BcInstr::BitAnd { a, b, .. }
+            | BcInstr::BitOr { a, b, .. }
+            | BcInstr::BitXor { a, b, .. }
+            | BcInstr::Shl { a, b, .. }
+            | BcInstr::Shr { a, b, .. }
+            | BcInstr::Pow { a, b, .. }
+            | BcInstr::Eq { a, b, .. }
+            | BcInstr::Ne { a, b, .. }
+            | BcInstr::Lt { a, b, .. }
+            | BcInstr::Le { a, b, .. }
+            | BcInstr::Gt { a, b, .. }
+            | BcInstr::Ge { a, b, .. } => RegisterList::new(&[a, b]),
+            BcInstr::Move { src, .. }
+            | BcInstr::Print { src }
+            | BcInstr::DefineGlobal { src, .. }
+            | BcInstr::SetGlobal { src, .. }
+            | BcInstr::Spill { src, .. } => RegisterList::new(&[src]),
+            BcInstr::Inc { dest } | BcInstr::Dec { dest } => RegisterList::new(&[dest]),
+            BcInstr::MulAdd { dest, a, b } => RegisterList::new(&[a, b, dest]),
+            BcInstr::JumpIfFalse { cond, .. } => RegisterList::new(&[cond]),
+            BcInstr::Call { callee, arg_count } => {
+                RegisterList::range(callee, arg_count as usize + 1)
+            }
+            BcInstr::LoadConst { .. }
+            | BcInstr::LoadConstWide { .. }
+            | BcInstr::ExtraArg { .. }
+            | BcInstr::LoadTrue { .. }
+            | BcInstr::LoadFalse { .. }
+            | BcInstr::LoadNil { .. }
+            | BcInstr::GetGlobal { .. }
+            | BcInstr::Unspill { .. }
+            | BcInstr::Jump { .. }
+            | BcInstr::Loop { .. } => RegisterList::new(&[]),
+        }
+    }
 }
 
-/// Representation of line numbers using an RLE encoding
+/// Run of consecutive instructions sharing the same source position
 #[derive(Debug)]
-struct RLELine {
-    line: usize,
-    count: usize,
+struct RLERun {
+    value: usize,
+    end: usize,
+}
+
+/// Representation of source positions (lines or columns) using an RLE encoding. Each run stores the
+/// index one past its last instruction rather than its length so lookups can binary search the runs
+#[derive(Debug, Default)]
+struct RLETable {
+    runs: Vec<RLERun>,
+}
+
+impl RLETable {
+    /// Record the position of the next instruction
+    fn push(&mut self, value: usize) {
+        match self.runs.last_mut() {
+            Some(run) if run.value == value => run.end += 1,
+            last => {
+                let end = last.map_or(0, |run| run.end) + 1;
+                self.runs.push(RLERun { value, end });
+            }
+        }
+    }
+
+    fn get(&self, instr_index: usize) -> Option<usize> {
+        let run = self.runs.partition_point(|run| run.end <= instr_index);
+        self.runs.get(run).map(|run| run.value)
+    }
+
+    /// Iterate over each run as the range of instructions it covers and its value
+    fn iter(&self) -> impl Iterator<Item = (core::ops::Range<usize>, usize)> + '_ {
+        self.runs.iter().scan(0, |start, run| {
+            let range = *start..run.end;
+            *start = run.end;
+            Some((range, run.value))
+        })
+    }
+
+    fn clear(&mut self) {
+        self.runs.clear();
+    }
+}
+
+/// Key deduplicating entries in the constant pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    /// Numbers are keyed by their bits rather than `==` so that -0.0 is kept distinct from 0.0 and
+    /// identical NaNs share a single entry
+    Number(u64),
+    Int(i64),
+    /// Objects are shared by identity
+    Obj(usize),
+}
+
+/// Entry of the constant pool. Strings are kept by their bytes in a section of their own, rather
+/// than as objects, so they don't depend on the heap the chunk is loaded into
+#[derive(Debug, Clone, Copy)]
+enum Constant {
+    Value(Value),
+    /// Index into the string section
+    String(u32),
+}
+
+impl ConstantKey {
+    /// The key of `v`, or `None` if it can't be stored in the constant pool
+    fn of(v: &Value) -> Option<Self> {
+        match *v {
+            Value::Number(n) => Some(ConstantKey::Number(n.to_bits())),
+            Value::Int(n) => Some(ConstantKey::Int(n)),
+            Value::Obj(obj) => Some(ConstantKey::Obj(obj.as_ptr() as usize)),
+            _ => None,
+        }
+    }
 }
 
 pub struct Chunk {
     code: Vec<BcInstr>,
-    lines: Vec<RLELine>,
-    constants: Vec<Value>,
+    lines: RLETable,
+    columns: RLETable,
+    /// Range of bytes of the source each instruction was compiled from, by offset. Only the
+    /// instructions given a span have an entry, sorted by offset
+    spans: Vec<(usize, (usize, usize))>,
+    constants: Vec<Constant>,
+    /// Contents of the string constants, referenced by the `Constant::String` entries of the pool
+    strings: Vec<String>,
+
+    /// Index into `constants` so `add_constant` doesn't have to scan the whole pool
+    constant_ids: HashMap<ConstantKey, ConstantId>,
+    /// Index into `constants` of every string, so equal strings share a single entry
+    string_ids: HashMap<String, ConstantId>,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Chunk {
     pub fn new() -> Self {
         Chunk {
             code: Vec::new(),
-            lines: Vec::new(),
+            lines: RLETable::default(),
+            columns: RLETable::default(),
+            spans: Vec::new(),
             constants: Vec::new(),
+            strings: Vec::new(),
+            constant_ids: HashMap::new(),
+            string_ids: HashMap::new(),
         }
     }
 
+    /// Create an empty chunk with room for `code` instructions and `constants` constants, for
+    /// writers that know roughly how much they will write
+    pub fn with_capacity(code: usize, constants: usize) -> Self {
+        let mut chunk = Chunk::new();
+        chunk.code.reserve(code);
+        chunk.constants.reserve(constants);
+        chunk
+    }
+
+    /// Make room for at least `additional` more instructions
+    pub fn reserve(&mut self, additional: usize) {
+        self.code.reserve(additional);
+    }
+
+    /// Add `v` to the constant pool, returning its id. Only numbers and objects can be stored in
+    /// the constant pool for now
     pub fn add_constant(&mut self, v: Value) -> ConstantId {
-        (if let Some((i, _)) = self.constants.iter().enumerate().find(|(_, &c)| c == v) {
-            i
-        } else {
-            self.constants.push(v);
-            self.constants.len() - 1
-        }) as ConstantId
+        let key = ConstantKey::of(&v).unwrap_or_else(|| {
+            panic!(
+                "Only numbers and objects can be stored in the constant pool, got {:?}",
+                v
+            )
+        });
+
+        if let Some(&id) = self.constant_ids.get(&key) {
+            return id;
+        }
+
+        let id = self.push_constant(Constant::Value(v));
+        self.constant_ids.insert(key, id);
+        id
+    }
+
+    /// Add the string `s` to the string section of the constant pool, returning its id. Loading
+    /// the constant allocates the string on the heap of the VM
+    pub fn add_string(&mut self, s: &str) -> ConstantId {
+        if let Some(&id) = self.string_ids.get(s) {
+            return id;
+        }
+
+        let index = u32::try_from(self.strings.len()).expect("String section is full!");
+        self.strings.push(s.into());
+        let id = self.push_constant(Constant::String(index));
+        self.string_ids.insert(s.into(), id);
+        id
+    }
+
+    fn push_constant(&mut self, constant: Constant) -> ConstantId {
+        let id = ConstantId::try_from(self.constants.len()).expect("Constant pool is full!");
+        self.constants.push(constant);
+        id
+    }
+
+    /// Add the constant `id` of `other` to this chunk's pool, returning its new id
+    fn copy_constant(&mut self, other: &Chunk, id: ConstantId) -> Option<ConstantId> {
+        match *other.constants.get(id as usize)? {
+            Constant::Value(v) => Some(self.add_constant(v)),
+            Constant::String(index) => Some(self.add_string(&other.strings[index as usize])),
+        }
+    }
+
+    /// Write the instructions loading the constant `id` into `dest`, using the wide form if the id
+    /// does not fit in a `LoadConst`
+    pub fn write_load_const(&mut self, dest: Register, id: ConstantId, line: usize) {
+        self.write_load_const_at(dest, id, line, 0);
+    }
+
+    fn write_load_const_at(&mut self, dest: Register, id: ConstantId, line: usize, column: usize) {
+        match u16::try_from(id) {
+            Ok(id) => self.write_at(BcInstr::LoadConst { dest, id }, line, column),
+            Err(_) => {
+                let hi = (id >> 16) as u16;
+                self.write_at(BcInstr::LoadConstWide { dest, hi }, line, column);
+                self.write_at(BcInstr::ExtraArg { lo: id as u16 }, line, column);
+            }
+        }
     }
 
+    /// Copy the code of `other` after the end of this chunk, merging its constants into this
+    /// chunk's pool. Constant loads may switch between the narrow and wide forms as their ids are
+    /// remapped, so jumps within `other` are adjusted to land on the same instructions. Fails
+    /// without changing this chunk if a remapped id doesn't fit in the instruction using it
+    pub fn append(&mut self, other: &Chunk) -> Result<(), AppendError> {
+        let pool_len = (self.constants.len(), self.strings.len());
+        let appended = match self.remap_constants(other) {
+            Ok(appended) => appended,
+            Err(err) => {
+                self.truncate_pool(pool_len);
+                return Err(err);
+            }
+        };
+
+        // Where each instruction of `other` ends up
+        let mut new_index = Vec::with_capacity(other.code.len() + 1);
+        let mut next = self.code.len();
+        for instr in &appended {
+            new_index.push(next);
+            next += instr.len();
+        }
+        new_index.push(next);
+        for &(offset, span) in &other.spans {
+            self.set_span(new_index[offset], span);
+        }
+
+        for (offset, instr) in appended.into_iter().enumerate() {
+            let (line, column) = (other.get_line(offset), other.get_column(offset));
+            let mut instr = match instr {
+                AppendedInstr::Load { dest, id } => {
+                    self.write_load_const_at(dest, id, line, column);
+                    continue;
+                }
+                AppendedInstr::Unfused {
+                    dest,
+                    a,
+                    scratch,
+                    id,
+                } => {
+                    self.write_load_const_at(scratch, id, line, column);
+                    let add = BcInstr::Add {
+                        dest,
+                        a,
+                        b: scratch,
+                    };
+                    self.write_at(add, line, column);
+                    continue;
+                }
+                AppendedInstr::Operand => continue,
+                AppendedInstr::Copy(instr) => instr,
+            };
+
+            let target = instr
+                .jump_offset()
+                .and_then(|jump| (offset + 1).checked_add_signed(jump))
+                .and_then(|target| new_index.get(target));
+            if let Some(&target) = target {
+                let jump = target as isize - (new_index[offset] as isize + 1);
+                match &mut instr {
+                    BcInstr::Jump { offset } | BcInstr::JumpIfFalse { offset, .. } => {
+                        *offset = jump as i16
+                    }
+                    BcInstr::Loop { offset } => *offset = -jump as u16,
+                    _ => unreachable!(),
+                }
+            }
+            self.write_at(instr, line, column);
+        }
+        Ok(())
+    }
+
+    /// Merge the constants used by the code of `other` into this chunk's pool, returning how
+    /// each instruction of `other` is rewritten to use the remapped ids
+    fn remap_constants(&mut self, other: &Chunk) -> Result<Vec<AppendedInstr>, AppendError> {
+        let leaders = other.block_leaders();
+        let mut appended: Vec<AppendedInstr> = Vec::with_capacity(other.code.len());
+        for (offset, &instr) in other.code.iter().enumerate() {
+            let load = match instr {
+                BcInstr::LoadConst { id, .. } => Some(id as ConstantId),
+                BcInstr::LoadConstWide { hi, .. } => match other.code.get(offset + 1) {
+                    Some(&BcInstr::ExtraArg { lo }) => Some(wide_constant_id(hi, lo)),
+                    _ => None,
+                },
+                // The operand of a wide load is re-emitted along with the load itself
+                BcInstr::ExtraArg { .. }
+                    if offset > 0
+                        && matches!(other.code[offset - 1], BcInstr::LoadConstWide { .. })
+                        && matches!(appended[offset - 1], AppendedInstr::Load { .. }) =>
+                {
+                    appended.push(AppendedInstr::Operand);
+                    continue;
+                }
+                _ => None,
+            };
+
+            // Loads of constants missing from `other` are copied as-is
+            if let Some(id) = load.and_then(|id| self.copy_constant(other, id)) {
+                let dest = instr.defs().unwrap();
+                appended.push(AppendedInstr::Load { dest, id });
+                continue;
+            }
+
+            let mut instr = instr;
+            let operand = instr.constant_operand();
+            if let Some(id) = operand.and_then(|id| self.copy_constant(other, id)) {
+                match &mut instr {
+                    BcInstr::DefineGlobal { name, .. }
+                    | BcInstr::GetGlobal { name, .. }
+                    | BcInstr::SetGlobal { name, .. } => {
+                        *name = u16::try_from(id)
+                            .map_err(|_| AppendError::GlobalNameOutOfRange { offset, id })?
+                    }
+                    BcInstr::AddConst {
+                        dest,
+                        a,
+                        id: operand,
+                    } => match u8::try_from(id) {
+                        Ok(id) => *operand = id,
+                        Err(_) => {
+                            let (dest, a) = (*dest, *a);
+                            let scratch = other
+                                .free_register(dest, a, offset, &leaders)
+                                .ok_or(AppendError::NoFreeRegister { offset })?;
+                            appended.push(AppendedInstr::Unfused {
+                                dest,
+                                a,
+                                scratch,
+                                id,
+                            });
+                            continue;
+                        }
+                    },
+                    _ => unreachable!(),
+                }
+            }
+            appended.push(AppendedInstr::Copy(instr));
+        }
+        Ok(appended)
+    }
+
+    /// Remove the constants added to the pool after it had `constants` entries and `strings`
+    /// strings
+    fn truncate_pool(&mut self, (constants, strings): (usize, usize)) {
+        self.constants.truncate(constants);
+        self.strings.truncate(strings);
+        self.constant_ids.retain(|_, id| (*id as usize) < constants);
+        self.string_ids.retain(|_, id| (*id as usize) < constants);
+    }
+
+    /// Write `instr` without column information
     pub fn write(&mut self, instr: BcInstr, line: usize) {
+        self.write_at(instr, line, 0);
+    }
+
+    /// Write `instr`, recording the line and column it was compiled from. Columns start at 1, with
+    /// 0 meaning the column is unknown
+    pub fn write_at(&mut self, instr: BcInstr, line: usize, column: usize) {
         self.code.push(instr);
-        if let Some(rle_line) = self.lines.last_mut() {
-            if rle_line.line == line {
-                rle_line.count += 1;
-                return;
+        self.lines.push(line);
+        self.columns.push(column);
+    }
+
+    /// Write a forward jump whose target is not known yet, returning its index so it can later be
+    /// passed to `patch_jump`
+    pub fn emit_jump(&mut self, instr: BcInstr, line: usize) -> usize {
+        assert!(
+            matches!(instr, BcInstr::Jump { .. } | BcInstr::JumpIfFalse { .. }),
+            "Only jumps can be patched, got {:?}",
+            instr
+        );
+
+        self.write(instr, line);
+        self.code.len() - 1
+    }
+
+    /// Point the jump at `jump_index` to the current end of the code, so it lands on the next
+    /// instruction written
+    pub fn patch_jump(&mut self, jump_index: usize) -> Result<(), JumpTooFar> {
+        let distance = self.code.len() - (jump_index + 1);
+        let distance_offset = i16::try_from(distance).map_err(|_| JumpTooFar { distance })?;
+
+        match &mut self.code[jump_index] {
+            BcInstr::Jump { offset } | BcInstr::JumpIfFalse { offset, .. } => {
+                *offset = distance_offset;
+                Ok(())
             }
+            instr => panic!("Instruction at {} is not a jump: {:?}", jump_index, instr),
         }
+    }
 
-        self.lines.push(RLELine { line, count: 1 });
+    /// Replace the last instruction written with `instr`, which keeps the line, column and span the
+    /// last one was compiled from
+    pub fn patch_last(&mut self, instr: BcInstr) {
+        *self.code.last_mut().expect("No instruction to patch") = instr;
     }
 
     pub fn clear(&mut self) {
         self.code.clear();
         self.lines.clear();
+        self.columns.clear();
+        self.spans.clear();
     }
 
     pub fn get_line(&self, instr_index: usize) -> usize {
-        let mut line_count = 0;
-        for RLELine { line, count } in &self.lines {
-            line_count += count;
+        match self.lines.get(instr_index) {
+            Some(line) => line,
+            None => panic!("Inconsistency in source lines! {:?}", self.lines),
+        }
+    }
 
-            if line_count > instr_index {
-                return *line;
-            }
+    /// Return the column the instruction was compiled from, or 0 if it is unknown
+    pub fn get_column(&self, instr_index: usize) -> usize {
+        match self.columns.get(instr_index) {
+            Some(column) => column,
+            None => panic!("Inconsistency in source columns! {:?}", self.columns),
         }
-        panic!("Inconsistency in source lines! {:?}", self.lines);
+    }
+
+    /// Record that the instruction at `offset` was compiled from the bytes `start..end` of the
+    /// source
+    pub fn set_span(&mut self, offset: usize, (start, end): (usize, usize)) {
+        match self
+            .spans
+            .binary_search_by_key(&offset, |&(offset, _)| offset)
+        {
+            Ok(entry) => self.spans[entry].1 = (start, end),
+            Err(entry) => self.spans.insert(entry, (offset, (start, end))),
+        }
+    }
+
+    /// Range of bytes of the source the instruction at `offset` was compiled from, if it is known.
+    /// Finer than the line, for highlighting the expression an instruction belongs to
+    pub fn span(&self, offset: usize) -> Option<(usize, usize)> {
+        self.spans
+            .binary_search_by_key(&offset, |&(offset, _)| offset)
+            .ok()
+            .map(|entry| self.spans[entry].1)
+    }
+
+    /// Iterate over each instruction along with its offset and source line. Walks the line table
+    /// alongside the code rather than looking up each line separately
+    pub fn iter_with_lines(&self) -> impl Iterator<Item = (usize, &BcInstr, usize)> {
+        self.lines
+            .iter()
+            .flat_map(move |(run, line)| run.map(move |offset| (offset, &self.code[offset], line)))
     }
 
     pub fn instrs(&self) -> &[BcInstr] {
         &self.code
     }
 
-    pub fn constant(&self, index: ConstantId) -> Value {
-        self.constants[index as usize]
+    /// Returns the constant with id `index`, or `None` if it is not in the constant pool or is a
+    /// string, which are read with `string`
+    pub fn constant(&self, index: ConstantId) -> Option<Value> {
+        match self.constants.get(index as usize)? {
+            Constant::Value(v) => Some(*v),
+            Constant::String(_) => None,
+        }
+    }
+
+    /// Returns the contents of the string constant with id `index`, or `None` if it is not a
+    /// string in the constant pool
+    pub fn string(&self, index: ConstantId) -> Option<&str> {
+        match self.constants.get(index as usize)? {
+            Constant::String(string) => Some(&self.strings[*string as usize]),
+            Constant::Value(_) => None,
+        }
+    }
+
+    /// Pass every object in the constant pool to `visit`, replacing it with the object returned,
+    /// as the collector may move objects
+    pub(crate) fn trace_constants(
+        &mut self,
+        mut visit: impl FnMut(NonNull<ObjHeader>) -> NonNull<ObjHeader>,
+    ) {
+        for (id, constant) in self.constants.iter_mut().enumerate() {
+            if let Constant::Value(Value::Obj(obj)) = constant {
+                let moved = visit(*obj);
+                if moved != *obj {
+                    // Objects are shared by identity, so they are found by their new address
+                    self.constant_ids
+                        .remove(&ConstantKey::Obj(obj.as_ptr() as usize));
+                    self.constant_ids
+                        .insert(ConstantKey::Obj(moved.as_ptr() as usize), id as ConstantId);
+                    *obj = moved;
+                }
+            }
+        }
     }
 
-    #[cfg(debug_assertions)]
+    /// Return the full listing of the chunk: every instruction with its source line, followed by
+    /// the constant pool
+    pub fn disassemble(&self) -> String {
+        let mut out = String::from("== CHUNK ==\n");
+        for offset in 0..self.code.len() {
+            let _ = writeln!(
+                out,
+                "{:>4} {}",
+                self.get_line(offset),
+                self.dump_instr(offset)
+            );
+        }
+
+        out.push_str("-- DATA  --\n");
+        for id in 0..self.constants.len() {
+            let _ = writeln!(out, "0x{:X} {}", id, self.dump_constant(id as ConstantId));
+        }
+        out.push_str("=== END ===\n");
+        out
+    }
+
+    fn dump_constant(&self, id: ConstantId) -> String {
+        match self.constants.get(id as usize) {
+            Some(Constant::Value(constant)) => format!("{:?}", constant),
+            Some(Constant::String(index)) => format!("{:?}", self.strings[*index as usize]),
+            None => format!("<invalid constant 0x{:X}>", id),
+        }
+    }
+
+    /// Disassemble the instruction at `offset`
     pub fn dump_instr(&self, offset: usize) -> String {
         let s = match &self.code[offset] {
-            BcInstr::Ret => format!("RET {}", Register::ret()),
-            BcInstr::LoadConst { dest, id } => format!("LOAD {} <= {}", dest, self.constant(*id)),
+            BcInstr::Ret { src } => format!("RET {}", src),
+            BcInstr::LoadConst { dest, id } => {
+                format!("LOAD {} <= {}", dest, self.dump_constant(*id as ConstantId))
+            }
+            BcInstr::LoadConstWide { dest, hi } => match self.code.get(offset + 1) {
+                Some(BcInstr::ExtraArg { lo }) => format!(
+                    "LOADW {} <= {}",
+                    dest,
+                    self.dump_constant(wide_constant_id(*hi, *lo))
+                ),
+                _ => format!("LOADW {} <= <missing EXTRA>", dest),
+            },
+            BcInstr::ExtraArg { lo } => format!("EXTRA 0x{:X}", lo),
+            BcInstr::Move { dest, src } => format!("MOV {} <= {}", dest, src),
+            BcInstr::LoadTrue { dest } => format!("LOAD {} <= true", dest),
+            BcInstr::LoadFalse { dest } => format!("LOAD {} <= false", dest),
+            BcInstr::LoadNil { dest } => format!("LOAD {} <= nil", dest),
+            BcInstr::Print { src } => format!("PRINT {}", src),
+            BcInstr::Spill { slot, src } => format!("SPILL [{}] <= {}", slot, src),
+            BcInstr::Unspill { dest, slot } => format!("UNSPILL {} <= [{}]", dest, slot),
+            BcInstr::Jump { offset } => format!("JMP {:+}", offset),
+            BcInstr::JumpIfFalse { cond, offset } => format!("JMPF {}, {:+}", cond, offset),
+            BcInstr::Loop { offset } => format!("LOOP -{}", offset),
             BcInstr::Neg { dest, a } => format!("NEG {} <= {}", dest, a),
+            BcInstr::Inc { dest } => format!("INC {}", dest),
+            BcInstr::Dec { dest } => format!("DEC {}", dest),
+            BcInstr::Not { dest, a } => format!("NOT {} <= {}", dest, a),
             BcInstr::Add { dest, a, b } => format!("ADD {} <= {}, {}", dest, a, b),
+            BcInstr::AddConst { dest, a, id } => format!(
+                "ADDK {} <= {}, {}",
+                dest,
+                a,
+                self.dump_constant(*id as ConstantId)
+            ),
             BcInstr::Sub { dest, a, b } => format!("SUB {} <= {}, {}", dest, a, b),
             BcInstr::Mul { dest, a, b } => format!("MUL {} <= {}, {}", dest, a, b),
             BcInstr::Div { dest, a, b } => format!("DIV {} <= {}, {}", dest, a, b),
+            BcInstr::Mod { dest, a, b } => format!("MOD {} <= {}, {}", dest, a, b),
+            BcInstr::Pow { dest, a, b } => format!("POW {} <= {}, {}", dest, a, b),
+            BcInstr::MulAdd { dest, a, b } => format!("MULADD {} <= {}, {}", dest, a, b),
+            BcInstr::BitAnd { dest, a, b } => format!("BAND {} <= {}, {}", dest, a, b),
+            BcInstr::BitOr { dest, a, b } => format!("BOR {} <= {}, {}", dest, a, b),
+            BcInstr::BitXor { dest, a, b } => format!("BXOR {} <= {}, {}", dest, a, b),
+            BcInstr::Shl { dest, a, b } => format!("SHL {} <= {}, {}", dest, a, b),
+            BcInstr::Shr { dest, a, b } => format!("SHR {} <= {}, {}", dest, a, b),
+            BcInstr::Eq { dest, a, b } => format!("EQ {} <= {}, {}", dest, a, b),
+            BcInstr::Ne { dest, a, b } => format!("NE {} <= {}, {}", dest, a, b),
+            BcInstr::Lt { dest, a, b } => format!("LT {} <= {}, {}", dest, a, b),
+            BcInstr::Le { dest, a, b } => format!("LE {} <= {}, {}", dest, a, b),
+            BcInstr::Gt { dest, a, b } => format!("GT {} <= {}, {}", dest, a, b),
+            BcInstr::Ge { dest, a, b } => format!("GE {} <= {}, {}", dest, a, b),
+            BcInstr::Call { callee, arg_count } => format!("CALL {}, {}", callee, arg_count),
+            BcInstr::DefineGlobal { name, src } => {
+                format!(
+                    "DEFGLOBAL {} <= {}",
+                    self.dump_constant(*name as ConstantId),
+                    src
+                )
+            }
+            BcInstr::GetGlobal { dest, name } => {
+                format!(
+                    "GETGLOBAL {} <= {}",
+                    dest,
+                    self.dump_constant(*name as ConstantId)
+                )
+            }
+            BcInstr::SetGlobal { name, src } => {
+                format!(
+                    "SETGLOBAL {} <= {}",
+                    self.dump_constant(*name as ConstantId),
+                    src
+                )
+            }
         };
 
         format!("0x{:X} {}", offset, s)
     }
 }
 
-impl std::fmt::Display for Chunk {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        writeln!(f, "== CHUNK ==\n")?;
-        for (offset, _instr) in self.code.iter().enumerate() {
-            f.write_fmt(format_args!("0x{:X} {} ", offset, self.get_line(offset)))?;
-            f.write_str(&self.dump_instr(offset))?;
-        }
-
-        writeln!(f, "\n-- DATA  --\n")?;
-        for (offset, constant) in self.constants.iter().enumerate() {
-            f.write_fmt(format_args!("0x{:X} ", offset))?;
-            f.write_str(&constant.to_string())?;
-            writeln!(f, "\n")?;
-        }
-        writeln!(f, "=== END ===")
+impl core::fmt::Display for Chunk {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(&self.disassemble())
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use alloc::string::ToString;
+
+    const RET: BcInstr = BcInstr::Ret {
+        src: Register::ret(),
+    };
 
     #[test]
     fn size_of_instr() {
-        let size = std::mem::size_of::<BcInstr>();
+        let size = core::mem::size_of::<BcInstr>();
         assert!(size <= 4, "BcInstr is size {}", size);
     }
 
+    #[test]
+    fn constants_dedup_by_bits() {
+        let mut chunk = Chunk::new();
+        let zero = chunk.add_constant(Value::Number(0.0));
+        let neg_zero = chunk.add_constant(Value::Number(-0.0));
+        assert_ne!(zero, neg_zero);
+        assert_eq!(chunk.add_constant(Value::Number(-0.0)), neg_zero);
+
+        let nan = chunk.add_constant(Value::Number(f64::NAN));
+        assert_eq!(chunk.add_constant(Value::Number(f64::NAN)), nan);
+        assert_eq!(chunk.constants.len(), 3);
+    }
+
+    #[test]
+    fn reserve_capacity() {
+        let write = |chunk: &mut Chunk| {
+            for n in 0..20 {
+                let id = chunk.add_constant(Value::Number(n as f64));
+                chunk.write_load_const(Register::new(1), id, n);
+            }
+            chunk.write(RET, 20);
+        };
+
+        let mut reserved = Chunk::with_capacity(16, 8);
+        assert!(reserved.code.capacity() >= 16 && reserved.constants.capacity() >= 8);
+        write(&mut reserved);
+        reserved.reserve(100);
+        assert!(reserved.code.capacity() >= 121);
+
+        // Writing past the capacity grows the chunk as usual
+        let mut chunk = Chunk::new();
+        write(&mut chunk);
+        assert_eq!(reserved.disassemble(), chunk.disassemble());
+        assert_eq!(reserved.to_bytes(), chunk.to_bytes());
+    }
+
+    #[test]
+    fn disassemble_chunk() {
+        let (r0, r1) = (Register::new(0), Register::new(1));
+        let mut chunk = Chunk::new();
+        let id = chunk.add_constant(Value::Number(2.5));
+        chunk.write_load_const(r1, id, 7);
+        chunk.write(
+            BcInstr::Add {
+                dest: r0,
+                a: r1,
+                b: r1,
+            },
+            8,
+        );
+        chunk.write(RET, 8);
+
+        let listing = chunk.disassemble();
+        let lines: Vec<_> = listing.lines().collect();
+        assert_eq!(lines[1], "   7 0x0 LOAD %r1 <= Number(2.5)");
+        assert_eq!(lines[2], "   8 0x1 ADD %r0 <= %r1, %r1");
+        assert_eq!(lines[3], "   8 0x2 RET %r0");
+        assert_eq!(lines[5], "0x0 Number(2.5)");
+        assert_eq!(chunk.to_string(), listing);
+    }
+
+    #[test]
+    fn instr_operands() {
+        let (r0, r1, r2) = (Register::new(0), Register::new(1), Register::new(2));
+
+        let load = BcInstr::LoadConst { dest: r1, id: 0 };
+        assert_eq!(load.defs(), Some(r1));
+        assert!(load.uses().is_empty());
+
+        let add = BcInstr::Add {
+            dest: r0,
+            a: r1,
+            b: r2,
+        };
+        assert_eq!(add.defs(), Some(r0));
+        assert_eq!(&*add.uses(), &[r1, r2]);
+
+        let jump = BcInstr::JumpIfFalse {
+            cond: r2,
+            offset: 1,
+        };
+        assert_eq!(jump.defs(), None);
+        assert_eq!(&*jump.uses(), &[r2]);
+
+        assert_eq!(&*BcInstr::Ret { src: r1 }.uses(), &[r1]);
+
+        let call = BcInstr::Call {
+            callee: r0,
+            arg_count: 2,
+        };
+        assert_eq!(call.defs(), Some(r0));
+        assert_eq!(&*call.uses(), &[r0, r1, r2]);
+
+        let spill = BcInstr::Spill { slot: 3, src: r1 };
+        assert_eq!(spill.defs(), None);
+        assert_eq!(&*spill.uses(), &[r1]);
+        let unspill = BcInstr::Unspill { dest: r2, slot: 3 };
+        assert_eq!(unspill.defs(), Some(r2));
+        assert!(unspill.uses().is_empty());
+
+        let inc = BcInstr::Inc { dest: r1 };
+        assert_eq!(inc.defs(), Some(r1));
+        assert_eq!(&*inc.uses(), &[r1]);
+    }
+
+    #[test]
+    fn patch_jumps() {
+        let r0 = Register::ret();
+        let mut chunk = Chunk::new();
+        let jump = chunk.emit_jump(
+            BcInstr::JumpIfFalse {
+                cond: r0,
+                offset: 0,
+            },
+            0,
+        );
+        chunk.write(BcInstr::Print { src: r0 }, 0);
+        chunk.write(BcInstr::Print { src: r0 }, 0);
+        assert_eq!(chunk.patch_jump(jump), Ok(()));
+        chunk.write(RET, 0);
+
+        assert_eq!(
+            chunk.instrs()[jump],
+            BcInstr::JumpIfFalse {
+                cond: r0,
+                offset: 2
+            }
+        );
+
+        let jump = chunk.emit_jump(BcInstr::Jump { offset: 0 }, 1);
+        for _ in 0..=i16::MAX {
+            chunk.write(BcInstr::Print { src: r0 }, 1);
+        }
+        assert_eq!(
+            chunk.patch_jump(jump),
+            Err(JumpTooFar {
+                distance: i16::MAX as usize + 1
+            })
+        );
+    }
+
+    #[test]
+    fn append_chunks() {
+        let (r0, r1) = (Register::new(0), Register::new(1));
+        let loaded = |chunk: &Chunk, offset: usize| match chunk.instrs()[offset] {
+            BcInstr::LoadConst { id, .. } => chunk.constant(id as ConstantId).unwrap(),
+            instr => panic!("Not a load: {:?}", instr),
+        };
+
+        let mut first = Chunk::new();
+        let id = first.add_constant(Value::Number(1.0));
+        first.write_load_const(r0, id, 1);
+        let id = first.add_constant(Value::Number(2.0));
+        first.write_load_const(r1, id, 2);
+
+        let mut second = Chunk::new();
+        let id = second.add_constant(Value::Number(3.0));
+        second.write_load_const(r0, id, 10);
+        second.write(
+            BcInstr::JumpIfFalse {
+                cond: r0,
+                offset: 1,
+            },
+            11,
+        );
+        let id = second.add_constant(Value::Number(2.0));
+        second.write_load_const(r1, id, 12);
+        second.write(RET, 13);
+
+        first.append(&second).unwrap();
+        assert_eq!(first.instrs().len(), 6);
+        assert_eq!(loaded(&first, 0), Value::Number(1.0));
+        assert_eq!(loaded(&first, 1), Value::Number(2.0));
+        assert_eq!(loaded(&first, 2), Value::Number(3.0));
+        assert_eq!(
+            first.instrs()[3],
+            BcInstr::JumpIfFalse {
+                cond: r0,
+                offset: 1
+            }
+        );
+        assert_eq!(loaded(&first, 4), Value::Number(2.0));
+        assert_eq!(first.get_line(4), 12);
+        assert_eq!(first.constants.len(), 3);
+    }
+
+    #[test]
+    fn append_widens_loads() {
+        let r0 = Register::ret();
+        let mut first = Chunk::new();
+        for i in 0..=u16::MAX as u32 {
+            first.add_constant(Value::Number(i as f64));
+        }
+
+        let mut second = Chunk::new();
+        second.write(BcInstr::Jump { offset: 1 }, 0);
+        let id = second.add_constant(Value::Number(-1.0));
+        second.write_load_const(r0, id, 0);
+        second.write(RET, 0);
+
+        first.append(&second).unwrap();
+        assert_eq!(first.instrs().len(), 4);
+        assert_eq!(first.instrs()[0], BcInstr::Jump { offset: 2 });
+        assert_eq!(first.verify(), Ok(()));
+    }
+
+    #[test]
+    fn append_global_out_of_range() {
+        let mut first = Chunk::new();
+        for i in 0..=u16::MAX as u32 {
+            first.add_constant(Value::Number(i as f64));
+        }
+
+        let mut second = Chunk::new();
+        let id = second.add_constant(Value::Number(-1.0));
+        second.write_load_const(Register::ret(), id, 0);
+        let name = second.add_string("x") as u16;
+        let src = Register::ret();
+        second.write(BcInstr::DefineGlobal { name, src }, 0);
+
+        // The chunk is left as it was
+        let id = u16::MAX as ConstantId + 2;
+        assert_eq!(
+            first.append(&second),
+            Err(AppendError::GlobalNameOutOfRange { offset: 1, id })
+        );
+        assert!(first.instrs().is_empty());
+        assert_eq!(first.constants.len(), u16::MAX as usize + 1);
+        assert!(first.strings.is_empty() && first.string_ids.is_empty());
+        assert_eq!(
+            first.add_constant(Value::Number(-1.0)),
+            u16::MAX as ConstantId + 1
+        );
+    }
+
+    #[test]
+    fn append_unfuses_add_const() {
+        let (r0, r1, r2) = (Register::new(0), Register::new(1), Register::new(2));
+        let mut first = Chunk::new();
+        for i in 0..300 {
+            first.add_constant(Value::Number(i as f64));
+        }
+
+        // One addition writes another register, and the other adds to the register in place
+        let mut second = Chunk::new();
+        let one = second.add_constant(Value::Number(0.5));
+        let two = second.add_constant(Value::Number(2.5));
+        second.write_load_const(r1, one, 0);
+        second.write_load_const(r2, two, 0);
+        second.write(
+            BcInstr::Add {
+                dest: r0,
+                a: r1,
+                b: r2,
+            },
+            0,
+        );
+        second.write_load_const(r2, two, 0);
+        second.write(
+            BcInstr::Add {
+                dest: r0,
+                a: r0,
+                b: r2,
+            },
+            0,
+        );
+        second.write(RET, 0);
+        second.fuse();
+        assert!(matches!(second.instrs()[1], BcInstr::AddConst { .. }));
+        assert!(matches!(second.instrs()[2], BcInstr::AddConst { .. }));
+
+        first.append(&second).unwrap();
+        let two = 301;
+        assert_eq!(
+            first.instrs()[1..],
+            [
+                BcInstr::LoadConst { dest: r0, id: two },
+                BcInstr::Add {
+                    dest: r0,
+                    a: r1,
+                    b: r0
+                },
+                BcInstr::LoadConst { dest: r1, id: two },
+                BcInstr::Add {
+                    dest: r0,
+                    a: r0,
+                    b: r1
+                },
+                RET,
+            ]
+        );
+        assert_eq!(first.verify(), Ok(()));
+
+        // Without a register free to load the constant into, the chunk can't be appended
+        let mut second = Chunk::new();
+        let a = Register::new(3);
+        second.write(BcInstr::AddConst { dest: a, a, id: 0 }, 0);
+        second.write(BcInstr::Jump { offset: 0 }, 0);
+        second.add_constant(Value::Number(-1.0));
+        assert_eq!(
+            first.append(&second),
+            Err(AppendError::NoFreeRegister { offset: 0 })
+        );
+    }
+
+    #[test]
+    fn constant_out_of_range() {
+        let mut chunk = Chunk::new();
+        let id = chunk.add_constant(Value::Number(1.0));
+        assert_eq!(chunk.constant(id), Some(Value::Number(1.0)));
+        assert_eq!(chunk.constant(id + 1), None);
+
+        chunk.write(
+            BcInstr::LoadConst {
+                dest: Register::ret(),
+                id: 7,
+            },
+            0,
+        );
+        assert_eq!(
+            chunk.dump_instr(0),
+            "0x0 LOAD %r0 <= <invalid constant 0x7>"
+        );
+    }
+
     #[test]
     fn get_instr_line() {
         let mut instrs = Chunk::new();
-        instrs.write(BcInstr::Ret, 0);
-        instrs.write(BcInstr::Ret, 0);
-        instrs.write(BcInstr::Ret, 0);
-        instrs.write(BcInstr::Ret, 1);
-        instrs.write(BcInstr::Ret, 2);
-        instrs.write(BcInstr::Ret, 3);
+        instrs.write(RET, 0);
+        instrs.write(RET, 0);
+        instrs.write(RET, 0);
+        instrs.write(RET, 1);
+        instrs.write(RET, 2);
+        instrs.write(RET, 3);
 
         assert_eq!(instrs.get_line(0), 0);
         assert_eq!(instrs.get_line(1), 0);
@@ -191,4 +1519,67 @@ mod test {
         assert_eq!(instrs.get_line(4), 2);
         assert_eq!(instrs.get_line(5), 3);
     }
+
+    #[test]
+    fn iter_instrs_with_lines() {
+        let mut instrs = Chunk::new();
+        instrs.write(
+            BcInstr::LoadTrue {
+                dest: Register::ret(),
+            },
+            1,
+        );
+        instrs.write(
+            BcInstr::Print {
+                src: Register::ret(),
+            },
+            1,
+        );
+        instrs.write(
+            BcInstr::LoadNil {
+                dest: Register::ret(),
+            },
+            4,
+        );
+        instrs.write(RET, 5);
+        instrs.write(RET, 5);
+
+        let items: Vec<_> = instrs.iter_with_lines().collect();
+        assert_eq!(items.len(), instrs.instrs().len());
+        for (offset, instr, line) in items {
+            assert_eq!(instr, &instrs.instrs()[offset]);
+            assert_eq!(line, instrs.get_line(offset));
+        }
+    }
+
+    #[test]
+    fn get_instr_column() {
+        let mut instrs = Chunk::new();
+        instrs.write_at(RET, 1, 1);
+        instrs.write_at(RET, 1, 1);
+        instrs.write_at(RET, 1, 7);
+        instrs.write_at(RET, 2, 7);
+        instrs.write(RET, 2);
+
+        assert_eq!(instrs.get_column(0), 1);
+        assert_eq!(instrs.get_column(1), 1);
+        assert_eq!(instrs.get_column(2), 7);
+        assert_eq!(instrs.get_column(3), 7);
+        assert_eq!(instrs.get_column(4), 0);
+        assert_eq!(instrs.get_line(2), 1);
+        assert_eq!(instrs.get_line(3), 2);
+        assert_eq!(instrs.columns.runs.len(), 3);
+    }
+
+    #[test]
+    fn get_instr_line_many_runs() {
+        let mut instrs = Chunk::new();
+        for i in 0..5000 {
+            instrs.write(RET, i / 3);
+        }
+
+        for i in 0..5000 {
+            assert_eq!(instrs.get_line(i), i / 3);
+        }
+    }
 }
@@ -0,0 +1,627 @@
+use super::{wide_constant_id, BcInstr, Chunk, ConstantId, RLETable, Register};
+use crate::value::Value;
+use crate::vm::REGISTER_MAX;
+use alloc::{vec, vec::Vec};
+
+impl Chunk {
+    /// Replace arithmetic and negation whose operands are known constants with a load of the
+    /// result. The loads feeding a folded instruction are removed when nothing else reads them.
+    ///
+    /// Register values are only tracked within a basic block, so anything crossing a jump or a jump
+    /// target is left alone.
+    pub fn fold_constants(&mut self) {
+        let leaders = self.block_leaders();
+
+        // Constant currently held in each register, along with the offset of the load. Only floats
+        // are tracked, as integers stay integers and fail on overflow in the VM
+        let mut known: [Option<(usize, f64)>; REGISTER_MAX] = [None; REGISTER_MAX];
+        let mut folded_loads = Vec::new();
+
+        for (offset, &leader) in leaders.iter().enumerate() {
+            if leader {
+                known = [None; REGISTER_MAX];
+            }
+
+            let instr = self.code[offset];
+            let mut fold = |a: Register, b: Option<Register>, op: fn(f64, f64) -> Option<f64>| {
+                let (a_load, a) = known.get(a.num()).copied().flatten()?;
+                let (b_load, b) = match b {
+                    Some(b) => known.get(b.num()).copied().flatten()?,
+                    None => (a_load, 0.0),
+                };
+
+                let result = op(a, b)?;
+                folded_loads.extend([a_load, b_load]);
+                Some(result)
+            };
+
+            let result = match instr {
+                BcInstr::Neg { a, .. } => fold(a, None, |a, _| Some(-a)),
+                BcInstr::Inc { dest } => fold(dest, None, |a, _| Some(a + 1.0)),
+                BcInstr::Dec { dest } => fold(dest, None, |a, _| Some(a - 1.0)),
+                BcInstr::Add { a, b, .. } => fold(a, Some(b), |a, b| Some(a + b)),
+                BcInstr::Sub { a, b, .. } => fold(a, Some(b), |a, b| Some(a - b)),
+                BcInstr::Mul { a, b, .. } => fold(a, Some(b), |a, b| Some(a * b)),
+                // A zero divisor is left for the VM to report at runtime
+                BcInstr::Div { a, b, .. } => fold(a, Some(b), |a, b| (b != 0.0).then(|| a / b)),
+                BcInstr::Mod { a, b, .. } => fold(a, Some(b), |a, b| (b != 0.0).then(|| a % b)),
+                BcInstr::LoadConst { id, .. } => self
+                    .constant(id as ConstantId)
+                    .and_then(|constant| constant.as_number()),
+                BcInstr::LoadConstWide { hi, .. } => match self.code.get(offset + 1) {
+                    Some(&BcInstr::ExtraArg { lo }) => self
+                        .constant(wide_constant_id(hi, lo))
+                        .and_then(|constant| constant.as_number()),
+                    _ => None,
+                },
+                _ => None,
+            };
+
+            // The callee's registers overlap every register of the caller from the callee on
+            if let BcInstr::Call { callee, .. } = instr {
+                if let Some(clobbered) = known.get_mut(callee.num()..) {
+                    clobbered.fill(None);
+                }
+            }
+
+            let Some(dest) = instr.defs() else {
+                if instr.jump_offset().is_some() {
+                    known = [None; REGISTER_MAX];
+                }
+                continue;
+            };
+
+            known[dest.num()] = match (instr, result) {
+                (BcInstr::LoadConst { .. } | BcInstr::LoadConstWide { .. }, Some(n)) => {
+                    Some((offset, n))
+                }
+                (_, Some(n)) => {
+                    // Results that would need a wide load can't replace a single instruction
+                    let id = self.add_constant(Value::Number(n));
+                    u16::try_from(id).ok().map(|id| {
+                        self.code[offset] = BcInstr::LoadConst { dest, id };
+                        (offset, n)
+                    })
+                }
+                (_, None) => None,
+            };
+        }
+
+        let dead: Vec<bool> = (0..self.code.len())
+            .map(|offset| folded_loads.contains(&offset) && !self.is_def_live(offset, &leaders))
+            .collect();
+        self.remove_instrs(&dead);
+    }
+
+    /// Remove every instruction that control flow can never reach from the start of the chunk
+    pub fn strip_unreachable(&mut self) {
+        let mut reachable = vec![false; self.code.len()];
+        let mut worklist = vec![0];
+        while let Some(offset) = worklist.pop() {
+            match reachable.get_mut(offset) {
+                Some(seen) if !*seen => *seen = true,
+                _ => continue,
+            }
+
+            let instr = self.code[offset];
+            if let Some(jump) = instr.jump_offset() {
+                worklist.extend((offset + 1).checked_add_signed(jump));
+            }
+
+            match instr {
+                BcInstr::Ret { .. } | BcInstr::Jump { .. } | BcInstr::Loop { .. } => {}
+                // The operand is kept along with the wide load by `remove_instrs`
+                BcInstr::LoadConstWide { .. } => worklist.push(offset + 2),
+                _ => worklist.push(offset + 1),
+            }
+        }
+
+        let dead: Vec<bool> = reachable.iter().map(|reachable| !reachable).collect();
+        self.remove_instrs(&dead);
+    }
+
+    /// Fuse pairs of instructions into one where an `Add` consumes the result of the instruction
+    /// right before it:
+    /// - a `LoadConst` of its right operand becomes an `AddConst`, as long as the constant id fits
+    ///   in the operand of `AddConst`
+    /// - a `Mul` whose product is added to the destination of the `Add` becomes a `MulAdd`
+    ///
+    /// Nothing else may read the register the first instruction writes
+    pub fn fuse(&mut self) {
+        let leaders = self.block_leaders();
+        let mut dead = vec![false; self.code.len()];
+        for offset in 1..self.code.len() {
+            // A jump landing on the `Add` would skip the first instruction
+            if leaders[offset] || dead[offset - 1] {
+                continue;
+            }
+
+            let fused = self
+                .fuse_add_const(offset, &leaders)
+                .or_else(|| self.fuse_mul_add(offset, &leaders));
+            if let Some(fused) = fused {
+                self.code[offset] = fused;
+                dead[offset - 1] = true;
+            }
+        }
+        self.remove_instrs(&dead);
+    }
+
+    fn fuse_add_const(&self, offset: usize, leaders: &[bool]) -> Option<BcInstr> {
+        let (BcInstr::LoadConst { dest: loaded, id }, BcInstr::Add { dest, a, b }) =
+            (self.code[offset - 1], self.code[offset])
+        else {
+            return None;
+        };
+
+        // Strings don't commute, so only the right operand can be the constant
+        if b != loaded || a == loaded {
+            return None;
+        }
+        if dest != loaded && self.is_live(loaded, offset + 1, leaders) {
+            return None;
+        }
+        let id = u8::try_from(id).ok()?;
+        Some(BcInstr::AddConst { dest, a, id })
+    }
+
+    fn fuse_mul_add(&self, offset: usize, leaders: &[bool]) -> Option<BcInstr> {
+        let (
+            BcInstr::Mul {
+                dest: product,
+                a,
+                b,
+            },
+            BcInstr::Add { dest, a: x, b: y },
+        ) = (self.code[offset - 1], self.code[offset])
+        else {
+            return None;
+        };
+
+        // `MulAdd` adds to its destination, so the other operand of the `Add` has to be it. The
+        // product is a number, so it can be on either side
+        let adds_product = (x, y) == (product, dest) || (x, y) == (dest, product);
+        if !adds_product || product == dest || self.is_live(product, offset + 1, leaders) {
+            return None;
+        }
+        Some(BcInstr::MulAdd { dest, a, b })
+    }
+
+    /// Returns the instructions that start a basic block: the first instruction and every jump
+    /// target
+    pub(super) fn block_leaders(&self) -> Vec<bool> {
+        let mut leaders = vec![false; self.code.len()];
+        if let Some(first) = leaders.first_mut() {
+            *first = true;
+        }
+
+        for (offset, instr) in self.code.iter().enumerate() {
+            let target = instr
+                .jump_offset()
+                .and_then(|jump| (offset + 1).checked_add_signed(jump));
+            if let Some(leader) = target.and_then(|target| leaders.get_mut(target)) {
+                *leader = true;
+            }
+        }
+        leaders
+    }
+
+    /// A register the instruction at `offset`, writing `dest` from `a`, could load one more value
+    /// into: `dest` itself unless it is also `a`, or else any other register that is not read
+    /// afterwards
+    pub(super) fn free_register(
+        &self,
+        dest: Register,
+        a: Register,
+        offset: usize,
+        leaders: &[bool],
+    ) -> Option<Register> {
+        if dest != a {
+            return Some(dest);
+        }
+        (0..REGISTER_MAX as u8)
+            .map(Register::new)
+            .find(|&r| r != a && !self.is_live(r, offset + 1, leaders))
+    }
+
+    /// Returns `true` if the register written at `offset` may be read afterwards
+    fn is_def_live(&self, offset: usize, leaders: &[bool]) -> bool {
+        match self.code[offset].defs() {
+            Some(register) => self.is_live(register, offset + 1, leaders),
+            None => false,
+        }
+    }
+
+    /// Returns `true` if `register` may be read from `from` on before being written.
+    /// Conservatively assumes it is read if its block ends in anything other than a `Ret`
+    fn is_live(&self, register: Register, from: usize, leaders: &[bool]) -> bool {
+        for (next, &leader) in leaders.iter().enumerate().skip(from) {
+            let instr = self.code[next];
+            if leader || instr.uses().contains(&register) {
+                return true;
+            }
+
+            match instr {
+                BcInstr::Ret { .. } => return false,
+                _ if instr.jump_offset().is_some() => return true,
+                _ if instr.defs() == Some(register) => return false,
+                _ => {}
+            }
+        }
+        true
+    }
+
+    /// Remove every instruction marked in `dead`, along with its line, column and span, and
+    /// adjust jumps so they still land on the same instructions. A jump to a removed instruction
+    /// lands on the next remaining one instead
+    fn remove_instrs(&mut self, dead: &[bool]) {
+        // Remove the operand along with a wide load
+        let mut dead = dead.to_vec();
+        for offset in 0..self.code.len() {
+            if let BcInstr::LoadConstWide { .. } = self.code[offset] {
+                let is_dead = dead[offset];
+                if let Some(operand) = dead.get_mut(offset + 1) {
+                    *operand = is_dead;
+                }
+            }
+        }
+
+        // Index of each instruction after removal. Removed instructions map to the next one kept
+        let mut new_index = Vec::with_capacity(self.code.len() + 1);
+        let mut kept = 0;
+        for &is_dead in &dead {
+            new_index.push(kept);
+            kept += !is_dead as usize;
+        }
+        new_index.push(kept);
+
+        let mut code = Vec::with_capacity(kept);
+        let (mut lines, mut columns) = (RLETable::default(), RLETable::default());
+        for (offset, mut instr) in self.code.iter().copied().enumerate() {
+            if dead[offset] {
+                continue;
+            }
+
+            if let Some(jump) = instr.jump_offset() {
+                let target = (offset + 1).checked_add_signed(jump).unwrap();
+                let jump = new_index[target] as isize - (new_index[offset] as isize + 1);
+                match &mut instr {
+                    BcInstr::Jump { offset } | BcInstr::JumpIfFalse { offset, .. } => {
+                        *offset = jump as i16
+                    }
+                    BcInstr::Loop { offset } => *offset = -jump as u16,
+                    _ => unreachable!(),
+                }
+            }
+
+            code.push(instr);
+            lines.push(self.get_line(offset));
+            columns.push(self.get_column(offset));
+        }
+
+        self.spans.retain(|&(offset, _)| !dead[offset]);
+        for (offset, _) in &mut self.spans {
+            *offset = new_index[*offset];
+        }
+
+        self.code = code;
+        self.lines = lines;
+        self.columns = columns;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const RET: BcInstr = BcInstr::Ret {
+        src: Register::ret(),
+    };
+
+    fn load(chunk: &mut Chunk, dest: Register, n: f64) {
+        let id = chunk.add_constant(Value::Number(n));
+        chunk.write_load_const(dest, id, 0);
+    }
+
+    fn loaded_number(chunk: &Chunk, offset: usize) -> Option<f64> {
+        match chunk.instrs()[offset] {
+            BcInstr::LoadConst { id, .. } => chunk.constant(id as ConstantId)?.as_number(),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn fold_neg() {
+        let r0 = Register::ret();
+        let mut chunk = Chunk::new();
+        load(&mut chunk, r0, 4.0);
+        chunk.write(BcInstr::Neg { dest: r0, a: r0 }, 0);
+        chunk.write(RET, 0);
+
+        chunk.fold_constants();
+        assert_eq!(chunk.instrs().len(), 2);
+        assert_eq!(loaded_number(&chunk, 0), Some(-4.0));
+        assert_eq!(chunk.instrs()[1], RET);
+    }
+
+    #[test]
+    fn fold_add_chain() {
+        let (r0, r1, r2, r3) = (
+            Register::new(0),
+            Register::new(1),
+            Register::new(2),
+            Register::new(3),
+        );
+        let mut chunk = Chunk::new();
+        load(&mut chunk, r1, 1.0);
+        load(&mut chunk, r2, 2.0);
+        load(&mut chunk, r3, 3.0);
+        chunk.write(
+            BcInstr::Add {
+                dest: r1,
+                a: r1,
+                b: r2,
+            },
+            1,
+        );
+        chunk.write(
+            BcInstr::Add {
+                dest: r0,
+                a: r1,
+                b: r3,
+            },
+            2,
+        );
+        chunk.write(RET, 3);
+
+        chunk.fold_constants();
+        assert_eq!(chunk.instrs().len(), 2);
+        assert_eq!(loaded_number(&chunk, 0), Some(6.0));
+        assert_eq!(chunk.get_line(0), 2);
+        assert_eq!(chunk.instrs()[1], RET);
+    }
+
+    #[test]
+    fn keep_live_sources() {
+        let (r0, r1, r2) = (Register::new(0), Register::new(1), Register::new(2));
+        let mut chunk = Chunk::new();
+        load(&mut chunk, r1, 1.0);
+        load(&mut chunk, r2, 2.0);
+        chunk.write(
+            BcInstr::Add {
+                dest: r0,
+                a: r1,
+                b: r2,
+            },
+            0,
+        );
+        chunk.write(BcInstr::Print { src: r1 }, 0);
+        chunk.write(RET, 0);
+
+        chunk.fold_constants();
+
+        // r1 is still printed, so only the load of r2 can go
+        assert_eq!(chunk.instrs().len(), 4);
+        assert_eq!(loaded_number(&chunk, 0), Some(1.0));
+        assert_eq!(loaded_number(&chunk, 1), Some(3.0));
+    }
+
+    #[test]
+    fn no_fold_across_blocks() {
+        let (r0, r1) = (Register::new(0), Register::new(1));
+        let mut chunk = Chunk::new();
+        load(&mut chunk, r1, 1.0);
+        chunk.write(
+            BcInstr::JumpIfFalse {
+                cond: r0,
+                offset: 1,
+            },
+            0,
+        );
+        load(&mut chunk, r1, 2.0);
+        chunk.write(BcInstr::Neg { dest: r0, a: r1 }, 0);
+        chunk.write(RET, 0);
+
+        chunk.fold_constants();
+        assert_eq!(chunk.instrs()[3], BcInstr::Neg { dest: r0, a: r1 });
+    }
+
+    #[test]
+    fn fuse_add_const() {
+        let (r0, r1, r2) = (Register::new(0), Register::new(1), Register::new(2));
+        let mut chunk = Chunk::new();
+        load(&mut chunk, r1, 1.0);
+        chunk.write(
+            BcInstr::Add {
+                dest: r0,
+                a: r0,
+                b: r1,
+            },
+            1,
+        );
+        // r2 is printed, and the constant is the left operand of the second add, so neither of
+        // the following loads can be fused
+        load(&mut chunk, r2, 2.0);
+        chunk.write(
+            BcInstr::Add {
+                dest: r0,
+                a: r0,
+                b: r2,
+            },
+            2,
+        );
+        chunk.write(BcInstr::Print { src: r2 }, 2);
+        load(&mut chunk, r1, 3.0);
+        chunk.write(
+            BcInstr::Add {
+                dest: r0,
+                a: r1,
+                b: r0,
+            },
+            3,
+        );
+        chunk.write(RET, 3);
+
+        chunk.fuse();
+
+        assert_eq!(chunk.instrs().len(), 7);
+        assert_eq!(
+            chunk.instrs()[0],
+            BcInstr::AddConst {
+                dest: r0,
+                a: r0,
+                id: 0
+            }
+        );
+        assert_eq!(chunk.get_line(0), 1);
+        assert_eq!(loaded_number(&chunk, 1), Some(2.0));
+        assert_eq!(loaded_number(&chunk, 4), Some(3.0));
+    }
+
+    #[test]
+    fn fuse_mul_add() {
+        let (r0, r1, r2) = (Register::new(0), Register::new(1), Register::new(2));
+        let square = BcInstr::Mul {
+            dest: r1,
+            a: r2,
+            b: r2,
+        };
+        let mut chunk = Chunk::new();
+        chunk.write(
+            BcInstr::Mul {
+                dest: r1,
+                a: r1,
+                b: r2,
+            },
+            1,
+        );
+        chunk.write(
+            BcInstr::Add {
+                dest: r0,
+                a: r0,
+                b: r1,
+            },
+            1,
+        );
+        // The product is printed, so it has to be kept
+        chunk.write(square, 2);
+        chunk.write(
+            BcInstr::Add {
+                dest: r0,
+                a: r1,
+                b: r0,
+            },
+            2,
+        );
+        chunk.write(BcInstr::Print { src: r1 }, 2);
+        chunk.write(RET, 2);
+
+        chunk.fuse();
+
+        assert_eq!(
+            chunk.instrs()[0],
+            BcInstr::MulAdd {
+                dest: r0,
+                a: r1,
+                b: r2
+            }
+        );
+        assert_eq!(chunk.instrs()[1], square);
+        assert_eq!(chunk.instrs().len(), 5);
+    }
+
+    #[test]
+    fn strip_after_ret() {
+        let r0 = Register::ret();
+        let mut chunk = Chunk::new();
+        chunk.write(BcInstr::LoadNil { dest: r0 }, 0);
+        chunk.write(RET, 0);
+        chunk.write(BcInstr::Print { src: r0 }, 1);
+        chunk.write(RET, 1);
+
+        chunk.strip_unreachable();
+        assert_eq!(chunk.instrs(), &[BcInstr::LoadNil { dest: r0 }, RET]);
+    }
+
+    #[test]
+    fn strip_keeps_jump_targets() {
+        let r0 = Register::ret();
+        let mut chunk = Chunk::new();
+        chunk.write(BcInstr::LoadTrue { dest: r0 }, 0);
+        chunk.write(BcInstr::Jump { offset: 2 }, 0);
+        chunk.write(BcInstr::Print { src: r0 }, 1);
+        chunk.write(BcInstr::Print { src: r0 }, 1);
+        chunk.write(
+            BcInstr::JumpIfFalse {
+                cond: r0,
+                offset: 2,
+            },
+            2,
+        );
+        chunk.write(RET, 3);
+        chunk.write(BcInstr::Print { src: r0 }, 4);
+        chunk.write(BcInstr::Loop { offset: 4 }, 5);
+
+        chunk.strip_unreachable();
+        assert_eq!(
+            chunk.instrs(),
+            &[
+                BcInstr::LoadTrue { dest: r0 },
+                BcInstr::Jump { offset: 0 },
+                BcInstr::JumpIfFalse {
+                    cond: r0,
+                    offset: 1
+                },
+                RET,
+                BcInstr::Loop { offset: 3 },
+            ]
+        );
+        assert_eq!(chunk.get_line(4), 5);
+        assert_eq!(chunk.verify(), Ok(()));
+    }
+
+    #[test]
+    fn fold_inc_of_numbers_only() {
+        let r0 = Register::ret();
+        let mut chunk = Chunk::new();
+        load(&mut chunk, r0, 1.5);
+        chunk.write(BcInstr::Inc { dest: r0 }, 0);
+        chunk.write(BcInstr::Inc { dest: r0 }, 0);
+        chunk.write(BcInstr::Dec { dest: r0 }, 0);
+        chunk.write(RET, 0);
+
+        chunk.fold_constants();
+        assert_eq!(chunk.instrs().len(), 2);
+        assert_eq!(loaded_number(&chunk, 0), Some(2.5));
+
+        // Folding integers as floats would turn them into numbers
+        let mut chunk = Chunk::new();
+        let id = chunk.add_constant(Value::Int(1));
+        chunk.write_load_const(r0, id, 0);
+        chunk.write(BcInstr::Inc { dest: r0 }, 0);
+        chunk.write(RET, 0);
+
+        chunk.fold_constants();
+        assert_eq!(chunk.instrs()[1], BcInstr::Inc { dest: r0 });
+    }
+
+    #[test]
+    fn no_fold_division_by_zero() {
+        let (r0, r1) = (Register::new(0), Register::new(1));
+        let mut chunk = Chunk::new();
+        load(&mut chunk, r0, 1.0);
+        load(&mut chunk, r1, 0.0);
+        chunk.write(
+            BcInstr::Div {
+                dest: r0,
+                a: r0,
+                b: r1,
+            },
+            0,
+        );
+        chunk.write(RET, 0);
+
+        chunk.fold_constants();
+        assert_eq!(chunk.instrs().len(), 4);
+    }
+}
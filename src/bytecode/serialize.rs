@@ -0,0 +1,503 @@
+use super::{BcInstr, Chunk, Constant, ConstantKey, RLERun, RLETable, Register};
+use crate::value::Value;
+use alloc::{string::String, vec::Vec};
+
+/// Identifies a serialized `Chunk`
+const MAGIC: &[u8; 4] = b"RLOX";
+
+/// Version of the serialized format. Must be bumped whenever the encoding of a `Chunk` or the set
+/// of instructions changes
+const VERSION: u16 = 15;
+
+// Every multi-byte field is little-endian, so chunks can be loaded on hosts of either endianness
+
+/// Reasons a byte buffer could not be decoded into a `Chunk`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkDecodeError {
+    /// The buffer does not start with the magic number, so it is not a serialized chunk
+    BadMagic,
+    /// The chunk was serialized in another version of the format
+    UnsupportedVersion(u16),
+    /// The buffer ended in the middle of the chunk
+    UnexpectedEof,
+    /// An instruction had an unknown opcode
+    InvalidOpcode(u8),
+    /// A constant had an unknown type tag
+    InvalidConstant(u8),
+    /// A string constant is not valid UTF-8, or refers past the end of the string section
+    InvalidString,
+    /// There are bytes left over after the end of the chunk
+    TrailingBytes,
+}
+
+impl core::fmt::Display for ChunkDecodeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            ChunkDecodeError::BadMagic => write!(f, "not an rlox chunk"),
+            ChunkDecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported chunk version {}", version)
+            }
+            ChunkDecodeError::UnexpectedEof => write!(f, "unexpected end of chunk"),
+            ChunkDecodeError::InvalidOpcode(op) => write!(f, "invalid opcode 0x{:X}", op),
+            ChunkDecodeError::InvalidConstant(tag) => write!(f, "invalid constant tag 0x{:X}", tag),
+            ChunkDecodeError::InvalidString => write!(f, "invalid string constant"),
+            ChunkDecodeError::TrailingBytes => write!(f, "trailing bytes after chunk"),
+        }
+    }
+}
+
+/// Cursor over the buffer being decoded
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], ChunkDecodeError> {
+        if self.bytes.len() < N {
+            return Err(ChunkDecodeError::UnexpectedEof);
+        }
+
+        let (head, rest) = self.bytes.split_at(N);
+        self.bytes = rest;
+        Ok(head.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, ChunkDecodeError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, ChunkDecodeError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, ChunkDecodeError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+}
+
+/// Instruction operand that can be written to and read back from a serialized chunk
+trait Operand: Sized {
+    fn encode(self, out: &mut Vec<u8>);
+    fn decode(reader: &mut Reader) -> Result<Self, ChunkDecodeError>;
+}
+
+impl Operand for Register {
+    fn encode(self, out: &mut Vec<u8>) {
+        out.push(self.0);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, ChunkDecodeError> {
+        Ok(Register(reader.u8()?))
+    }
+}
+
+impl Operand for u8 {
+    fn encode(self, out: &mut Vec<u8>) {
+        out.push(self);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, ChunkDecodeError> {
+        reader.u8()
+    }
+}
+
+impl Operand for u16 {
+    fn encode(self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, ChunkDecodeError> {
+        Ok(u16::from_le_bytes(reader.take()?))
+    }
+}
+
+impl Operand for i16 {
+    fn encode(self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, ChunkDecodeError> {
+        Ok(i16::from_le_bytes(reader.take()?))
+    }
+}
+
+/// Generate the encoder and decoder for every instruction from a single table of opcodes, so the
+/// two can never disagree
+macro_rules! instr_codec {
+    ($($opcode:literal => $name:ident { $($field:ident),* }),* $(,)?) => {
+        fn encode_instr(instr: BcInstr, out: &mut Vec<u8>) {
+            match instr {
+                $(BcInstr::$name { $($field),* } => {
+                    out.push($opcode);
+                    $($field.encode(out);)*
+                })*
+            }
+        }
+
+        fn decode_instr(reader: &mut Reader) -> Result<BcInstr, ChunkDecodeError> {
+            match reader.u8()? {
+                $($opcode => Ok(BcInstr::$name { $($field: Operand::decode(reader)?),* }),)*
+                opcode => Err(ChunkDecodeError::InvalidOpcode(opcode)),
+            }
+        }
+    };
+}
+
+instr_codec! {
+    0x00 => Ret { src },
+    0x01 => Neg { dest, a },
+    0x02 => Not { dest, a },
+    0x03 => Add { dest, a, b },
+    0x04 => Sub { dest, a, b },
+    0x05 => Mul { dest, a, b },
+    0x06 => Div { dest, a, b },
+    0x07 => Mod { dest, a, b },
+    0x08 => LoadConst { dest, id },
+    0x09 => LoadConstWide { dest, hi },
+    0x0A => ExtraArg { lo },
+    0x0B => Move { dest, src },
+    0x0C => LoadTrue { dest },
+    0x0D => LoadFalse { dest },
+    0x0E => LoadNil { dest },
+    0x0F => Print { src },
+    0x10 => Jump { offset },
+    0x11 => JumpIfFalse { cond, offset },
+    0x12 => Loop { offset },
+    0x13 => Eq { dest, a, b },
+    0x14 => Ne { dest, a, b },
+    0x15 => Lt { dest, a, b },
+    0x16 => Le { dest, a, b },
+    0x17 => Gt { dest, a, b },
+    0x18 => Ge { dest, a, b },
+    0x19 => Call { callee, arg_count },
+    0x1A => DefineGlobal { name, src },
+    0x1B => GetGlobal { dest, name },
+    0x1C => SetGlobal { name, src },
+    0x1D => AddConst { dest, a, id },
+    0x1E => Spill { slot, src },
+    0x1F => Unspill { dest, slot },
+    0x20 => Inc { dest },
+    0x21 => Dec { dest },
+    0x22 => BitAnd { dest, a, b },
+    0x23 => BitOr { dest, a, b },
+    0x24 => BitXor { dest, a, b },
+    0x25 => Shl { dest, a, b },
+    0x26 => Shr { dest, a, b },
+    0x27 => Pow { dest, a, b },
+    0x28 => MulAdd { dest, a, b },
+}
+
+fn encode_rle_table(table: &RLETable, out: &mut Vec<u8>) {
+    out.extend_from_slice(&(table.runs.len() as u32).to_le_bytes());
+    for RLERun { value, end } in &table.runs {
+        out.extend_from_slice(&(*value as u64).to_le_bytes());
+        out.extend_from_slice(&(*end as u64).to_le_bytes());
+    }
+}
+
+fn decode_rle_table(reader: &mut Reader) -> Result<RLETable, ChunkDecodeError> {
+    let mut table = RLETable::default();
+    for _ in 0..reader.u32()? {
+        let value = reader.u64()? as usize;
+        let end = reader.u64()? as usize;
+        table.runs.push(RLERun { value, end });
+    }
+    Ok(table)
+}
+
+const TAG_NUMBER: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_NIL: u8 = 2;
+const TAG_INT: u8 = 3;
+const TAG_STRING: u8 = 4;
+
+impl Chunk {
+    /// Serialize the code, line table and constant pool of the chunk. Source spans are left out.
+    /// Panics if the pool holds an object, as those only live as long as the heap they were
+    /// allocated in. Strings are written from the string section instead
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+
+        out.extend_from_slice(&(self.code.len() as u32).to_le_bytes());
+        for instr in &self.code {
+            encode_instr(*instr, &mut out);
+        }
+
+        encode_rle_table(&self.lines, &mut out);
+        encode_rle_table(&self.columns, &mut out);
+
+        out.extend_from_slice(&(self.strings.len() as u32).to_le_bytes());
+        for string in &self.strings {
+            out.extend_from_slice(&(string.len() as u32).to_le_bytes());
+            out.extend_from_slice(string.as_bytes());
+        }
+
+        out.extend_from_slice(&(self.constants.len() as u32).to_le_bytes());
+        for constant in &self.constants {
+            let constant = match constant {
+                Constant::Value(v) => v,
+                Constant::String(index) => {
+                    out.push(TAG_STRING);
+                    out.extend_from_slice(&index.to_le_bytes());
+                    continue;
+                }
+            };
+
+            match constant {
+                Value::Number(n) => {
+                    out.push(TAG_NUMBER);
+                    out.extend_from_slice(&n.to_bits().to_le_bytes());
+                }
+                Value::Bool(b) => {
+                    out.push(TAG_BOOL);
+                    out.push(*b as u8);
+                }
+                Value::Int(n) => {
+                    out.push(TAG_INT);
+                    out.extend_from_slice(&n.to_le_bytes());
+                }
+                Value::Nil => out.push(TAG_NIL),
+                Value::Obj(_) => panic!("Object constants can't be serialized"),
+            }
+        }
+
+        out
+    }
+
+    /// Deserialize a chunk previously written by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Chunk, ChunkDecodeError> {
+        let mut reader = Reader { bytes };
+        let magic = reader.take::<4>().map_err(|_| ChunkDecodeError::BadMagic)?;
+        if &magic != MAGIC {
+            return Err(ChunkDecodeError::BadMagic);
+        }
+        let version = u16::decode(&mut reader)?;
+        if version != VERSION {
+            return Err(ChunkDecodeError::UnsupportedVersion(version));
+        }
+
+        let mut chunk = Chunk::new();
+        for _ in 0..reader.u32()? {
+            chunk.code.push(decode_instr(&mut reader)?);
+        }
+
+        chunk.lines = decode_rle_table(&mut reader)?;
+        chunk.columns = decode_rle_table(&mut reader)?;
+
+        for _ in 0..reader.u32()? {
+            let len = reader.u32()? as usize;
+            if reader.bytes.len() < len {
+                return Err(ChunkDecodeError::UnexpectedEof);
+            }
+            let (bytes, rest) = reader.bytes.split_at(len);
+            reader.bytes = rest;
+            let string =
+                core::str::from_utf8(bytes).map_err(|_| ChunkDecodeError::InvalidString)?;
+            chunk.strings.push(String::from(string));
+        }
+
+        for _ in 0..reader.u32()? {
+            // Keep the ids from the serialized pool rather than deduplicating again
+            let id = chunk.constants.len() as u32;
+            let constant = match reader.u8()? {
+                TAG_NUMBER => Value::Number(f64::from_bits(reader.u64()?)),
+                TAG_BOOL => Value::Bool(reader.u8()? != 0),
+                TAG_NIL => Value::Nil,
+                TAG_INT => Value::Int(reader.u64()? as i64),
+                TAG_STRING => {
+                    let index = reader.u32()?;
+                    let string = chunk
+                        .strings
+                        .get(index as usize)
+                        .ok_or(ChunkDecodeError::InvalidString)?;
+                    chunk.string_ids.insert(string.clone(), id);
+                    chunk.constants.push(Constant::String(index));
+                    continue;
+                }
+                tag => return Err(ChunkDecodeError::InvalidConstant(tag)),
+            };
+
+            if let Some(key) = ConstantKey::of(&constant) {
+                chunk.constant_ids.insert(key, id);
+            }
+            chunk.constants.push(Constant::Value(constant));
+        }
+
+        if !reader.bytes.is_empty() {
+            return Err(ChunkDecodeError::TrailingBytes);
+        }
+
+        Ok(chunk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const RET: BcInstr = BcInstr::Ret {
+        src: Register::ret(),
+    };
+
+    #[test]
+    fn round_trip() {
+        let (r0, r1, r2) = (Register::new(0), Register::new(1), Register::new(2));
+        let mut chunk = Chunk::new();
+        let id = chunk.add_constant(Value::Number(1.5));
+        chunk.write_load_const(r1, id, 1);
+        let id = chunk.add_constant(Value::Number(-0.0));
+        chunk.write_load_const(r2, id, 1);
+        let id = chunk.add_constant(Value::Int(-7));
+        chunk.write_load_const(r2, id, 1);
+        chunk.write(
+            BcInstr::Add {
+                dest: r0,
+                a: r1,
+                b: r2,
+            },
+            2,
+        );
+        chunk.write(
+            BcInstr::JumpIfFalse {
+                cond: r0,
+                offset: -3,
+            },
+            2,
+        );
+        chunk.write_at(BcInstr::Loop { offset: 4 }, 3, 12);
+        chunk.write(BcInstr::LoadNil { dest: r2 }, 3);
+        chunk.write(BcInstr::Spill { slot: 300, src: r2 }, 3);
+        chunk.write(
+            BcInstr::Unspill {
+                dest: r1,
+                slot: 300,
+            },
+            3,
+        );
+        chunk.write(BcInstr::Inc { dest: r1 }, 3);
+        chunk.write(
+            BcInstr::Call {
+                callee: r1,
+                arg_count: 1,
+            },
+            3,
+        );
+        chunk.write(RET, 4);
+
+        let decoded = Chunk::from_bytes(&chunk.to_bytes()).expect("Could not decode chunk!");
+        assert_eq!(decoded.instrs().len(), chunk.instrs().len());
+        for (offset, (a, b)) in chunk.instrs().iter().zip(decoded.instrs()).enumerate() {
+            assert_eq!(a, b);
+            assert_eq!(chunk.get_line(offset), decoded.get_line(offset));
+            assert_eq!(chunk.get_column(offset), decoded.get_column(offset));
+        }
+
+        assert_eq!(decoded.constants.len(), chunk.constants.len());
+        for id in 0..chunk.constants.len() as u32 {
+            let (a, b) = (chunk.constant(id).unwrap(), decoded.constant(id).unwrap());
+            assert_eq!(
+                a.as_number().map(f64::to_bits),
+                b.as_number().map(f64::to_bits)
+            );
+            assert_eq!(a.as_int(), b.as_int());
+        }
+    }
+
+    #[test]
+    fn round_trip_strings() {
+        let mut chunk = Chunk::new();
+        let number = chunk.add_constant(Value::Number(2.5));
+        let string = chunk.add_string("héllo");
+        chunk.write_load_const(Register::new(1), number, 1);
+        chunk.write_load_const(Register::ret(), string, 1);
+        chunk.write(RET, 1);
+
+        let decoded = Chunk::from_bytes(&chunk.to_bytes()).expect("Could not decode chunk!");
+        assert_eq!(decoded.instrs(), chunk.instrs());
+        assert_eq!(
+            decoded.constant(number).and_then(|n| n.as_number()),
+            Some(2.5)
+        );
+        assert_eq!(decoded.constant(string), None);
+        assert_eq!(decoded.string(string), Some("héllo"));
+        assert_eq!(decoded.string(number), None);
+
+        // Strings are deduplicated against the ones read back
+        let mut decoded = decoded;
+        assert_eq!(decoded.add_string("héllo"), string);
+    }
+
+    #[test]
+    fn decode_little_endian() {
+        let mut bytes = b"RLOX".to_vec();
+        bytes.extend([VERSION as u8, (VERSION >> 8) as u8]);
+        // Code: `JMPF %r1, -2` and `RET %r0`
+        bytes.extend([2, 0, 0, 0]);
+        bytes.extend([0x11, 1, 0xFE, 0xFF]);
+        bytes.extend([0x00, 0]);
+        // Lines: both instructions on line 258. Columns: none
+        bytes.extend([1, 0, 0, 0]);
+        bytes.extend([2, 1, 0, 0, 0, 0, 0, 0]);
+        bytes.extend([2, 0, 0, 0, 0, 0, 0, 0]);
+        bytes.extend([0, 0, 0, 0]);
+        // Strings: none
+        bytes.extend([0, 0, 0, 0]);
+        // Constants: 1.5 and 0x0102
+        bytes.extend([2, 0, 0, 0]);
+        bytes.extend([TAG_NUMBER, 0, 0, 0, 0, 0, 0, 0xF8, 0x3F]);
+        bytes.extend([TAG_INT, 2, 1, 0, 0, 0, 0, 0, 0]);
+
+        let chunk = Chunk::from_bytes(&bytes).expect("Could not decode chunk!");
+        assert_eq!(
+            chunk.instrs(),
+            [
+                BcInstr::JumpIfFalse {
+                    cond: Register::new(1),
+                    offset: -2
+                },
+                RET
+            ]
+        );
+        assert_eq!(chunk.get_line(1), 258);
+        assert_eq!(chunk.constant(0).and_then(|n| n.as_number()), Some(1.5));
+        assert_eq!(chunk.constant(1).and_then(|n| n.as_int()), Some(0x0102));
+        assert_eq!(chunk.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn reject_bad_magic() {
+        let mut bytes = Chunk::new().to_bytes();
+        bytes[0] = b'X';
+        assert_eq!(
+            Chunk::from_bytes(&bytes).err(),
+            Some(ChunkDecodeError::BadMagic)
+        );
+        assert_eq!(
+            Chunk::from_bytes(b"RL").err(),
+            Some(ChunkDecodeError::BadMagic)
+        );
+    }
+
+    #[test]
+    fn reject_future_version() {
+        let mut bytes = Chunk::new().to_bytes();
+        bytes[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(
+            Chunk::from_bytes(&bytes).err(),
+            Some(ChunkDecodeError::UnsupportedVersion(VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn reject_truncated() {
+        let mut chunk = Chunk::new();
+        chunk.write(RET, 0);
+        let bytes = chunk.to_bytes();
+        assert_eq!(
+            Chunk::from_bytes(&bytes[..bytes.len() - 1]).err(),
+            Some(ChunkDecodeError::UnexpectedEof)
+        );
+    }
+}
@@ -0,0 +1,285 @@
+use super::{wide_constant_id, BcInstr, Chunk, ConstantId, Register};
+use crate::vm::REGISTER_MAX;
+use alloc::vec;
+
+/// Reasons a chunk is rejected by `Chunk::verify`. Each carries the offset of the offending
+/// instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyError {
+    /// A constant load refers to an id outside of the constant pool
+    ConstantOutOfRange { offset: usize, id: ConstantId },
+    /// A jump lands outside of the chunk or on an operand of another instruction
+    BadJumpTarget { offset: usize },
+    /// An instruction uses a register outside of the register window
+    RegisterOutOfRange { offset: usize, register: Register },
+    /// A `LoadConstWide` is not followed by its `ExtraArg`
+    MissingExtraArg { offset: usize },
+    /// An `ExtraArg` can be executed on its own
+    StrayExtraArg { offset: usize },
+    /// Execution can run past the last instruction without reaching a `Ret`
+    MissingRet { offset: usize },
+}
+
+impl Chunk {
+    /// Check that the chunk can be run without indexing outside of the code, the constant pool or
+    /// the register window
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        for (offset, instr) in self.code.iter().enumerate() {
+            self.verify_instr(offset, instr)?;
+        }
+
+        self.verify_control_flow()
+    }
+
+    fn verify_instr(&self, offset: usize, instr: &BcInstr) -> Result<(), VerifyError> {
+        // `uses` truncates the arguments of a call to the window, so check the last one here
+        if let BcInstr::Call { callee, arg_count } = *instr {
+            if callee.num() + arg_count as usize >= REGISTER_MAX {
+                let register = Register::new(callee.0.saturating_add(arg_count));
+                return Err(VerifyError::RegisterOutOfRange { offset, register });
+            }
+        }
+
+        let uses = instr.uses();
+        for register in instr.defs().into_iter().chain(uses.iter().copied()) {
+            if register.num() >= REGISTER_MAX {
+                return Err(VerifyError::RegisterOutOfRange { offset, register });
+            }
+        }
+
+        let id = match *instr {
+            BcInstr::LoadConst { id, .. } => id as ConstantId,
+            BcInstr::LoadConstWide { hi, .. } => match self.code.get(offset + 1) {
+                Some(&BcInstr::ExtraArg { lo }) => wide_constant_id(hi, lo),
+                _ => return Err(VerifyError::MissingExtraArg { offset }),
+            },
+            _ => match instr.constant_operand() {
+                Some(id) => id,
+                None => return Ok(()),
+            },
+        };
+
+        if id as usize >= self.constants.len() {
+            return Err(VerifyError::ConstantOutOfRange { offset, id });
+        }
+        Ok(())
+    }
+
+    /// Walk every instruction reachable from the start of the chunk, checking that each one
+    /// transfers control to another instruction or returns
+    fn verify_control_flow(&self) -> Result<(), VerifyError> {
+        let mut reachable = vec![false; self.code.len()];
+        let mut worklist = vec![0];
+        if self.code.is_empty() {
+            return Err(VerifyError::MissingRet { offset: 0 });
+        }
+
+        while let Some(offset) = worklist.pop() {
+            if core::mem::replace(&mut reachable[offset], true) {
+                continue;
+            }
+
+            let instr = self.code[offset];
+            let next = match instr {
+                BcInstr::Ret { .. } => continue,
+                BcInstr::ExtraArg { .. } => return Err(VerifyError::StrayExtraArg { offset }),
+                BcInstr::LoadConstWide { .. } => offset + 2,
+                _ => offset + 1,
+            };
+
+            if let Some(jump) = instr.jump_offset() {
+                let target = next
+                    .checked_add_signed(jump)
+                    .filter(|&target| target < self.code.len())
+                    .ok_or(VerifyError::BadJumpTarget { offset })?;
+                if let BcInstr::ExtraArg { .. } = self.code[target] {
+                    return Err(VerifyError::BadJumpTarget { offset });
+                }
+                worklist.push(target);
+            }
+
+            let falls_through = !matches!(instr, BcInstr::Jump { .. } | BcInstr::Loop { .. });
+            if falls_through {
+                if next >= self.code.len() {
+                    return Err(VerifyError::MissingRet { offset });
+                }
+                worklist.push(next);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::value::Value;
+
+    const RET: BcInstr = BcInstr::Ret {
+        src: Register::ret(),
+    };
+
+    #[test]
+    fn verify_valid_chunk() {
+        let r0 = Register::ret();
+        let mut chunk = Chunk::new();
+        let id = chunk.add_constant(Value::Number(1.0));
+        chunk.write_load_const(r0, id, 0);
+        chunk.write(
+            BcInstr::JumpIfFalse {
+                cond: r0,
+                offset: 1,
+            },
+            0,
+        );
+        chunk.write(BcInstr::Loop { offset: 2 }, 0);
+        chunk.write(RET, 0);
+
+        assert_eq!(chunk.verify(), Ok(()));
+    }
+
+    #[test]
+    fn reject_bad_constant() {
+        let mut chunk = Chunk::new();
+        chunk.write(
+            BcInstr::LoadConst {
+                dest: Register::ret(),
+                id: 3,
+            },
+            0,
+        );
+        chunk.write(RET, 0);
+        assert_eq!(
+            chunk.verify(),
+            Err(VerifyError::ConstantOutOfRange { offset: 0, id: 3 })
+        );
+
+        let mut chunk = Chunk::new();
+        chunk.write(
+            BcInstr::LoadConstWide {
+                dest: Register::ret(),
+                hi: 1,
+            },
+            0,
+        );
+        chunk.write(RET, 0);
+        assert_eq!(
+            chunk.verify(),
+            Err(VerifyError::MissingExtraArg { offset: 0 })
+        );
+
+        let mut chunk = Chunk::new();
+        chunk.write(
+            BcInstr::GetGlobal {
+                dest: Register::ret(),
+                name: 0,
+            },
+            0,
+        );
+        chunk.write(RET, 0);
+        assert_eq!(
+            chunk.verify(),
+            Err(VerifyError::ConstantOutOfRange { offset: 0, id: 0 })
+        );
+    }
+
+    #[test]
+    fn reject_bad_register() {
+        let register = Register::new(REGISTER_MAX as u8);
+        let mut chunk = Chunk::new();
+        chunk.write(
+            BcInstr::LoadNil {
+                dest: Register::ret(),
+            },
+            0,
+        );
+        chunk.write(BcInstr::Print { src: register }, 0);
+        chunk.write(RET, 0);
+        assert_eq!(
+            chunk.verify(),
+            Err(VerifyError::RegisterOutOfRange {
+                offset: 1,
+                register
+            })
+        );
+
+        // Arguments of a call must also fit in the window of the caller
+        let mut chunk = Chunk::new();
+        chunk.write(
+            BcInstr::Call {
+                callee: Register::new(10),
+                arg_count: 200,
+            },
+            0,
+        );
+        chunk.write(RET, 0);
+        assert_eq!(
+            chunk.verify(),
+            Err(VerifyError::RegisterOutOfRange {
+                offset: 0,
+                register: Register::new(210)
+            })
+        );
+    }
+
+    #[test]
+    fn reject_bad_jumps() {
+        let mut chunk = Chunk::new();
+        chunk.write(BcInstr::Jump { offset: 5 }, 0);
+        chunk.write(RET, 0);
+        assert_eq!(
+            chunk.verify(),
+            Err(VerifyError::BadJumpTarget { offset: 0 })
+        );
+
+        // Jumping into the middle of a wide load
+        let mut chunk = Chunk::new();
+        chunk.write(BcInstr::Jump { offset: 1 }, 0);
+        chunk.write(
+            BcInstr::LoadConstWide {
+                dest: Register::ret(),
+                hi: 0,
+            },
+            0,
+        );
+        chunk.write(BcInstr::ExtraArg { lo: 0 }, 0);
+        chunk.write(RET, 0);
+        chunk.add_constant(Value::Number(0.0));
+        assert_eq!(
+            chunk.verify(),
+            Err(VerifyError::BadJumpTarget { offset: 0 })
+        );
+    }
+
+    #[test]
+    fn reject_missing_ret() {
+        assert_eq!(
+            Chunk::new().verify(),
+            Err(VerifyError::MissingRet { offset: 0 })
+        );
+
+        let mut chunk = Chunk::new();
+        chunk.write(
+            BcInstr::LoadNil {
+                dest: Register::ret(),
+            },
+            0,
+        );
+        chunk.write(
+            BcInstr::JumpIfFalse {
+                cond: Register::ret(),
+                offset: 1,
+            },
+            0,
+        );
+        chunk.write(RET, 0);
+        chunk.write(
+            BcInstr::Print {
+                src: Register::ret(),
+            },
+            0,
+        );
+        assert_eq!(chunk.verify(), Err(VerifyError::MissingRet { offset: 3 }));
+    }
+}
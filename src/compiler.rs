@@ -0,0 +1,739 @@
+use crate::bytecode::{BcInstr, Chunk, Register};
+use crate::scanner::{Scanner, Token, TokenKind};
+use crate::value::Value;
+use crate::vm::{REGISTER_MAX, SPILL_MAX};
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+/// Kind of a compilation error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompileErrorKind {
+    /// The source is not a valid program
+    Syntax,
+    /// An expression needs the values of more operands at once than fit in the registers and
+    /// spill slots of a function
+    RegisterExhausted,
+}
+
+/// Error that stopped the compilation of a program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileError {
+    pub kind: CompileErrorKind,
+    /// Description of the error, along with the token it was found at
+    pub message: String,
+    /// Source line of the token the error was found at
+    pub line: usize,
+}
+
+impl core::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "[line {}] {}", self.line, self.message)
+    }
+}
+
+/// Register kept free to reload a spilled operand into, right before the operator using it
+const SPILL_REGISTER: Register = Register::new(REGISTER_MAX as u8 - 1);
+
+/// Binding power of the operators, from the loosest to the tightest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Precedence {
+    None,
+    Assignment,
+    Or,
+    And,
+    Equality,
+    Comparison,
+    Term,
+    Factor,
+    Unary,
+    Call,
+    Primary,
+}
+
+impl Precedence {
+    /// The next tighter precedence, used to parse the right operand of left-associative operators
+    fn next(self) -> Precedence {
+        match self {
+            Precedence::None => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary => Precedence::Call,
+            Precedence::Call | Precedence::Primary => Precedence::Primary,
+        }
+    }
+}
+
+/// Parses the expression starting at the previous token into the register passed to it
+type ParseFn<'a> = fn(&mut Compiler<'a>, Register) -> Result<(), CompileError>;
+
+/// How a token is parsed at the start of an expression and after an operand
+struct ParseRule<'a> {
+    prefix: Option<ParseFn<'a>>,
+    infix: Option<ParseFn<'a>>,
+    precedence: Precedence,
+}
+
+impl<'a> ParseRule<'a> {
+    fn new(
+        prefix: Option<ParseFn<'a>>,
+        infix: Option<ParseFn<'a>>,
+        precedence: Precedence,
+    ) -> Self {
+        ParseRule {
+            prefix,
+            infix,
+            precedence,
+        }
+    }
+}
+
+/// Compile the Lox script in `source`, a sequence of expressions separated by `;`, into a chunk
+/// returning the value of the last one. Compilation carries on after an error from the next
+/// statement, so every error found is returned. The instructions `Chunk::fuse` can combine are
+/// fused, such as a product on either side of `+` into a `MulAdd`
+pub fn compile(source: &str) -> Result<Chunk, Vec<CompileError>> {
+    let mut compiler = Compiler::new(source);
+    compiler.advance();
+    while compiler.current.kind != TokenKind::Eof {
+        if let Err(e) = compiler.statement() {
+            compiler.errors.push(e);
+            compiler.synchronize();
+        }
+    }
+
+    if !compiler.errors.is_empty() {
+        return Err(compiler.errors);
+    }
+    if compiler.chunk.instrs().is_empty() {
+        compiler.emit(BcInstr::LoadNil {
+            dest: Register::ret(),
+        });
+    }
+    compiler.emit(BcInstr::Ret {
+        src: Register::ret(),
+    });
+    compiler.chunk.fuse();
+    Ok(compiler.chunk)
+}
+
+/// Single-pass compiler, parsing the tokens with a Pratt parser and writing the instructions of
+/// each expression as soon as it is parsed. Each operand gets a free register of its own, which
+/// is freed again once the operator using it is written. Once every register is taken, the left
+/// operands of the operators being compiled are spilled instead
+struct Compiler<'a> {
+    scanner: Scanner<'a>,
+    current: Token<'a>,
+    previous: Token<'a>,
+    chunk: Chunk,
+    /// Errors found so far
+    errors: Vec<CompileError>,
+    /// Registers holding the value of an expression being compiled. `SPILL_REGISTER` is never
+    /// allocated
+    used_registers: [bool; REGISTER_MAX],
+    /// Number of spill slots holding the left operand of an operator being compiled
+    spill_slots: usize,
+    /// Offset in the source of the left operand of the infix operator being compiled
+    operand_start: usize,
+    /// Offset of the instruction the last patched jump lands on
+    jump_target: Option<usize>,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(source: &'a str) -> Self {
+        let start = Token {
+            kind: TokenKind::Eof,
+            lexeme: "",
+            line: 1,
+            start: 0,
+        };
+        Compiler {
+            scanner: Scanner::new(source),
+            current: start,
+            previous: start,
+            chunk: Chunk::new(),
+            errors: Vec::new(),
+            used_registers: [false; REGISTER_MAX],
+            spill_slots: 0,
+            operand_start: 0,
+            jump_target: None,
+        }
+    }
+
+    /// Compile an expression statement. Its value is left in the return register, so the script
+    /// returns the value of the last one. The `;` after the last statement is optional
+    fn statement(&mut self) -> Result<(), CompileError> {
+        // Every statement starts with all the registers free, even after an error
+        self.used_registers = [false; REGISTER_MAX];
+        self.spill_slots = 0;
+        let dest = self.alloc_register().unwrap();
+        self.expression(dest)?;
+
+        if self.current.kind != TokenKind::Eof {
+            self.consume(TokenKind::Semicolon, "Expect ';' after expression.")?;
+        }
+        Ok(())
+    }
+
+    fn expression(&mut self, dest: Register) -> Result<(), CompileError> {
+        self.parse_precedence(Precedence::Assignment, dest)
+    }
+
+    /// Parse the expression starting at the current token into `dest`, stopping at the first
+    /// operator binding looser than `precedence`
+    fn parse_precedence(
+        &mut self,
+        precedence: Precedence,
+        dest: Register,
+    ) -> Result<(), CompileError> {
+        self.advance();
+        let start = self.previous.start;
+        let prefix = Self::rule(self.previous.kind)
+            .prefix
+            .ok_or_else(|| self.error_at(self.previous, "Expect expression."))?;
+        prefix(self, dest)?;
+
+        while precedence <= Self::rule(self.current.kind).precedence {
+            self.advance();
+            self.operand_start = start;
+            // Tokens with a precedence are always infix operators
+            let infix = Self::rule(self.previous.kind).infix.unwrap();
+            infix(self, dest)?;
+        }
+        Ok(())
+    }
+
+    fn rule(kind: TokenKind) -> ParseRule<'a> {
+        match kind {
+            TokenKind::LeftParen => ParseRule::new(Some(Self::grouping), None, Precedence::None),
+            TokenKind::Minus => {
+                ParseRule::new(Some(Self::unary), Some(Self::binary), Precedence::Term)
+            }
+            TokenKind::Plus => ParseRule::new(None, Some(Self::binary), Precedence::Term),
+            TokenKind::Slash | TokenKind::Star | TokenKind::Percent => {
+                ParseRule::new(None, Some(Self::binary), Precedence::Factor)
+            }
+            TokenKind::Bang => ParseRule::new(Some(Self::unary), None, Precedence::None),
+            TokenKind::And => ParseRule::new(None, Some(Self::and), Precedence::And),
+            TokenKind::Or => ParseRule::new(None, Some(Self::or), Precedence::Or),
+            TokenKind::Number => ParseRule::new(Some(Self::number), None, Precedence::None),
+            TokenKind::False | TokenKind::True | TokenKind::Nil => {
+                ParseRule::new(Some(Self::literal), None, Precedence::None)
+            }
+            _ => ParseRule::new(None, None, Precedence::None),
+        }
+    }
+
+    fn grouping(&mut self, dest: Register) -> Result<(), CompileError> {
+        self.expression(dest)?;
+        self.consume(TokenKind::RightParen, "Expect ')' after expression.")
+    }
+
+    fn unary(&mut self, dest: Register) -> Result<(), CompileError> {
+        let (operator, start) = (self.previous.kind, self.previous.start);
+        self.parse_precedence(Precedence::Unary, dest)?;
+
+        match operator {
+            TokenKind::Minus => self.emit_spanned(BcInstr::Neg { dest, a: dest }, start),
+            TokenKind::Bang => self.emit_spanned(BcInstr::Not { dest, a: dest }, start),
+            _ => unreachable!("Not a unary operator: {:?}", operator),
+        }
+        Ok(())
+    }
+
+    /// Parse the right operand of the operator just consumed, combining it with the left operand
+    /// already in `dest`
+    fn binary(&mut self, dest: Register) -> Result<(), CompileError> {
+        let (operator, start) = (self.previous.kind, self.operand_start);
+        let precedence = Self::rule(operator).precedence.next();
+        if let Some(instr) = self.step_instr(operator, dest) {
+            self.advance();
+            self.emit_spanned(instr, start);
+            return Ok(());
+        }
+
+        // A product on the left is only multiplied once the addend is in `dest`, so that like a
+        // product on the right it is added right after the `Mul`, and the two are fused
+        let deferred = match operator {
+            TokenKind::Plus => self.defer_product(dest),
+            _ => None,
+        };
+        if let Some((factor, product)) = deferred {
+            self.parse_precedence(precedence, dest)?;
+            self.emit(BcInstr::Mul {
+                dest: product,
+                a: factor,
+                b: product,
+            });
+            self.free_register(factor);
+            self.free_register(product);
+            let add = BcInstr::Add {
+                dest,
+                a: product,
+                b: dest,
+            };
+            self.emit_spanned(add, start);
+            return Ok(());
+        }
+
+        let (a, b) = match self.alloc_register() {
+            Some(b) => {
+                self.parse_precedence(precedence, b)?;
+                self.free_register(b);
+                (dest, b)
+            }
+            None => {
+                // The left operand waits in a spill slot while the right one is computed in its
+                // register, and is reloaded right before the operator
+                let slot = self.alloc_spill_slot()?;
+                self.emit(BcInstr::Spill { slot, src: dest });
+                self.parse_precedence(precedence, dest)?;
+                self.emit(BcInstr::Unspill {
+                    dest: SPILL_REGISTER,
+                    slot,
+                });
+                self.spill_slots -= 1;
+                (SPILL_REGISTER, dest)
+            }
+        };
+
+        let instr = match operator {
+            TokenKind::Plus => BcInstr::Add { dest, a, b },
+            TokenKind::Minus => BcInstr::Sub { dest, a, b },
+            TokenKind::Star => BcInstr::Mul { dest, a, b },
+            TokenKind::Slash => BcInstr::Div { dest, a, b },
+            TokenKind::Percent => BcInstr::Mod { dest, a, b },
+            _ => unreachable!("Not a binary operator: {:?}", operator),
+        };
+        self.emit_spanned(instr, start);
+        Ok(())
+    }
+
+    /// Replace the `Mul` just written into `dest` with a copy of its left factor, if it computed
+    /// the whole left operand of the `+` being compiled, so the multiplication can be written after
+    /// the right operand instead. Returns the registers reserved for the left factor and for the
+    /// right one, which the product is written over
+    fn defer_product(&mut self, dest: Register) -> Option<(Register, Register)> {
+        let end = self.chunk.instrs().len();
+        let &BcInstr::Mul {
+            dest: product,
+            a,
+            b,
+        } = self.chunk.instrs().last()?
+        else {
+            return None;
+        };
+        let reusable = product == dest && a == dest && b != dest && !self.used_registers[b.num()];
+        // Code jumping past the `Mul` relies on `dest` holding something else
+        if !reusable || self.jump_target == Some(end) {
+            return None;
+        }
+
+        self.used_registers[b.num()] = true;
+        let Some(factor) = self.alloc_register() else {
+            self.free_register(b);
+            return None;
+        };
+        self.chunk.patch_last(BcInstr::Move {
+            dest: factor,
+            src: dest,
+        });
+        Some((factor, b))
+    }
+
+    /// The `Inc` or `Dec` of `dest` the operator just consumed amounts to, if its right operand is
+    /// the number 1 alone, so `+ 1` and `- 1` don't need to load the 1 into a register
+    fn step_instr(&self, operator: TokenKind, dest: Register) -> Option<BcInstr> {
+        let is_one =
+            self.current.kind == TokenKind::Number && self.current.lexeme.parse() == Ok(1.0);
+        // An operator after the 1 binding tighter than this one takes the 1 as its left operand
+        let next = self.scanner.clone().next_token();
+        if !is_one || Self::rule(next.kind).precedence > Self::rule(operator).precedence {
+            return None;
+        }
+
+        match operator {
+            TokenKind::Plus => Some(BcInstr::Inc { dest }),
+            TokenKind::Minus => Some(BcInstr::Dec { dest }),
+            _ => None,
+        }
+    }
+
+    /// Parse the right operand of `and` into `dest` only if the left operand in `dest` is truthy,
+    /// otherwise leaving the left operand as the result
+    fn and(&mut self, dest: Register) -> Result<(), CompileError> {
+        let end_jump = self.emit_jump(BcInstr::JumpIfFalse {
+            cond: dest,
+            offset: 0,
+        });
+        self.parse_precedence(Precedence::And.next(), dest)?;
+        self.patch_jump(end_jump)
+    }
+
+    /// Parse the right operand of `or` into `dest` only if the left operand in `dest` is falsey,
+    /// otherwise leaving the left operand as the result
+    fn or(&mut self, dest: Register) -> Result<(), CompileError> {
+        let else_jump = self.emit_jump(BcInstr::JumpIfFalse {
+            cond: dest,
+            offset: 0,
+        });
+        let end_jump = self.emit_jump(BcInstr::Jump { offset: 0 });
+        self.patch_jump(else_jump)?;
+
+        self.parse_precedence(Precedence::Or.next(), dest)?;
+        self.patch_jump(end_jump)
+    }
+
+    fn number(&mut self, dest: Register) -> Result<(), CompileError> {
+        // The scanner only produces number tokens made of digits and a dot
+        let value = self.previous.lexeme.parse::<f64>().unwrap();
+        let id = self.chunk.add_constant(Value::Number(value));
+        let offset = self.chunk.instrs().len();
+        self.chunk.write_load_const(dest, id, self.previous.line);
+        self.chunk
+            .set_span(offset, self.span_from(self.previous.start));
+        Ok(())
+    }
+
+    fn literal(&mut self, dest: Register) -> Result<(), CompileError> {
+        let instr = match self.previous.kind {
+            TokenKind::False => BcInstr::LoadFalse { dest },
+            TokenKind::True => BcInstr::LoadTrue { dest },
+            TokenKind::Nil => BcInstr::LoadNil { dest },
+            kind => unreachable!("Not a literal: {:?}", kind),
+        };
+        self.emit_spanned(instr, self.previous.start);
+        Ok(())
+    }
+
+    /// Reserve the lowest free register for the value of an expression, if there is one
+    fn alloc_register(&mut self) -> Option<Register> {
+        let register = self.used_registers[..SPILL_REGISTER.num()]
+            .iter()
+            .position(|&used| !used)?;
+        self.used_registers[register] = true;
+        Some(Register::new(register as u8))
+    }
+
+    /// Reserve the next spill slot for the left operand of an operator
+    fn alloc_spill_slot(&mut self) -> Result<u16, CompileError> {
+        if self.spill_slots == SPILL_MAX {
+            let mut error = self.error_at(self.previous, "Expression too complex.");
+            error.kind = CompileErrorKind::RegisterExhausted;
+            return Err(error);
+        }
+        self.spill_slots += 1;
+        Ok((self.spill_slots - 1) as u16)
+    }
+
+    /// Release `register` once the value in it was consumed, so another operand can reuse it
+    fn free_register(&mut self, register: Register) {
+        assert!(
+            self.used_registers[register.num()],
+            "Register {} is not allocated",
+            register
+        );
+        self.used_registers[register.num()] = false;
+    }
+
+    /// Write `instr`, attributing it to the line of the previous token
+    fn emit(&mut self, instr: BcInstr) {
+        self.chunk.write(instr, self.previous.line);
+    }
+
+    /// Write `instr`, attributing it to the source from `start` to the end of the previous token
+    fn emit_spanned(&mut self, instr: BcInstr, start: usize) {
+        self.emit(instr);
+        let span = self.span_from(start);
+        self.chunk.set_span(self.chunk.instrs().len() - 1, span);
+    }
+
+    /// Write a jump to be patched by `patch_jump`, returning its index. The jump is attributed to
+    /// the previous token, the operator it implements
+    fn emit_jump(&mut self, instr: BcInstr) -> usize {
+        let jump_index = self.chunk.emit_jump(instr, self.previous.line);
+        let span = self.span_from(self.previous.start);
+        self.chunk.set_span(jump_index, span);
+        jump_index
+    }
+
+    /// Range of the source from `start` to the end of the previous token
+    fn span_from(&self, start: usize) -> (usize, usize) {
+        (start, self.previous.start + self.previous.lexeme.len())
+    }
+
+    /// Point the jump at `jump_index` to the next instruction written
+    fn patch_jump(&mut self, jump_index: usize) -> Result<(), CompileError> {
+        self.jump_target = Some(self.chunk.instrs().len());
+        self.chunk
+            .patch_jump(jump_index)
+            .map_err(|_| self.error_at(self.previous, "Too much code to jump over."))
+    }
+
+    /// Move to the next token. Tokens that can't be scanned are reported and skipped, so the
+    /// parser never sees them
+    fn advance(&mut self) {
+        self.previous = self.current;
+        loop {
+            self.current = self.scanner.next_token();
+            if self.current.kind != TokenKind::Error {
+                return;
+            }
+            let error = self.error_at(self.current, self.current.lexeme);
+            self.errors.push(error);
+        }
+    }
+
+    /// Skip the tokens following an error up to the start of the next statement, the token after
+    /// a `;` or a keyword starting a statement, so a single error isn't reported many times
+    fn synchronize(&mut self) {
+        while self.current.kind != TokenKind::Eof {
+            if self.previous.kind == TokenKind::Semicolon {
+                return;
+            }
+            match self.current.kind {
+                TokenKind::Class
+                | TokenKind::Fun
+                | TokenKind::Var
+                | TokenKind::For
+                | TokenKind::If
+                | TokenKind::While
+                | TokenKind::Print
+                | TokenKind::Return => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    /// Consume the current token if it is a `kind`, failing with `message` otherwise
+    fn consume(&mut self, kind: TokenKind, message: &str) -> Result<(), CompileError> {
+        if self.current.kind != kind {
+            return Err(self.error_at(self.current, message));
+        }
+        self.advance();
+        Ok(())
+    }
+
+    fn error_at(&self, token: Token, message: &str) -> CompileError {
+        let location = match token.kind {
+            TokenKind::Eof => " at end".to_string(),
+            // The lexeme of error tokens is the message itself
+            TokenKind::Error => String::new(),
+            _ => format!(" at '{}'", token.lexeme),
+        };
+        CompileError {
+            kind: CompileErrorKind::Syntax,
+            message: format!("Error{}: {}", location, message),
+            line: token.line,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vm::{InterpretResult, VM};
+
+    fn eval(source: &str) -> Value {
+        let chunk = compile(source).expect("Could not compile!");
+        assert_eq!(chunk.verify(), Ok(()));
+
+        let mut vm = VM::new();
+        assert_eq!(vm.interpret(chunk), InterpretResult::Ok);
+        vm.result()
+    }
+
+    #[test]
+    fn compile_arithmetic() {
+        assert_eq!(eval("-(1 + 2) * 3"), Value::Number(-9.0));
+        assert_eq!(eval("1 + 2 * 3 - 8 / (2 + 2)"), Value::Number(5.0));
+        assert_eq!(eval("2 - 1 - 1"), Value::Number(0.0));
+        assert_eq!(eval("7 % 4"), Value::Number(3.0));
+        assert_eq!(eval("!nil"), Value::Bool(true));
+        assert_eq!(eval("!!false"), Value::Bool(false));
+
+        // Scripts return the value of their last expression
+        assert_eq!(eval("1; 2 + 3;"), Value::Number(5.0));
+        assert_eq!(eval(""), Value::Nil);
+    }
+
+    #[test]
+    fn compile_logical() {
+        // Logical operators return the operand deciding the result, without evaluating the right
+        // one if the left one decides it. Negating nil fails if it is evaluated
+        assert_eq!(eval("false and -nil"), Value::Bool(false));
+        assert_eq!(eval("nil and -nil"), Value::Nil);
+        assert_eq!(eval("1 and 2"), Value::Number(2.0));
+        assert_eq!(eval("true or -nil"), Value::Bool(true));
+        assert_eq!(eval("nil or 2"), Value::Number(2.0));
+        assert_eq!(eval("nil or false and -nil"), Value::Bool(false));
+        assert_eq!(eval("1 + 1 or 3"), Value::Number(2.0));
+
+        // Zero is truthy like any other number
+        assert_eq!(eval("!0"), Value::Bool(false));
+        assert_eq!(eval("0 or 2"), Value::Number(0.0));
+        assert_eq!(eval("0 and 2"), Value::Number(2.0));
+    }
+
+    #[test]
+    fn compile_errors() {
+        let error = |source| {
+            let errors = compile(source).err().unwrap();
+            assert_eq!(errors.len(), 1);
+            errors[0].to_string()
+        };
+        assert_eq!(error("1 +"), "[line 1] Error at end: Expect expression.");
+        assert_eq!(
+            error("(1\n+ 2"),
+            "[line 2] Error at end: Expect ')' after expression."
+        );
+        assert_eq!(
+            error("1 2"),
+            "[line 1] Error at '2': Expect ';' after expression."
+        );
+        assert_eq!(error("1 + @ 2"), "[line 1] Error: Unexpected character.");
+
+        // Each nested right operand holds on to another register or spill slot
+        let depth = REGISTER_MAX + SPILL_MAX;
+        let nested = "1 + (".repeat(depth) + "1" + &")".repeat(depth);
+        assert_eq!(
+            error(&nested),
+            "[line 1] Error at '+': Expression too complex."
+        );
+    }
+
+    #[test]
+    fn source_spans() {
+        let source = "-1 * (2 + 3) or nil";
+        let chunk = compile(source).unwrap();
+        let span = |instr: fn(&BcInstr) -> bool| {
+            let offset = chunk.instrs().iter().position(instr).unwrap();
+            let (start, end) = chunk.span(offset).unwrap();
+            &source[start..end]
+        };
+        assert_eq!(span(|instr| matches!(instr, BcInstr::Add { .. })), "2 + 3");
+        assert_eq!(
+            span(|instr| matches!(instr, BcInstr::Mul { .. })),
+            "-1 * (2 + 3)"
+        );
+        assert_eq!(span(|instr| matches!(instr, BcInstr::Neg { .. })), "-1");
+        assert_eq!(
+            span(|instr| matches!(instr, BcInstr::LoadNil { .. })),
+            "nil"
+        );
+        assert_eq!(span(|instr| matches!(instr, BcInstr::Jump { .. })), "or");
+
+        // Instructions that don't come from the source have no span
+        assert_eq!(chunk.span(chunk.instrs().len() - 1), None);
+    }
+
+    #[test]
+    fn step_by_one() {
+        let r0 = Register::ret();
+        let chunk = compile("2 + 1 - 1 + 1").unwrap();
+        assert_eq!(
+            &chunk.instrs()[1..4],
+            [
+                BcInstr::Inc { dest: r0 },
+                BcInstr::Dec { dest: r0 },
+                BcInstr::Inc { dest: r0 }
+            ]
+        );
+        assert_eq!(eval("2 + 1 - 1 + 1"), Value::Number(3.0));
+
+        // The 1 is only the whole right operand when nothing after it binds tighter
+        let chunk = compile("2 + 1 * 3").unwrap();
+        assert!(!chunk
+            .instrs()
+            .iter()
+            .any(|instr| matches!(instr, BcInstr::Inc { .. })));
+        assert_eq!(eval("2 + 1 * 3"), Value::Number(5.0));
+        assert_eq!(eval("2 - 1.0"), Value::Number(1.0));
+    }
+
+    #[test]
+    fn fuse_multiply_add() {
+        // The product is fused on either side of the `+`
+        for source in ["-1 + 0.1 * 10", "0.1 * 10 + -1"] {
+            let chunk = compile(source).unwrap();
+            let ops: Vec<_> = chunk
+                .instrs()
+                .iter()
+                .filter(|instr| matches!(instr, BcInstr::Mul { .. } | BcInstr::MulAdd { .. }))
+                .collect();
+            assert!(matches!(ops[..], [BcInstr::MulAdd { .. }]), "{}", source);
+            assert_eq!(chunk.verify(), Ok(()));
+
+            // Rounding once keeps the error of 0.1, where separate instructions would give 0
+            assert_eq!(eval(source), Value::Number(5.551115123125783e-17));
+        }
+        assert_eq!(eval("2 + 3 * 4 + 5"), Value::Number(19.0));
+        assert_eq!(eval("2 * 3 + 4 * 5"), Value::Number(26.0));
+
+        // A jump landing past the product skips it, so it is left unfused
+        assert_eq!(eval("(0 or 2 * 3) + 1.5"), Value::Number(1.5));
+        assert_eq!(eval("(nil or 2 * 3) + 1.5"), Value::Number(7.5));
+    }
+
+    #[test]
+    fn reuse_registers() {
+        // Operands already consumed give their register back, however deep the expression is
+        let depth = 4 * REGISTER_MAX;
+        let source = "(".repeat(depth) + "1" + &" + 2) * (3 - 2)".repeat(depth);
+        let chunk = compile(&source).unwrap();
+        let mut registers = 0;
+        for instr in chunk.instrs() {
+            for register in instr.defs().into_iter().chain(instr.uses().iter().copied()) {
+                registers = registers.max(register.num() + 1);
+            }
+        }
+        assert_eq!(registers, 3);
+        assert_eq!(eval(&source), Value::Number(1.0 + 2.0 * depth as f64));
+
+        let depth = REGISTER_MAX + SPILL_MAX;
+        let nested = "1 + (".repeat(depth) + "1" + &")".repeat(depth);
+        let errors = compile(&nested).err().unwrap();
+        assert_eq!(errors[0].kind, CompileErrorKind::RegisterExhausted);
+    }
+
+    #[test]
+    fn spill_registers() {
+        // Every nested right operand needs another register, so the innermost ones are spilled.
+        // Subtraction checks the operands are reloaded in the right order
+        let depth = 2 * REGISTER_MAX;
+        let source = (1..=depth)
+            .map(|n| format!("{} - (", n))
+            .collect::<String>()
+            + "0"
+            + &")".repeat(depth);
+        let chunk = compile(&source).unwrap();
+        assert!(chunk
+            .instrs()
+            .iter()
+            .any(|instr| matches!(instr, BcInstr::Spill { .. })));
+
+        let expected = (1..=depth).rev().fold(0.0, |rest, n| n as f64 - rest);
+        assert_eq!(eval(&source), Value::Number(expected));
+    }
+
+    #[test]
+    fn report_every_error() {
+        let errors = compile("1 + ;\n2; (3 * 4;\n@ 5").err().unwrap();
+        let errors: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            errors,
+            [
+                "[line 1] Error at ';': Expect expression.",
+                "[line 2] Error at ';': Expect ')' after expression.",
+                "[line 3] Error: Unexpected character.",
+            ]
+        );
+    }
+}
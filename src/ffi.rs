@@ -0,0 +1,101 @@
+//! C interface for embedding the VM. Every function is safe to call with the pointers returned by
+//! the others, and reports invalid arguments with `RLOX_INVALID_ARGUMENT`. Panics never unwind
+//! into C
+
+use crate::compiler;
+use crate::vm::{InterpretResult, VM};
+use std::ffi::{c_char, CStr};
+use std::panic::{self, AssertUnwindSafe};
+
+pub const RLOX_OK: i32 = 0;
+pub const RLOX_COMPILE_ERROR: i32 = 1;
+pub const RLOX_RUNTIME_ERROR: i32 = 2;
+pub const RLOX_LIMIT_EXCEEDED: i32 = 3;
+pub const RLOX_BREAKPOINT_HIT: i32 = 4;
+/// The VM or source was null, or the source was not valid UTF-8
+pub const RLOX_INVALID_ARGUMENT: i32 = -1;
+/// The VM panicked. It may be left in an inconsistent state, so it should only be freed
+pub const RLOX_PANIC: i32 = -2;
+
+/// Create a VM, which must be freed with `rlox_vm_free`. Returns null if creating it panicked
+#[no_mangle]
+pub extern "C" fn rlox_vm_new() -> *mut VM {
+    panic::catch_unwind(|| Box::into_raw(Box::new(VM::new()))).unwrap_or(std::ptr::null_mut())
+}
+
+/// Compile and run the nul-terminated `source` in `vm`, returning the status of the run
+///
+/// # Safety
+///
+/// `vm` must be null or returned by `rlox_vm_new` and not yet freed, and `source` must be null or
+/// point to a nul-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn rlox_vm_interpret(vm: *mut VM, source: *const c_char) -> i32 {
+    if vm.is_null() || source.is_null() {
+        return RLOX_INVALID_ARGUMENT;
+    }
+    let Ok(source) = CStr::from_ptr(source).to_str() else {
+        return RLOX_INVALID_ARGUMENT;
+    };
+
+    // Unwinding out of an `extern "C"` function aborts the host process
+    let vm = &mut *vm;
+    panic::catch_unwind(AssertUnwindSafe(|| interpret(vm, source))).unwrap_or(RLOX_PANIC)
+}
+
+fn interpret(vm: &mut VM, source: &str) -> i32 {
+    let chunk = match compiler::compile(source) {
+        Ok(chunk) => chunk,
+        Err(_) => return RLOX_COMPILE_ERROR,
+    };
+    match vm.interpret(chunk) {
+        InterpretResult::Ok => RLOX_OK,
+        InterpretResult::CompileErr => RLOX_COMPILE_ERROR,
+        InterpretResult::RuntimeErr(_) => RLOX_RUNTIME_ERROR,
+        InterpretResult::LimitExceeded => RLOX_LIMIT_EXCEEDED,
+        InterpretResult::BreakpointHit(_) => RLOX_BREAKPOINT_HIT,
+    }
+}
+
+/// Free a VM created with `rlox_vm_new`. Freeing null does nothing
+///
+/// # Safety
+///
+/// `vm` must be null or returned by `rlox_vm_new` and not yet freed
+#[no_mangle]
+pub unsafe extern "C" fn rlox_vm_free(vm: *mut VM) {
+    if !vm.is_null() {
+        // A panicking destructor leaks whatever it didn't free rather than aborting
+        let vm = AssertUnwindSafe(Box::from_raw(vm));
+        let _ = panic::catch_unwind(move || drop(vm));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn interpret_through_ffi() {
+        let vm = rlox_vm_new();
+        unsafe {
+            assert_eq!(rlox_vm_interpret(vm, c"1 + 2".as_ptr()), RLOX_OK);
+            assert_eq!(rlox_vm_interpret(vm, c"1 +".as_ptr()), RLOX_COMPILE_ERROR);
+            assert_eq!(rlox_vm_interpret(vm, c"-nil".as_ptr()), RLOX_RUNTIME_ERROR);
+
+            let invalid = [0xff_u8, 0];
+            let invalid = invalid.as_ptr().cast::<c_char>();
+            assert_eq!(rlox_vm_interpret(vm, invalid), RLOX_INVALID_ARGUMENT);
+            let null = std::ptr::null();
+            assert_eq!(rlox_vm_interpret(vm, null), RLOX_INVALID_ARGUMENT);
+            let null_vm = std::ptr::null_mut();
+            assert_eq!(
+                rlox_vm_interpret(null_vm, c"1".as_ptr()),
+                RLOX_INVALID_ARGUMENT
+            );
+
+            rlox_vm_free(vm);
+            rlox_vm_free(std::ptr::null_mut());
+        }
+    }
+}
@@ -1,80 +1,545 @@
-use super::bump_alloc::{BumpBlock, ManagedPtr};
-use super::memory::AllocError;
-use super::policy::AllocationPolicy;
+use super::bump_alloc::{BlockState, BumpBlock, ManagedPtr};
+use super::linemap::LineMap;
+use super::memory::{AllocError, Block, BlockPool, BlockSize};
+use super::policy::BlockGeometry;
+use alloc::{vec, vec::Vec};
+use core::ptr::NonNull;
+use hashbrown::HashMap;
 
-/// List of `BumpBlock`s that have been allocated, in address-order.
-pub struct BlockList<A: AllocationPolicy> {
-    blocks: Vec<BumpBlock<A>>,
+/// The lists a block can be filed in
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum List {
+    Free,
+    Recyclable,
+    Full,
+    ToSpace,
 }
 
-impl<A: AllocationPolicy> BlockList<A> {
-    pub fn new() -> Self {
-        BlockList { blocks: Vec::new() }
+/// `BumpBlock`s that have been allocated, filed by how much room they have left. Blocks move
+/// between the lists as objects are freed by `dealloc` or `sweep`, and as allocation fills them up.
+pub struct BlockList<A: BlockGeometry> {
+    /// Blocks without any used line
+    free: Vec<BumpBlock<A>>,
+
+    /// Partially used blocks. Objects are allocated from the last one, the head of the list
+    recyclable: Vec<BumpBlock<A>>,
+
+    /// Blocks with no room left for the objects allocated from them
+    full: Vec<BumpBlock<A>>,
+
+    /// Blocks holding the objects evacuated by the current collection
+    to_space: Vec<BumpBlock<A>>,
+
+    /// Where each block is filed, by the address of the block. Blocks are aligned to their size, so
+    /// masking a pointer gives the address of the block that owns it
+    owners: HashMap<usize, (List, usize)>,
+
+    /// Slots free to allocate small objects in, for each size class. Slots are carved out of lines
+    /// allocated for their class, and forgotten when the lines are marked again
+    slots: Vec<Vec<NonNull<u8>>>,
+
+    /// Released blocks, reused before allocating new ones
+    pool: BlockPool,
+
+    /// Sizes of the blocks and of their lines
+    geometry: A,
+}
+
+impl<A: BlockGeometry> BlockList<A> {
+    pub fn new(geometry: A) -> Self {
+        BlockList {
+            free: Vec::new(),
+            recyclable: Vec::new(),
+            full: Vec::new(),
+            to_space: Vec::new(),
+            owners: HashMap::new(),
+            slots: vec![Vec::new(); geometry.size_classes().len()],
+            pool: BlockPool::new(0),
+            geometry,
+        }
+    }
+
+    /// Keep up to `pooled_blocks` of the blocks released, to reuse them instead of going back to
+    /// the global allocator
+    pub fn with_pool(mut self, pooled_blocks: usize) -> Self {
+        self.pool = BlockPool::new(pooled_blocks);
+        self
     }
 
-    /// Allocate a block of size `bytes` from the BlockList. Will allocate from the first block
-    /// that fits
-    pub fn alloc(&mut self, bytes: usize) -> Result<ManagedPtr, AllocError> {
-        for block in self.blocks.iter_mut() {
-            if let Some(ptr) = block.inner_alloc(bytes) {
+    /// Number of blocks requested from the global allocator
+    pub fn allocated_blocks(&self) -> usize {
+        self.pool.allocated()
+    }
+
+    pub fn geometry(&self) -> A {
+        self.geometry
+    }
+
+    /// Take a block of `size` bytes from the pool, or a new one. Used for the blocks of large
+    /// objects, which are released back with `release_block`
+    pub fn take_block(&mut self, size: BlockSize) -> Result<Block, AllocError> {
+        self.pool.take(size)
+    }
+
+    pub fn release_block(&mut self, block: Block) {
+        self.pool.release(block);
+    }
+
+    /// Allocate a block of size `bytes`, aligned to `align`, from the BlockList. Objects fitting a
+    /// size class get a slot of that size. Otherwise, allocates from the head recyclable block,
+    /// retiring it to the full blocks when the object doesn't fit, then from a free block, and
+    /// finally from a new block. Fails if a new block is needed and `max_blocks` have already
+    /// been allocated, or if the object is larger than a block
+    pub fn alloc(&mut self, bytes: usize, align: usize) -> Result<ManagedPtr, AllocError> {
+        let line_size = self.geometry.line_size();
+        let class = self
+            .geometry
+            .size_classes()
+            .iter()
+            .position(|&size| size >= bytes.max(align) && size < line_size);
+        match class {
+            Some(class) => self.alloc_slot(class),
+            None => self.alloc_lines(bytes, align),
+        }
+    }
+
+    /// Allocate a slot of the size class `class`, splitting a new line into slots if none is free.
+    /// Slots are aligned to their size, as lines are aligned to theirs
+    fn alloc_slot(&mut self, class: usize) -> Result<ManagedPtr, AllocError> {
+        let size = self.geometry.size_classes()[class];
+        if let Some(slot) = self.slots[class].pop() {
+            return Ok(ManagedPtr::new(slot, size));
+        }
+
+        let line_size = self.geometry.line_size();
+        let line = self.alloc_lines(line_size, 1)?.as_ptr();
+        // Pushed in reverse, so the slots are handed out in address order
+        for offset in (size..line_size).step_by(size).rev() {
+            self.slots[class].push(unsafe { NonNull::new_unchecked(line.add(offset)) });
+        }
+        Ok(ManagedPtr::new(
+            unsafe { NonNull::new_unchecked(line) },
+            size,
+        ))
+    }
+
+    /// Allocate whole lines for `bytes`, see `alloc`
+    fn alloc_lines(&mut self, bytes: usize, align: usize) -> Result<ManagedPtr, AllocError> {
+        while let Some(block) = self.recyclable.last_mut() {
+            if let Some(ptr) = block.inner_alloc_aligned(bytes, align) {
                 return Ok(ptr);
             }
+            let block = self.pop(List::Recyclable).unwrap();
+            self.push(List::Full, block);
+        }
+
+        let mut block = self.take_free()?;
+        let ptr = block
+            .inner_alloc_aligned(bytes, align)
+            .ok_or(AllocError::OutOfMemory);
+        match ptr {
+            Ok(_) => self.push(List::Recyclable, block),
+            Err(_) => self.push(List::Free, block),
+        }
+        ptr
+    }
+
+    /// Choose the partially used blocks with at least `threshold` of their lines unused as the
+    /// blocks to evacuate, before their lines are cleared for marking. Returns `true` if any block
+    /// was chosen
+    pub fn begin_evacuation(&mut self, threshold: f64) -> bool {
+        let mut any = false;
+        for block in self.recyclable.iter_mut() {
+            let evacuate = block.fragmentation() >= threshold;
+            block.set_evacuating(evacuate);
+            any |= evacuate;
+        }
+        any
+    }
+
+    /// Returns `true` if the object at `ptr` has to be moved out of its block
+    pub fn is_evacuating(&self, ptr: &ManagedPtr) -> bool {
+        self.owner(ptr)
+            .is_some_and(|(list, index)| self.list(list)[index].is_evacuating())
+    }
+
+    /// Allocate room for an evacuated object. Every line of the blocks in use is unmarked while
+    /// marking, so evacuated objects only go to free blocks and new ones
+    pub fn alloc_evacuated(
+        &mut self,
+        bytes: usize,
+        align: usize,
+    ) -> Result<ManagedPtr, AllocError> {
+        if let Some(ptr) = self
+            .to_space
+            .last_mut()
+            .and_then(|block| block.inner_alloc_aligned(bytes, align))
+        {
+            return Ok(ptr);
+        }
+
+        let mut block = self.take_free()?;
+        let ptr = block
+            .inner_alloc_aligned(bytes, align)
+            .ok_or(AllocError::OutOfMemory);
+        match ptr {
+            Ok(_) => self.push(List::ToSpace, block),
+            Err(_) => self.push(List::Free, block),
+        }
+        ptr
+    }
+
+    /// Mark every line as unused, before marking the lines of the live objects. The free slots are
+    /// forgotten, as their lines may be freed and reused
+    pub fn clear_marks(&mut self) {
+        for slots in self.slots.iter_mut() {
+            slots.clear();
+        }
+        for block in self
+            .free
+            .iter_mut()
+            .chain(self.recyclable.iter_mut())
+            .chain(self.full.iter_mut())
+            .chain(self.to_space.iter_mut())
+        {
+            block.clear_marks();
+        }
+    }
+
+    /// Mark the lines occupied by `ptr` as used. Returns `false` if no block contains it
+    pub fn mark(&mut self, ptr: ManagedPtr) -> bool {
+        match self.owner(&ptr) {
+            // Evacuated objects got their lines when they were allocated, and marking the line
+            // past them would take it from the objects still being evacuated
+            Some((List::ToSpace, _)) => true,
+            Some((list, index)) => {
+                self.list_mut(list)[index].mark(ptr);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Number of holes per unused line across the partially used blocks, from close to 0 when each
+    /// block has a single hole to 1 when no two unused lines are adjacent. Returns 0 without any
+    /// unused line
+    pub fn fragmentation(&self) -> f64 {
+        let (lines, holes) = self
+            .recyclable
+            .iter()
+            .map(|block| block.holes())
+            .fold((0, 0), |(lines, holes), (l, h)| (lines + l, holes + h));
+        if lines == 0 {
+            0.0
+        } else {
+            holes as f64 / lines as f64
         }
+    }
 
-        self.blocks.push(BumpBlock::<A>::new()?);
-        let new_block = self.blocks.last_mut().unwrap();
+    /// Every block, with the address of its memory and the map of its used lines
+    pub fn line_maps(&self) -> impl Iterator<Item = (usize, &LineMap)> + '_ {
+        self.free
+            .iter()
+            .chain(&self.recyclable)
+            .chain(&self.full)
+            .chain(&self.to_space)
+            .map(|block| (block.address(), block.used_lines()))
+    }
 
-        Ok(new_block.inner_alloc(bytes).expect(&format!(
-            "Object too large to allocate in {:?} bytes",
-            A::BLOCK_SIZE_BYTES
-        )))
+    /// Returns `true` if `ptr` only spans used lines of its block, `None` if no block contains it
+    pub fn is_allocated(&self, ptr: &ManagedPtr) -> Option<bool> {
+        self.owner(ptr)
+            .map(|(list, index)| self.list(list)[index].is_allocated(ptr))
+    }
+
+    /// File every block according to the lines left marked. Up to `max_free_blocks` blocks without
+    /// a single marked line are kept as free blocks, to be reused before allocating new ones, and
+    /// the others are released to the pool
+    pub fn sweep(&mut self, max_free_blocks: usize) {
+        let blocks: Vec<_> = self
+            .free
+            .drain(..)
+            .chain(self.recyclable.drain(..))
+            .chain(self.full.drain(..))
+            .chain(self.to_space.drain(..))
+            .collect();
+        self.owners.clear();
+        for mut block in blocks {
+            block.set_evacuating(false);
+            #[cfg(feature = "zero-on-free")]
+            block.zero_unused_lines();
+            self.file(block);
+        }
+        while self.free.len() > max_free_blocks {
+            let block = self.pop(List::Free).unwrap();
+            self.pool.release(block.into_block());
+        }
     }
 
     /// Deallocate the `ptr`. This is not necessary as tracing will "implicitly" deallocate objects
     /// when they are no longer used. At the beginning of tracing, we mark the whole line map as
     /// unused, then trace through object roots marking used locations. At the end of tracing, we
-    /// remove unused blocks.
+    /// file the blocks by the lines left used. Every line spanned by `ptr` is freed, so objects in
+    /// size class slots can't be deallocated this way.
+    #[cfg(test)]
     pub fn dealloc(&mut self, ptr: ManagedPtr) {
-        for block in self.blocks.iter_mut() {
-            if block.contains(&ptr) {
-                block.inner_dealloc(ptr);
-                return;
+        let (list, index) = self
+            .owner(&ptr)
+            .expect("ManagedPtr is not owned by the BlockList!");
+        let mut block = self.remove(list, index);
+        block.inner_dealloc(ptr);
+        self.file(block);
+    }
+
+    /// Take a free block, or one from the pool, or allocate a new one if there are none
+    fn take_free(&mut self) -> Result<BumpBlock<A>, AllocError> {
+        match self.pop(List::Free) {
+            Some(block) => Ok(block),
+            None if self.owners.len() >= self.geometry.max_blocks() => Err(AllocError::OutOfMemory),
+            None => {
+                let mem = self.pool.take(self.geometry.block_size())?;
+                Ok(BumpBlock::new(mem, self.geometry))
             }
         }
+    }
+
+    /// Returns the list and index of the block that owns `ptr`, if any
+    fn owner(&self, ptr: &ManagedPtr) -> Option<(List, usize)> {
+        let address = ptr.as_ptr() as usize & !(self.geometry.block_size() - 1);
+        self.owners.get(&address).copied()
+    }
+
+    /// Add `block` to the list matching its state
+    fn file(&mut self, block: BumpBlock<A>) {
+        let list = match block.get_block_state() {
+            BlockState::Free => List::Free,
+            BlockState::Recyclable => List::Recyclable,
+            BlockState::Unavailable => List::Full,
+        };
+        self.push(list, block);
+    }
+
+    fn push(&mut self, list: List, block: BumpBlock<A>) {
+        let index = self.list(list).len();
+        self.owners.insert(block.address(), (list, index));
+        self.list_mut(list).push(block);
+    }
 
-        panic!("ManagedPtr is not owned by the BlockList!");
+    fn pop(&mut self, list: List) -> Option<BumpBlock<A>> {
+        let block = self.list_mut(list).pop()?;
+        self.owners.remove(&block.address());
+        Some(block)
+    }
+
+    /// Remove the block at `index` of `list`, moving the last block of the list into its place
+    #[cfg(test)]
+    fn remove(&mut self, list: List, index: usize) -> BumpBlock<A> {
+        let block = self.list_mut(list).swap_remove(index);
+        self.owners.remove(&block.address());
+        if let Some(moved) = self.list(list).get(index) {
+            self.owners.insert(moved.address(), (list, index));
+        }
+        block
+    }
+
+    fn list(&self, list: List) -> &Vec<BumpBlock<A>> {
+        match list {
+            List::Free => &self.free,
+            List::Recyclable => &self.recyclable,
+            List::Full => &self.full,
+            List::ToSpace => &self.to_space,
+        }
+    }
+
+    fn list_mut(&mut self, list: List) -> &mut Vec<BumpBlock<A>> {
+        match list {
+            List::Free => &mut self.free,
+            List::Recyclable => &mut self.recyclable,
+            List::Full => &mut self.full,
+            List::ToSpace => &mut self.to_space,
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::immix::policy::AllocationPolicy;
     use crate::immix::test_allocator::TestAllocator;
 
     #[test]
     fn alloc_dealloc_blocks() {
-        let mut blist = BlockList::<TestAllocator>::new();
+        let mut blist = BlockList::new(TestAllocator);
 
         let mut ptrs = Vec::new();
         for _ in 0..10 {
-            ptrs.push(blist.alloc(64).expect("Could not allocate block!"));
+            ptrs.push(blist.alloc(64, 1).expect("Could not allocate block!"));
         }
-        // We should have 3 blocks in our list
-        assert_eq!(blist.blocks.len(), 3);
+        // We should have 2 full blocks, and a third one we are allocating from
+        assert_eq!(blist.full.len(), 2);
+        assert_eq!(blist.recyclable.len(), 1);
 
-        for i in 0..ptrs.len() {
-            assert!(blist.blocks[i / 4].contains(&ptrs[i]));
+        for (i, ptr) in ptrs.iter().enumerate() {
+            let block = match i / 4 {
+                2 => &blist.recyclable[0],
+                full => &blist.full[full],
+            };
+            assert!(block.contains(ptr));
         }
 
         for ptr in ptrs.into_iter() {
             blist.dealloc(ptr);
         }
+        assert_eq!(blist.free.len(), 3);
 
         let mut ptrs = Vec::new();
         for _ in 0..10 {
-            ptrs.push(blist.alloc(64).expect("Could not allocate block!"));
+            ptrs.push(blist.alloc(64, 1).expect("Could not allocate block!"));
         }
         // We should *still* have 3 blocks in our list - we deallocated them and re-used the others
-        assert_eq!(blist.blocks.len(), 3);
+        assert_eq!(blist.free.len(), 0);
+        assert_eq!(blist.full.len() + blist.recyclable.len(), 3);
+    }
+
+    #[test]
+    fn pack_size_classes() {
+        /// Blocks of four lines, split into slots of 16 or 32 bytes for small objects
+        #[derive(Clone, Copy, Default)]
+        struct Packed;
+        impl AllocationPolicy for Packed {
+            const BLOCK_SIZE_BYTES: usize = 256;
+            const LINE_SIZE_BYTES: usize = 64;
+            const SIZE_CLASSES: &'static [usize] = &[16, 32];
+        }
+
+        // Four objects fit in each line, and sixteen in each block
+        let mut blist = BlockList::new(Packed);
+        let ptrs: Vec<_> = (0..64)
+            .map(|_| blist.alloc(16, 8).expect("Could not allocate object!"))
+            .collect();
+        assert_eq!(blist.owners.len(), 4);
+        for block in ptrs.chunks(16) {
+            for pair in block.windows(2) {
+                assert_eq!(pair[1].as_ptr() as usize - pair[0].as_ptr() as usize, 16);
+            }
+        }
+
+        // Objects of other sizes don't share the lines of a size class
+        let ptr = blist.alloc(20, 8).expect("Could not allocate object!");
+        assert_eq!(blist.owners.len(), 5);
+        assert_eq!(ptr.as_ptr() as usize % Packed::LINE_SIZE_BYTES, 0);
+        let line = blist.alloc(48, 8).expect("Could not allocate object!");
+        assert_eq!(line.as_ptr() as usize - ptr.as_ptr() as usize, 64);
+    }
+
+    #[test]
+    fn dealloc_from_many_blocks() {
+        let mut blist = BlockList::new(TestAllocator);
+
+        let ptrs: Vec<_> = (0..64 * TestAllocator::LINES_PER_BLOCK)
+            .map(|_| blist.alloc(64, 1).expect("Could not allocate block!"))
+            .collect();
+        assert_eq!(blist.full.len() + blist.recyclable.len(), 64);
+
+        // Free every other object, then the rest, so blocks move between the lists while their
+        // owners are looked up
+        for ptr in ptrs.iter().step_by(2).chain(ptrs.iter().skip(1).step_by(2)) {
+            let (list, index) = blist.owner(ptr).expect("Block not found!");
+            assert!(blist.list(list)[index].contains(ptr));
+            blist.dealloc(*ptr);
+        }
+        assert_eq!(blist.free.len(), 64);
+        assert!(blist.recyclable.is_empty() && blist.full.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "ManagedPtr is not owned by the BlockList!")]
+    fn dealloc_unowned_ptr() {
+        let mut blist = BlockList::new(TestAllocator);
+        blist.alloc(64, 1).expect("Could not allocate block!");
+
+        let mut other = BlockList::new(TestAllocator);
+        let ptr = other.alloc(64, 1).expect("Could not allocate block!");
+        blist.dealloc(ptr);
+    }
+
+    #[test]
+    #[cfg(feature = "zero-on-free")]
+    fn zero_freed_lines() {
+        const SENTINEL: u8 = 0xAB;
+        let mut blist = BlockList::new(TestAllocator);
+        let ptrs: Vec<_> = (0..3)
+            .map(|_| {
+                let ptr = blist.alloc(64, 1).expect("Could not allocate block!");
+                unsafe { core::ptr::write_bytes(ptr.as_ptr(), SENTINEL, 64) };
+                ptr
+            })
+            .collect();
+        let read = |ptr: &ManagedPtr| unsafe { core::slice::from_raw_parts(ptr.as_ptr(), 64) };
+
+        // Only the first object is reachable, keeping the line after it
+        blist.clear_marks();
+        blist.mark(ptrs[0]);
+        blist.sweep(usize::MAX);
+        assert!(read(&ptrs[0]).iter().all(|&b| b == SENTINEL));
+        assert!(read(&ptrs[1]).iter().all(|&b| b == SENTINEL));
+        assert!(read(&ptrs[2]).iter().all(|&b| b == 0));
+
+        blist.dealloc(ptrs[0]);
+        assert!(read(&ptrs[0]).iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn sweep_unreachable_blocks() {
+        let mut blist = BlockList::new(TestAllocator);
+        for _ in 0..10 {
+            blist.alloc(64, 1).expect("Could not allocate block!");
+        }
+
+        // Nothing is reachable without roots, so every block is free
+        blist.clear_marks();
+        blist.sweep(usize::MAX);
+        assert!(blist.recyclable.is_empty() && blist.full.is_empty());
+        assert_eq!(blist.free.len(), 3);
+    }
+
+    #[test]
+    fn reuse_pooled_blocks() {
+        let mut blist = BlockList::new(TestAllocator).with_pool(1);
+        for _ in 0..2 * TestAllocator::LINES_PER_BLOCK {
+            blist.alloc(64, 1).expect("Could not allocate block!");
+        }
+        assert_eq!(blist.allocated_blocks(), 2);
+
+        // One of the released blocks is pooled, and reused with all of its lines unused
+        blist.clear_marks();
+        blist.sweep(0);
+        assert!(blist.owners.is_empty());
+        let ptrs: Vec<_> = (0..TestAllocator::LINES_PER_BLOCK)
+            .map(|_| blist.alloc(64, 1).expect("Could not allocate block!"))
+            .collect();
+        assert_eq!(blist.allocated_blocks(), 2);
+        assert_eq!(
+            ptrs[0].as_ptr() as usize % TestAllocator::BLOCK_SIZE_BYTES,
+            0
+        );
+
+        // The pool is empty again
+        blist.alloc(64, 1).expect("Could not allocate block!");
+        assert_eq!(blist.allocated_blocks(), 3);
+    }
+
+    #[test]
+    fn release_free_blocks() {
+        let mut blist = BlockList::new(TestAllocator);
+        for _ in 0..16 * TestAllocator::LINES_PER_BLOCK {
+            blist.alloc(64, 1).expect("Could not allocate block!");
+        }
+        assert_eq!(blist.owners.len(), 16);
+
+        blist.clear_marks();
+        blist.sweep(2);
+        assert_eq!(blist.free.len(), 2);
+        assert_eq!(blist.owners.len(), 2);
     }
 }
@@ -1,8 +1,7 @@
 use super::linemap::LineMap;
-use super::memory::{AllocError, Block};
-use super::policy::AllocationPolicy;
-use std::marker::PhantomData;
-use std::ptr::NonNull;
+use super::memory::Block;
+use super::policy::BlockGeometry;
+use core::ptr::NonNull;
 
 /// Each block can be in one of 3 states:
 ///
@@ -29,56 +28,87 @@ pub struct ManagedPtr {
 }
 
 impl ManagedPtr {
-    fn new(inner: NonNull<u8>, size: usize) -> ManagedPtr {
+    pub fn new(inner: NonNull<u8>, size: usize) -> ManagedPtr {
         ManagedPtr { inner, size }
     }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.inner.as_ptr()
+    }
 }
 
-impl std::fmt::Display for ManagedPtr {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for ManagedPtr {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(f, "0x{:X}", self.inner.as_ptr() as usize)
     }
 }
 
 /// Bump-allocated block containing lines. Objects can be allocated in unused lines
-pub struct BumpBlock<A: AllocationPolicy> {
+pub struct BumpBlock<A: BlockGeometry> {
     cursor: usize,
 
     /// The limit for immix is either the next occupied line, or the end of the block
     limit: usize,
     mem: Block,
     used_lines: LineMap,
-    _allocation_policy: PhantomData<A>,
+    /// Whether the live objects are being moved out of this block by the current collection
+    evacuating: bool,
+    geometry: A,
 }
 
-impl<A: AllocationPolicy> BumpBlock<A> {
-    pub fn new() -> Result<Self, AllocError> {
-        Ok(BumpBlock {
+impl<A: BlockGeometry> BumpBlock<A> {
+    /// Allocate from the memory of `mem`, a block of `geometry.block_size()` bytes. Its previous
+    /// contents are ignored, and every line starts unused
+    pub fn new(mem: Block, geometry: A) -> Self {
+        debug_assert_eq!(mem.size(), geometry.block_size());
+        BumpBlock {
             cursor: 0,
-            limit: A::LINES_PER_BLOCK,
-            mem: Block::new(A::BLOCK_SIZE_BYTES)?,
-            used_lines: LineMap::new(A::LINES_PER_BLOCK),
-            _allocation_policy: PhantomData::default(),
-        })
+            limit: geometry.lines_per_block(),
+            mem,
+            used_lines: LineMap::new(geometry.lines_per_block()),
+            evacuating: false,
+            geometry,
+        }
+    }
+
+    /// Give up the memory of the block
+    pub fn into_block(self) -> Block {
+        self.mem
     }
 
     /// Mark the bytes pointed to by the `ptr` as unused, allowing them to be re-used by
     /// `inner_alloc`
+    #[cfg(test)]
     pub fn inner_dealloc(&mut self, ptr: ManagedPtr) {
         assert!(self.contains(&ptr), "This block does not contain the ptr!");
         let block_start =
-            ((ptr.inner.as_ptr() as usize - self.mem.as_ptr() as usize) + A::LINE_SIZE_BYTES - 1)
-                / A::LINE_SIZE_BYTES;
-        let block_end_exclusive =
-            block_start + (ptr.size + A::LINE_SIZE_BYTES - 1) / A::LINE_SIZE_BYTES;
+            (ptr.inner.as_ptr() as usize - self.mem.as_ptr() as usize) / self.geometry.line_size();
+        let block_end_exclusive = block_start + ptr.size.div_ceil(self.geometry.line_size());
 
         self.used_lines
             .set_range_unused(block_start, block_end_exclusive);
+        #[cfg(feature = "zero-on-free")]
+        self.zero_lines(block_start, block_end_exclusive);
     }
 
     /// Try to alloc memory of the requested size in this block, starting at the cursor. If the
     /// space cannot be allocated, `None` is returned
+    #[cfg(test)]
     pub fn inner_alloc(&mut self, bytes: usize) -> Option<ManagedPtr> {
+        self.inner_alloc_aligned(bytes, 1)
+    }
+
+    /// Like `inner_alloc`, but the memory starts at a multiple of `align` bytes. Lines are skipped
+    /// until one is aligned, and are left unused to be allocated from the next time a hole is
+    /// searched for
+    pub fn inner_alloc_aligned(&mut self, bytes: usize, align: usize) -> Option<ManagedPtr> {
+        assert!(
+            align.is_power_of_two() && align <= self.geometry.block_size(),
+            "Bad alignment {} for a block of {} bytes",
+            align,
+            self.geometry.block_size()
+        );
+
         if self.cursor == self.limit {
             if let Some((hole_begin, hole_end_exclusive)) = self.find_first_hole() {
                 self.cursor = hole_begin;
@@ -93,20 +123,26 @@ impl<A: AllocationPolicy> BumpBlock<A> {
             "The cursor must be less than or equal to the limit"
         );
 
+        // Blocks are aligned to their size, so lines are aligned to the line size and every
+        // `align / LINE_SIZE_BYTES` lines to `align`
+        let line_align = (align / self.geometry.line_size()).max(1);
+        let padding = self.cursor.next_multiple_of(line_align) - self.cursor;
+
         let next_used = self.used_lines.find_next_used(self.cursor);
         let num_lines_available = next_used - self.cursor;
-        let lines_required = (bytes + A::LINE_SIZE_BYTES - 1) / A::LINE_SIZE_BYTES;
+        // Objects take whole lines, so partially used lines are rounded up
+        let lines_required = bytes.div_ceil(self.geometry.line_size());
 
-        if num_lines_available >= lines_required {
+        if num_lines_available >= padding + lines_required {
             // Allocate the bytes for this block, updating the cursor and limit accordingly. If the
             // cursor is greater than the limit, they will be updated lazily on request for new
             // memory
-            let block_start = self.cursor;
+            let block_start = self.cursor + padding;
             let block_end_exclusive = block_start + lines_required;
 
             self.used_lines
                 .set_range_used(block_start, block_end_exclusive);
-            self.cursor += lines_required;
+            self.cursor = block_end_exclusive;
 
             // This operation is safe because we *know* mem is NonNull
             return Some(ManagedPtr::new(
@@ -114,7 +150,7 @@ impl<A: AllocationPolicy> BumpBlock<A> {
                     NonNull::new_unchecked(
                         self.mem
                             .as_ptr()
-                            .wrapping_add(block_start * A::LINE_SIZE_BYTES),
+                            .wrapping_add(block_start * self.geometry.line_size()),
                     )
                 },
                 bytes,
@@ -124,15 +160,101 @@ impl<A: AllocationPolicy> BumpBlock<A> {
         None
     }
 
+    /// Mark every line as unused, before marking the lines of the live objects. Allocation restarts
+    /// from the first hole afterwards
+    pub fn clear_marks(&mut self) {
+        self.used_lines.clear();
+        self.cursor = 0;
+        self.limit = 0;
+    }
+
+    /// Mark the lines spanned by `ptr` as used. The line following the object is conservatively
+    /// marked as well, as Immix does for objects that may straddle a line boundary, so that its
+    /// tail is never overwritten by an object allocated in the next line
+    pub fn mark(&mut self, ptr: ManagedPtr) {
+        assert!(self.contains(&ptr), "This block does not contain the ptr!");
+        let offset = ptr.inner.as_ptr() as usize - self.mem.as_ptr() as usize;
+        let line_start = offset / self.geometry.line_size();
+        let line_end_exclusive = ((offset + ptr.size).div_ceil(self.geometry.line_size()) + 1)
+            .min(self.geometry.lines_per_block());
+
+        for line in line_start..line_end_exclusive {
+            self.used_lines.set_used(line);
+        }
+    }
+
+    /// Returns `true` if every line spanned by `ptr` is used, and none is past the end of the block
+    pub fn is_allocated(&self, ptr: &ManagedPtr) -> bool {
+        let offset = ptr.inner.as_ptr() as usize - self.mem.as_ptr() as usize;
+        let line_end_exclusive = (offset + ptr.size).div_ceil(self.geometry.line_size());
+        line_end_exclusive <= self.geometry.lines_per_block()
+            && (offset / self.geometry.line_size()..line_end_exclusive)
+                .all(|line| self.used_lines.is_used(line))
+    }
+
+    /// Overwrite every unused line with zeroes, so the data of the objects freed by a collection
+    /// doesn't linger until the lines are allocated again
+    #[cfg(feature = "zero-on-free")]
+    pub fn zero_unused_lines(&mut self) {
+        let holes: Vec<_> = self.used_lines.holes().collect();
+        for (start, end) in holes {
+            self.zero_lines(start, end);
+        }
+    }
+
+    #[cfg(feature = "zero-on-free")]
+    fn zero_lines(&mut self, start: usize, end: usize) {
+        // The lines are within the block, and no live object occupies them
+        unsafe {
+            core::ptr::write_bytes(
+                self.mem.as_ptr().add(start * self.geometry.line_size()),
+                0,
+                (end - start) * self.geometry.line_size(),
+            )
+        }
+    }
+
+    /// Fraction of the lines of the block that are unused
+    pub fn fragmentation(&self) -> f64 {
+        1.0 - self.used_lines.count_used() as f64 / self.used_lines.len() as f64
+    }
+
+    /// Number of unused lines, and of holes they are split into
+    pub fn holes(&self) -> (usize, usize) {
+        self.used_lines
+            .holes()
+            .fold((0, 0), |(lines, holes), (start, end)| {
+                (lines + end - start, holes + 1)
+            })
+    }
+
+    /// Map of the lines of the block in use
+    pub fn used_lines(&self) -> &LineMap {
+        &self.used_lines
+    }
+
+    pub fn is_evacuating(&self) -> bool {
+        self.evacuating
+    }
+
+    pub fn set_evacuating(&mut self, evacuating: bool) {
+        self.evacuating = evacuating;
+    }
+
     /// Return the current state of the block -- see `BlockState`.
     pub fn get_block_state(&self) -> BlockState {
         if self.used_lines.entire_block_used() {
-            return BlockState::Unavailable;
+            BlockState::Unavailable
         } else if self.used_lines.entire_block_unused() {
-            return BlockState::Free;
+            BlockState::Free
+        } else {
+            BlockState::Recyclable
         }
+    }
 
-        return BlockState::Recyclable;
+    /// Address of the start of the block
+    pub fn address(&self) -> usize {
+        self.mem.as_ptr() as usize
     }
 
     /// Returns `true` if this block is the one that allocated the `ManagedPtr`, false otherwise.
@@ -165,13 +287,15 @@ impl<A: AllocationPolicy> BumpBlock<A> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::immix::policy::AllocationPolicy;
     use crate::immix::test_allocator::TestAllocator;
 
-    fn is_range_unused<A: AllocationPolicy>(
-        block: &BumpBlock<A>,
-        start: usize,
-        end: usize,
-    ) -> bool {
+    fn new_block() -> BumpBlock<TestAllocator> {
+        let mem = Block::new(TestAllocator::BLOCK_SIZE_BYTES).expect("Could not allocate block!");
+        BumpBlock::new(mem, TestAllocator)
+    }
+
+    fn is_range_unused<A: BlockGeometry>(block: &BumpBlock<A>, start: usize, end: usize) -> bool {
         (start..end)
             .map(|i| block.used_lines.is_used(i))
             .all(|x| !x)
@@ -179,7 +303,7 @@ mod test {
 
     #[test]
     fn allocate_bytes() {
-        let mut bump_block = BumpBlock::<TestAllocator>::new().expect("Could not allocate block!");
+        let mut bump_block = new_block();
         assert_eq!(bump_block.cursor, 0);
         assert_eq!(bump_block.limit, 4);
         assert!(is_range_unused(&bump_block, 0, 4));
@@ -217,9 +341,55 @@ mod test {
         assert_eq!(bump_block.limit, 4);
     }
 
+    #[test]
+    fn allocate_bytes_in_lines() {
+        let mut bump_block = new_block();
+        bump_block
+            .inner_alloc(TestAllocator::LINE_SIZE_BYTES)
+            .expect("Could not allocate line!");
+        assert_eq!(bump_block.cursor, 1);
+
+        // A single byte past a line takes another whole line
+        let ptr = bump_block
+            .inner_alloc(TestAllocator::LINE_SIZE_BYTES + 1)
+            .expect("Could not allocate lines!");
+        assert_eq!(bump_block.cursor, 3);
+
+        bump_block.inner_dealloc(ptr);
+        assert!(is_range_unused(&bump_block, 1, 3));
+    }
+
+    #[test]
+    fn allocate_aligned() {
+        let mut bump_block = new_block();
+        let ptr = bump_block
+            .inner_alloc_aligned(8, 16)
+            .expect("Could not allocate aligned ptr!");
+        assert_eq!(ptr.as_ptr() as usize % 16, 0);
+        assert_eq!(bump_block.cursor, 1);
+
+        // Aligning to two lines skips the second line, leaving it unused
+        let ptr = bump_block
+            .inner_alloc_aligned(8, 2 * TestAllocator::LINE_SIZE_BYTES)
+            .expect("Could not allocate aligned ptr!");
+        assert_eq!(
+            ptr.as_ptr() as usize % (2 * TestAllocator::LINE_SIZE_BYTES),
+            0
+        );
+        assert_eq!(ptr.as_ptr(), bump_block.mem.as_ptr().wrapping_add(128));
+        assert!(is_range_unused(&bump_block, 1, 2));
+        assert_eq!(bump_block.cursor, 3);
+
+        // No aligned line is left
+        assert_eq!(
+            bump_block.inner_alloc_aligned(8, 2 * TestAllocator::LINE_SIZE_BYTES),
+            None
+        );
+    }
+
     #[test]
     fn dealloc_bytes() {
-        let mut bump_block = BumpBlock::<TestAllocator>::new().expect("Could not allocate block!");
+        let mut bump_block = new_block();
         let ptr1 = bump_block
             .inner_alloc(2 * TestAllocator::LINE_SIZE_BYTES)
             .expect("Could not allocate first ptr!");
@@ -244,14 +414,37 @@ mod test {
         assert_eq!(bump_block.limit, 2);
     }
 
+    #[test]
+    fn mark_following_line() {
+        let mut bump_block = new_block();
+        let straddling = bump_block
+            .inner_alloc(TestAllocator::LINE_SIZE_BYTES + 1)
+            .expect("Could not allocate straddling ptr!");
+        let follower = bump_block
+            .inner_alloc(1)
+            .expect("Could not allocate follower ptr!");
+
+        // Only the straddling object is live, but the line after it is retained as well
+        bump_block.clear_marks();
+        bump_block.mark(straddling);
+        assert!(!is_range_unused(&bump_block, 0, 3));
+        assert!(is_range_unused(&bump_block, 3, 4));
+        assert!(bump_block.contains(&follower));
+
+        // The follower is in the last line, so nothing past the block is marked
+        bump_block.clear_marks();
+        bump_block.mark(follower);
+        assert!(is_range_unused(&bump_block, 0, 2));
+        assert!(!is_range_unused(&bump_block, 2, 4));
+    }
+
     #[test]
     fn block_contains_ptr() {
-        let mut bump_block = BumpBlock::<TestAllocator>::new().expect("Could not allocate block!");
+        let mut bump_block = new_block();
         let ptr = bump_block.inner_alloc(2).expect("Could not allocate ptr!");
         assert!(bump_block.contains(&ptr));
 
-        let other_bump_block =
-            BumpBlock::<TestAllocator>::new().expect("Could not allocate block!");
+        let other_bump_block = new_block();
         assert!(!other_bump_block.contains(&ptr));
     }
 }
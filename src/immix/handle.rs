@@ -0,0 +1,56 @@
+use super::header::ObjectHeader;
+use super::roots::ApplicationRoots;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+
+/// Roots the objects of its handles for as long as it is alive, so code allocating several objects
+/// can keep the first ones alive until they are reachable from elsewhere. Scopes are created with
+/// `ApplicationRoots::scope`, and can be nested or dropped in any order
+pub struct HandleScope<'r> {
+    roots: &'r ApplicationRoots,
+    /// Slots of the handles created in this scope
+    slots: RefCell<Vec<usize>>,
+}
+
+impl<'r> HandleScope<'r> {
+    pub(super) fn new(roots: &'r ApplicationRoots) -> Self {
+        HandleScope {
+            roots,
+            slots: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Root `object` until the scope is dropped
+    pub fn handle<T: ObjectHeader + 'static>(&self, object: NonNull<T>) -> Handle<'_, T> {
+        let slot = self.roots.push_handle(object);
+        self.slots.borrow_mut().push(slot);
+        Handle {
+            roots: self.roots,
+            slot,
+            _object: PhantomData,
+        }
+    }
+}
+
+impl Drop for HandleScope<'_> {
+    fn drop(&mut self) {
+        for slot in self.slots.borrow().iter() {
+            self.roots.release_handle(*slot);
+        }
+    }
+}
+
+/// Object rooted by a `HandleScope`, which can't outlive its scope
+pub struct Handle<'s, T> {
+    roots: &'s ApplicationRoots,
+    slot: usize,
+    _object: PhantomData<NonNull<T>>,
+}
+
+impl<T> Handle<'_, T> {
+    pub fn get(&self) -> NonNull<T> {
+        self.roots.handle(self.slot).cast::<T>()
+    }
+}
@@ -1,4 +1,80 @@
-/// Required information for all heap-allocated objects
+use crate::object::ObjType;
+use core::ptr::NonNull;
+
+/// Required information for all heap-allocated objects. The collector drops objects once they
+/// are unreachable, or along with the heap
 pub trait ObjectHeader {
-    fn make_header(&self) -> Box<dyn ObjectHeader>;
+    /// Number of bytes the object occupies on the heap, including any data stored inline after it
+    fn size(&self) -> usize {
+        core::mem::size_of_val(self)
+    }
+
+    /// Pass every heap-allocated object this object references to `visit`, so the collector can
+    /// trace through it, and replace each reference with the pointer `visit` returns, since the
+    /// collector may have moved the object. Objects without references don't need to implement
+    /// this
+    fn trace(
+        &mut self,
+        _visit: &mut dyn FnMut(NonNull<dyn ObjectHeader>) -> NonNull<dyn ObjectHeader>,
+    ) {
+    }
+
+    /// Kind of the Lox object this is, so it can be downcast to it. Objects that aren't Lox values
+    /// have none
+    fn obj_type(&self) -> Option<ObjType> {
+        None
+    }
+
+    /// Record that the object was reached while tracing
+    fn mark(&mut self);
+
+    /// Returns `true` if the object was reached since it was last unmarked
+    fn is_marked(&self) -> bool;
+
+    fn unmark(&mut self);
+
+    /// Where the object was moved to, if the collector evacuated it
+    fn forwarded(&self) -> Option<NonNull<u8>>;
+
+    /// Record that the object was moved to `to`, so references to it can be updated
+    fn forward(&mut self, to: NonNull<u8>);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::immix::test_allocator::gc_state;
+
+    struct Object {
+        marked: bool,
+        forwarded: Option<NonNull<u8>>,
+        _data: [u64; 3],
+    }
+
+    impl ObjectHeader for Object {
+        gc_state!();
+    }
+
+    #[test]
+    fn mark_bit() {
+        let mut object = Object {
+            marked: false,
+            forwarded: None,
+            _data: [0; 3],
+        };
+        assert!(!object.is_marked());
+        assert_eq!(object.size(), core::mem::size_of::<Object>());
+
+        object.mark();
+        assert!(object.is_marked());
+        object.mark();
+        assert!(object.is_marked());
+        object.unmark();
+        assert!(!object.is_marked());
+
+        assert_eq!(object.forwarded(), None);
+        let to = NonNull::from(&object._data).cast::<u8>();
+        object.forward(to);
+        assert_eq!(object.forwarded(), Some(to));
+    }
 }
@@ -1,8 +1,15 @@
+use super::blocklist::BlockList;
+use super::bump_alloc::ManagedPtr;
 use super::header::ObjectHeader;
-use super::policy::{AllocationPolicy, ReclamationPolicy};
-use std::marker::PhantomData;
-use std::ptr::NonNull;
+use super::memory::{AllocError, Block};
+use super::policy::{AllocationPolicy, BlockGeometry, ReclamationPolicy, RuntimeAllocationPolicy};
+use super::roots::ApplicationRoots;
+use alloc::{format, string::String, vec::Vec};
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+use hashbrown::{HashMap, HashSet};
 
+#[derive(Clone, Copy, Default)]
 pub struct DefaultAllocation;
 pub struct DefaultReclamation;
 
@@ -10,24 +17,975 @@ impl ReclamationPolicy for DefaultReclamation {}
 impl AllocationPolicy for DefaultAllocation {
     const BLOCK_SIZE_BYTES: usize = 32 * 1024;
     const LINE_SIZE_BYTES: usize = 128;
+    const SIZE_CLASSES: &'static [usize] = &[16, 32, 64];
 }
 
-pub struct ImmixGc<A: AllocationPolicy, R: ReclamationPolicy> {
-    allocation_policy: PhantomData<A>,
+/// Object larger than `AllocationPolicy::LARGE_OBJECT_BYTES`, allocated in a block of its own
+struct LargeObject {
+    block: Block,
+    marked: bool,
+}
+
+pub struct ImmixGc<A: BlockGeometry, R: ReclamationPolicy> {
+    blocks: BlockList<A>,
+    large_objects: Vec<LargeObject>,
+    /// Objects that survived a collection, which keep their mark bit until the next full one
+    mature: Vec<NonNull<dyn ObjectHeader>>,
+    /// Objects allocated since the last collection
+    young: Vec<NonNull<dyn ObjectHeader>>,
+    /// Old objects that were made to reference young ones, by address
+    remembered: HashMap<NonNull<u8>, NonNull<dyn ObjectHeader>>,
+    /// Addresses of the objects that must not be moved by evacuation
+    pinned: HashSet<NonNull<u8>>,
+    /// Bytes of the objects that survived the last collection and of the ones allocated since
+    bytes_allocated: usize,
+    /// `bytes_allocated` past which `alloc_or_collect` collects before allocating
+    next_gc: usize,
+    collections_run: usize,
     reclamation_policy: PhantomData<R>,
 }
 
+/// Counters of the work done by a collector
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GcStats {
+    /// Number of collections that ran, minor or full
+    pub collections_run: usize,
+    /// Bytes of the objects that survived the last collection and of the ones allocated since
+    pub bytes_allocated: usize,
+    /// `bytes_allocated` past which the next collection is due
+    pub next_gc: usize,
+}
+
 /// Default implementation of Immix
 pub type StickyImmix = ImmixGc<DefaultAllocation, DefaultReclamation>;
 
+/// Immix with the block and line sizes chosen at runtime, see `RuntimeAllocationPolicy`
+pub type RuntimeImmix = ImmixGc<RuntimeAllocationPolicy, DefaultReclamation>;
+
+impl<A: AllocationPolicy, R: ReclamationPolicy> Default for ImmixGc<A, R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<A: AllocationPolicy, R: ReclamationPolicy> ImmixGc<A, R> {
+    pub fn new() -> Self {
+        Self::with_geometry(A::default())
+    }
+}
+
+impl<A: BlockGeometry, R: ReclamationPolicy> ImmixGc<A, R> {
+    /// Collector laying out its blocks with `geometry`, such as a `RuntimeAllocationPolicy` with
+    /// sizes only known at runtime
+    pub fn with_geometry(geometry: A) -> Self {
+        ImmixGc {
+            blocks: BlockList::new(geometry).with_pool(R::MAX_POOLED_BLOCKS),
+            large_objects: Vec::new(),
+            mature: Vec::new(),
+            young: Vec::new(),
+            remembered: HashMap::new(),
+            pinned: HashSet::new(),
+            bytes_allocated: 0,
+            next_gc: R::FIRST_GC_BYTES,
+            collections_run: 0,
+            reclamation_policy: PhantomData,
+        }
+    }
+
     /// Allocate the object of type `T`, returning the pointer to the object. Checks space in the
     /// bump allocator in the following order:
     ///
     ///  Look for open lines in address order in a recycled block
     ///  Repeat (1) in the next recycled block
     ///  Request a new block from the global allocator
-    pub fn alloc<T: ObjectHeader>(&mut self, object: T) -> NonNull<T> {
-        unsafe { NonNull::new_unchecked(std::ptr::null_mut()) }
+    ///
+    /// `object.size()` bytes are reserved, so objects can store data inline after themselves.
+    /// Objects larger than `AllocationPolicy::LARGE_OBJECT_BYTES` bypass the blocks and get one of
+    /// their own instead. Fails with `AllocError::OutOfMemory` if a new block was needed and could
+    /// not be allocated.
+    pub fn alloc<T: ObjectHeader + 'static>(
+        &mut self,
+        object: T,
+    ) -> Result<NonNull<T>, AllocError> {
+        let ptr = self.reserve(object.size(), core::mem::align_of::<T>())?;
+        Ok(self.init(ptr, object))
+    }
+
+    /// Allocate the object like `alloc`, but collect the objects not reachable from `roots` first
+    /// if the object takes the heap past the threshold of `needs_collection`, and when out of
+    /// memory collect and try once more. The objects referenced by `object` must be reachable
+    /// from `roots` to survive the collection
+    pub fn alloc_or_collect<T: ObjectHeader + 'static>(
+        &mut self,
+        object: T,
+        roots: &ApplicationRoots,
+    ) -> Result<NonNull<T>, AllocError> {
+        let (size, align) = (object.size(), core::mem::align_of::<T>());
+        if self.bytes_allocated + size > self.next_gc {
+            self.collect(roots);
+        }
+
+        let ptr = match self.reserve(size, align) {
+            Err(AllocError::OutOfMemory) => {
+                self.collect(roots);
+                self.reserve(size, align)?
+            }
+            ptr => ptr?,
+        };
+        Ok(self.init(ptr, object))
+    }
+
+    /// Record that `holder` was made to reference `new_ref`. Must be called whenever a reference
+    /// is stored in an object. Old objects are the ones with their mark bit set, as the objects
+    /// surviving a collection keep it, so a marked `holder` referencing an unmarked `new_ref` is
+    /// added to the remembered set for `minor_collect` to trace from
+    pub fn write_barrier(
+        &mut self,
+        holder: NonNull<dyn ObjectHeader>,
+        new_ref: NonNull<dyn ObjectHeader>,
+    ) {
+        let (holder_ref, new_ref) = unsafe { (holder.as_ref(), new_ref.as_ref()) };
+        if holder_ref.is_marked() && !new_ref.is_marked() {
+            self.remembered.insert(holder.cast::<u8>(), holder);
+        }
+    }
+
+    /// Old objects referencing young ones, recorded by `write_barrier` since the last collection
+    pub fn remembered(&self) -> impl Iterator<Item = NonNull<dyn ObjectHeader>> + '_ {
+        self.remembered.values().copied()
+    }
+
+    /// Keep `object` where it is when its block is evacuated, so pointers to it held outside of
+    /// the heap stay valid. The object is still freed once it is unreachable
+    pub fn pin(&mut self, object: NonNull<dyn ObjectHeader>) {
+        self.pinned.insert(object.cast::<u8>());
+    }
+
+    /// Allow `object` to be moved by evacuation again
+    pub fn unpin(&mut self, object: NonNull<dyn ObjectHeader>) {
+        self.pinned.remove(&object.cast::<u8>());
+    }
+
+    /// Call `f` with every object in the heap: the objects that survived the last collection and
+    /// the ones allocated since. Objects are trait objects, so their types can't be recovered from
+    /// the blocks alone, and the collector keeps track of them instead. `verify_heap` checks them
+    /// against the used lines of the blocks
+    pub fn walk_heap(&self, mut f: impl FnMut(NonNull<dyn ObjectHeader>)) {
+        for object in self.mature.iter().chain(self.young.iter()) {
+            f(*object);
+        }
+    }
+
+    /// Check that every object in the heap lies within memory the collector allocated for it: the
+    /// lines an object spans must all be used in its block, or fit in its block for large
+    /// objects. The objects `walk_heap` knows of are cross-checked against the blocks, so every
+    /// used line must be spanned by an object or follow one, as marking conservatively uses the
+    /// line past each object, and every large object block must hold an object. Returns the first
+    /// inconsistency found
+    pub fn verify_heap(&self) -> Result<(), String> {
+        let geometry = self.blocks.geometry();
+        let mut result = Ok(());
+        // Lines spanned by the objects or following them, as the address of their block and the
+        // index of the line
+        let mut covered = HashSet::new();
+        let mut large_objects = HashSet::new();
+        self.walk_heap(|object| {
+            if result.is_err() {
+                return;
+            }
+            let size = unsafe { object.as_ref() }.size();
+            let ptr = ManagedPtr::new(object.cast::<u8>(), size);
+            let in_block = self.blocks.is_allocated(&ptr);
+            let allocated = in_block.or_else(|| {
+                self.large_objects
+                    .iter()
+                    .find(|large| large.block.as_ptr() == ptr.as_ptr())
+                    .map(|large| size <= large.block.size())
+            });
+            result = match allocated {
+                Some(true) => Ok(()),
+                Some(false) => Err(format!(
+                    "Object at {} of {} bytes spans memory that is not allocated",
+                    ptr, size
+                )),
+                None => Err(format!("Object at {} is not owned by the collector", ptr)),
+            };
+
+            let address = ptr.as_ptr() as usize;
+            if in_block.is_none() {
+                large_objects.insert(address);
+                return;
+            }
+            let block = address & !(geometry.block_size() - 1);
+            let offset = address - block;
+            let end = (offset + size).div_ceil(geometry.line_size()) + 1;
+            for line in offset / geometry.line_size()..end.min(geometry.lines_per_block()) {
+                covered.insert((block, line));
+            }
+        });
+        result?;
+
+        for (block, lines) in self.blocks.line_maps() {
+            if let Some(line) = (0..lines.len())
+                .find(|&line| lines.is_used(line) && !covered.contains(&(block, line)))
+            {
+                return Err(format!(
+                    "Line {} of the block at 0x{:X} is used without an object",
+                    line, block
+                ));
+            }
+        }
+        match self
+            .large_objects
+            .iter()
+            .find(|large| !large_objects.contains(&(large.block.as_ptr() as usize)))
+        {
+            Some(large) => Err(format!(
+                "Large object block at 0x{:X} holds no object",
+                large.block.as_ptr() as usize
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether enough was allocated since the last collection for the next one to be due. The
+    /// threshold starts at `ReclamationPolicy::FIRST_GC_BYTES`, and each collection sets it to
+    /// `ReclamationPolicy::HEAP_GROW_FACTOR` times the bytes that survived it. `alloc` never
+    /// collects by itself, as it doesn't know the roots, so its callers should check this
+    pub fn needs_collection(&self) -> bool {
+        self.bytes_allocated > self.next_gc
+    }
+
+    pub fn stats(&self) -> GcStats {
+        GcStats {
+            collections_run: self.collections_run,
+            bytes_allocated: self.bytes_allocated,
+            next_gc: self.next_gc,
+        }
+    }
+
+    /// Number of blocks requested from the global allocator so far, including the blocks of large
+    /// objects. Released blocks taken back from the pool are not counted again
+    pub fn allocated_blocks(&self) -> usize {
+        self.blocks.allocated_blocks()
+    }
+
+    /// How scattered the free lines of the partially used blocks are, as the number of holes per
+    /// free line. Close to 0 when the free lines are contiguous, and 1 when every free line is a
+    /// hole of its own, at which point evacuating the blocks is worth it
+    pub fn fragmentation(&self) -> f64 {
+        self.blocks.fragmentation()
+    }
+
+    /// Mark phase of a collection: trace every object reachable from `roots`, marking the lines
+    /// they occupy as used. Every other line is free to be allocated again afterwards. The marked
+    /// objects keep their mark bit, making them old objects until the next full collection
+    pub fn mark(&mut self, roots: &ApplicationRoots) {
+        let mut roots: Vec<_> = roots.iter().collect();
+        self.trace(roots.iter_mut(), false);
+    }
+
+    /// Free the memory of every object not reachable from `roots`, releasing the blocks left
+    /// empty
+    pub fn collect(&mut self, roots: &ApplicationRoots) {
+        self.mark(roots);
+        self.sweep();
+    }
+
+    /// Free the memory of the young objects, the ones allocated since the last collection, that
+    /// are not reachable from `roots`. Old objects are not traced again and are all kept alive
+    /// with their mark bit, so the young objects they reference are only found through the
+    /// remembered set of `write_barrier`
+    pub fn minor_collect(&mut self, roots: &ApplicationRoots) {
+        self.blocks.clear_marks();
+        for object in self.mature.clone() {
+            self.mark_lines(object);
+        }
+
+        let mut worklist: Vec<_> = self.remembered.drain().map(|(_, holder)| holder).collect();
+        for root in roots.iter() {
+            self.visit(root, false, &mut worklist);
+        }
+        self.trace_worklist(worklist, false);
+        self.sweep();
+    }
+
+    /// Collect like `collect`, but also defragment the heap: the live objects of every partially
+    /// used block with at least `threshold` of its lines free are moved to new blocks, and
+    /// `roots` and the references of the live objects are updated to point to where they moved
+    pub fn collect_evacuating(&mut self, roots: &mut ApplicationRoots, threshold: f64) {
+        let evacuating = self.blocks.begin_evacuation(threshold);
+        self.trace(roots.iter_mut(), evacuating);
+        self.sweep();
+    }
+
+    /// Mark every object reachable from `roots`, moving the ones in blocks being evacuated if
+    /// `evacuating` is set
+    fn trace<'a>(
+        &mut self,
+        roots: impl Iterator<Item = &'a mut NonNull<dyn ObjectHeader>>,
+        evacuating: bool,
+    ) {
+        // The objects that survived the last collection are still marked, and are garbage like
+        // the young ones unless they are reached again
+        for mut object in self.mature.drain(..) {
+            unsafe { object.as_mut() }.unmark();
+            self.young.push(object);
+        }
+        for large in self.large_objects.iter_mut() {
+            large.marked = false;
+        }
+        self.blocks.clear_marks();
+
+        let mut worklist = Vec::new();
+        for root in roots {
+            *root = self.visit(*root, evacuating, &mut worklist);
+        }
+        self.trace_worklist(worklist, evacuating);
+    }
+
+    /// Mark the lines of every object in `worklist`, and visit the objects they reference until
+    /// every reachable object is marked. The young objects left unmarked are garbage afterwards,
+    /// and are dropped before their lines can be reused
+    fn trace_worklist(&mut self, mut worklist: Vec<NonNull<dyn ObjectHeader>>, evacuating: bool) {
+        while let Some(mut object) = worklist.pop() {
+            // Objects are alive until the lines they occupy are reused, which can only happen
+            // after marking
+            let object_ref = unsafe { object.as_mut() };
+            self.mark_lines(object);
+            object_ref.trace(&mut |child| self.visit(child, evacuating, &mut worklist));
+        }
+        for object in self.young.drain(..) {
+            let object_ref = unsafe { object.as_ref() };
+            // Evacuated objects live on in their copy, which owns their resources now
+            if !object_ref.is_marked() && object_ref.forwarded().is_none() {
+                unsafe { core::ptr::drop_in_place(object.as_ptr()) };
+            }
+        }
+    }
+
+    /// Mark the lines occupied by `object` as used, or its block for large objects
+    fn mark_lines(&mut self, object: NonNull<dyn ObjectHeader>) {
+        let ptr = ManagedPtr::new(object.cast::<u8>(), unsafe { object.as_ref() }.size());
+        if !self.blocks.mark(ptr) {
+            let large = self
+                .large_objects
+                .iter_mut()
+                .find(|large| large.block.as_ptr() == ptr.as_ptr())
+                .expect("Object is not owned by the collector!");
+            large.marked = true;
+        }
+    }
+
+    /// Mark `object` and queue it to be traced the first time it is reached, evacuating it first
+    /// if its block is being evacuated. Returns where the object lives from now on
+    fn visit(
+        &mut self,
+        mut object: NonNull<dyn ObjectHeader>,
+        evacuating: bool,
+        worklist: &mut Vec<NonNull<dyn ObjectHeader>>,
+    ) -> NonNull<dyn ObjectHeader> {
+        let object_ref = unsafe { object.as_mut() };
+        if let Some(to) = object_ref.forwarded() {
+            return relocated(object, to);
+        }
+        if object_ref.is_marked() {
+            return object;
+        }
+
+        let size = object_ref.size();
+        let ptr = ManagedPtr::new(object.cast::<u8>(), size);
+        if evacuating
+            && !self.pinned.contains(&object.cast::<u8>())
+            && self.blocks.is_evacuating(&ptr)
+        {
+            // Objects that don't fit anywhere else are marked in place instead
+            let align = core::mem::align_of_val(object_ref);
+            if let Ok(to) = self.blocks.alloc_evacuated(size, align) {
+                let to = unsafe {
+                    core::ptr::copy_nonoverlapping(ptr.as_ptr(), to.as_ptr(), size);
+                    NonNull::new_unchecked(to.as_ptr())
+                };
+                object_ref.forward(to);
+                object = relocated(object, to);
+            }
+        }
+
+        // Mark bits are sticky: the object is old once it survives the collection
+        unsafe { object.as_mut() }.mark();
+        self.mature.push(object);
+        worklist.push(object);
+        object
+    }
+
+    /// Sweep phase of a collection: release the memory of every object left unmarked by `mark`.
+    /// Anything referencing objects without keeping them alive, such as a weak table, must drop
+    /// the unmarked ones before
+    pub fn sweep(&mut self) {
+        // Every object was traced, so old objects referencing young ones no longer matter
+        self.remembered.clear();
+        self.blocks.sweep(R::MAX_FREE_BLOCKS);
+        for large in core::mem::take(&mut self.large_objects) {
+            if large.marked {
+                self.large_objects.push(large);
+            } else {
+                self.blocks.release_block(large.block);
+            }
+        }
+
+        // Every object left is marked, and is old until the next collection
+        self.bytes_allocated = self
+            .mature
+            .iter()
+            .map(|object| unsafe { object.as_ref() }.size())
+            .sum();
+        self.next_gc = (self.bytes_allocated * R::HEAP_GROW_FACTOR).max(R::FIRST_GC_BYTES);
+        self.collections_run += 1;
+    }
+
+    /// Reserve `size` bytes aligned to `align` for an object, in a block of its own if it is a
+    /// large object. Blocks are aligned to their size, so large objects are always aligned
+    fn reserve(&mut self, size: usize, align: usize) -> Result<*mut u8, AllocError> {
+        if size > self.blocks.geometry().large_object_bytes() {
+            let block = self.blocks.take_block(size.next_power_of_two())?;
+            let ptr = block.as_ptr();
+            self.large_objects.push(LargeObject {
+                block,
+                marked: false,
+            });
+            Ok(ptr)
+        } else {
+            Ok(self.blocks.alloc(size, align)?.as_ptr())
+        }
+    }
+
+    /// Move `object` to the memory reserved for it at `ptr`
+    fn init<T: ObjectHeader + 'static>(&mut self, ptr: *mut u8, object: T) -> NonNull<T> {
+        let size = object.size();
+        debug_assert!(size >= core::mem::size_of::<T>());
+        let ptr = unsafe {
+            let ptr = ptr.cast::<T>();
+            ptr.write(object);
+            NonNull::new_unchecked(ptr)
+        };
+        self.bytes_allocated += size;
+        self.young.push(ptr);
+        ptr
+    }
+}
+
+/// Objects are dropped along with the heap, so the resources they own are released
+impl<A: BlockGeometry, R: ReclamationPolicy> Drop for ImmixGc<A, R> {
+    fn drop(&mut self) {
+        for object in self.mature.drain(..).chain(self.young.drain(..)) {
+            unsafe { core::ptr::drop_in_place(object.as_ptr()) };
+        }
+    }
+}
+
+/// Pointer to the copy of `object` at `to`, keeping the type information of `object`
+fn relocated(object: NonNull<dyn ObjectHeader>, to: NonNull<u8>) -> NonNull<dyn ObjectHeader> {
+    let offset = to.as_ptr() as isize - object.cast::<u8>().as_ptr() as isize;
+    unsafe { NonNull::new_unchecked(object.as_ptr().wrapping_byte_offset(offset)) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::immix::test_allocator::{gc_state, TestAllocator};
+
+    struct Small {
+        value: u64,
+        marked: bool,
+        forwarded: Option<NonNull<u8>>,
+    }
+
+    impl ObjectHeader for Small {
+        gc_state!();
+    }
+
+    /// Node of an object graph, followed by `len` bytes of inline data
+    struct Node {
+        next: Option<NonNull<Node>>,
+        marked: bool,
+        forwarded: Option<NonNull<u8>>,
+        len: usize,
+    }
+
+    impl Node {
+        fn new(next: Option<NonNull<Node>>) -> Self {
+            Node {
+                next,
+                marked: false,
+                forwarded: None,
+                len: 0,
+            }
+        }
+    }
+
+    impl ObjectHeader for Node {
+        fn size(&self) -> usize {
+            core::mem::size_of::<Node>() + self.len
+        }
+
+        fn trace(
+            &mut self,
+            visit: &mut dyn FnMut(NonNull<dyn ObjectHeader>) -> NonNull<dyn ObjectHeader>,
+        ) {
+            if let Some(next) = self.next {
+                self.next = Some(visit(next).cast::<Node>());
+            }
+        }
+
+        gc_state!();
+    }
+
+    #[test]
+    fn alloc_objects() {
+        let mut gc = ImmixGc::<TestAllocator, DefaultReclamation>::new();
+
+        // Enough objects to fill several blocks
+        let ptrs: Vec<_> = (0..32)
+            .map(|i| {
+                gc.alloc(Small {
+                    value: i,
+                    marked: false,
+                    forwarded: None,
+                })
+                .expect("Could not allocate object!")
+            })
+            .collect();
+        for (i, ptr) in ptrs.iter().enumerate() {
+            assert_eq!(unsafe { ptr.as_ref() }.value, i as u64);
+            assert!(ptrs[..i].iter().all(|other| other != ptr));
+        }
+    }
+
+    #[test]
+    fn walk_heap_objects() {
+        let mut gc = ImmixGc::<TestAllocator, DefaultReclamation>::new();
+        let large = Node {
+            len: 4 * TestAllocator::BLOCK_SIZE_BYTES,
+            ..Node::new(None)
+        };
+        let mut nodes: Vec<_> = (0..5).map(|_| gc.alloc(Node::new(None)).unwrap()).collect();
+        nodes.push(gc.alloc(large).unwrap());
+
+        let walk = |gc: &ImmixGc<_, _>| {
+            let mut objects = Vec::new();
+            gc.walk_heap(|object| objects.push(object.cast::<Node>()));
+            objects.sort();
+            objects
+        };
+        let mut sorted = nodes.clone();
+        sorted.sort();
+        assert_eq!(walk(&gc), sorted);
+        assert_eq!(gc.verify_heap(), Ok(()));
+
+        // Only the reachable objects are left after a collection
+        let mut roots = ApplicationRoots::new();
+        roots.add(nodes[1]);
+        roots.add(nodes[5]);
+        gc.collect(&roots);
+        let mut sorted = [nodes[1], nodes[5]];
+        sorted.sort();
+        assert_eq!(walk(&gc), sorted);
+        assert_eq!(gc.verify_heap(), Ok(()));
+
+        // Objects missing from the lists leave lines and large object blocks without an object
+        let listed = gc.mature.clone();
+        gc.mature
+            .retain(|&object| object.cast::<Node>() != nodes[1]);
+        assert!(gc.verify_heap().unwrap_err().contains("without an object"));
+        gc.mature.clone_from(&listed);
+        gc.mature
+            .retain(|&object| object.cast::<Node>() != nodes[5]);
+        assert!(gc.verify_heap().unwrap_err().contains("holds no object"));
+        gc.mature = listed;
+        assert_eq!(gc.verify_heap(), Ok(()));
+
+        // An object growing past the lines it was allocated is caught
+        let mut node = gc.alloc(Node::new(None)).unwrap();
+        unsafe { node.as_mut() }.len = TestAllocator::BLOCK_SIZE_BYTES;
+        assert!(gc.verify_heap().is_err());
+    }
+
+    #[test]
+    fn runtime_geometry() {
+        let mut gc = RuntimeImmix::with_geometry(RuntimeAllocationPolicy::new(512, 32));
+
+        // Each node takes a single line, so the nodes are packed one line apart in blocks of
+        // sixteen lines
+        assert_eq!(core::mem::size_of::<Node>(), 32);
+        let nodes: Vec<_> = (0..20)
+            .map(|_| gc.alloc(Node::new(None)).unwrap())
+            .collect();
+        let address = |node: NonNull<Node>| node.as_ptr() as usize;
+        assert_eq!(address(nodes[1]) - address(nodes[0]), 32);
+        assert_eq!(address(nodes[15]) / 512, address(nodes[0]) / 512);
+        assert_ne!(address(nodes[16]) / 512, address(nodes[0]) / 512);
+
+        let mut roots = ApplicationRoots::new();
+        roots.add(nodes[3]);
+        gc.collect(&roots);
+        assert_eq!(gc.alloc(Node::new(None)).unwrap(), nodes[0]);
+        assert_eq!(gc.verify_heap(), Ok(()));
+    }
+
+    #[test]
+    fn out_of_memory() {
+        /// Heap of a single block of four lines
+        #[derive(Clone, Copy, Default)]
+        struct OneBlock;
+        impl AllocationPolicy for OneBlock {
+            const BLOCK_SIZE_BYTES: usize = 256;
+            const LINE_SIZE_BYTES: usize = 64;
+            const MAX_BLOCKS: usize = 1;
+        }
+
+        let mut gc = ImmixGc::<OneBlock, DefaultReclamation>::new();
+        let nodes: Vec<_> = (0..4).map(|_| gc.alloc(Node::new(None)).unwrap()).collect();
+        assert_eq!(
+            gc.alloc(Node::new(None)).err(),
+            Some(AllocError::OutOfMemory)
+        );
+
+        // Collecting frees the lines of the unreachable nodes
+        let mut roots = ApplicationRoots::new();
+        roots.add(nodes[0]);
+        let node = gc
+            .alloc_or_collect(Node::new(None), &roots)
+            .expect("Could not allocate after collecting!");
+        assert!(!nodes[..2].contains(&node));
+
+        // The line left is conservatively kept for the first node, so nothing can be freed once
+        // the other nodes are reachable
+        let last = gc.alloc(Node::new(None)).unwrap();
+        roots.add(node);
+        roots.add(last);
+        assert_eq!(
+            gc.alloc_or_collect(Node::new(None), &roots).err(),
+            Some(AllocError::OutOfMemory)
+        );
+    }
+
+    #[test]
+    fn fragmented_holes() {
+        /// Block of sixteen lines, each holding a node
+        #[derive(Clone, Copy, Default)]
+        struct SixteenLines;
+        impl AllocationPolicy for SixteenLines {
+            const BLOCK_SIZE_BYTES: usize = 1024;
+            const LINE_SIZE_BYTES: usize = 64;
+        }
+
+        let mut gc = ImmixGc::<SixteenLines, DefaultReclamation>::new();
+        let nodes: Vec<_> = (0..16)
+            .map(|_| gc.alloc(Node::new(None)).unwrap())
+            .collect();
+        assert_eq!(gc.fragmentation(), 0.0);
+
+        // Each live node also keeps the line after it, so every third line is a hole of its own
+        let mut roots = ApplicationRoots::new();
+        for node in nodes.iter().step_by(3) {
+            roots.add(*node);
+        }
+        gc.collect(&roots);
+        assert!(gc.fragmentation() > 0.9);
+
+        // Keeping the first nodes instead leaves a single hole at the end of the block
+        let mut roots = ApplicationRoots::new();
+        roots.add(nodes[0]);
+        gc.collect(&roots);
+        assert!(gc.fragmentation() < 0.1);
+    }
+
+    #[test]
+    fn handles_root_objects() {
+        let mut gc = ImmixGc::<TestAllocator, DefaultReclamation>::new();
+        let roots = ApplicationRoots::new();
+        let live = |gc: &ImmixGc<_, _>| {
+            let mut objects = Vec::new();
+            gc.walk_heap(|object| objects.push(object.cast::<Node>()));
+            objects
+        };
+
+        let (a, b) = {
+            let scope = roots.scope();
+            let a = scope.handle(gc.alloc(Node::new(None)).unwrap());
+            gc.alloc(Node::new(None)).unwrap();
+            {
+                // Handles of nested scopes are rooted as well, until their own scope ends
+                let inner = roots.scope();
+                let c = inner.handle(gc.alloc(Node::new(None)).unwrap());
+                gc.collect(&roots);
+                assert_eq!(live(&gc).len(), 2);
+                assert!(live(&gc).contains(&c.get()));
+            }
+
+            let b = scope.handle(gc.alloc(Node::new(Some(a.get()))).unwrap());
+            gc.collect(&roots);
+            let objects = live(&gc);
+            assert_eq!(objects.len(), 2);
+            assert!(objects.contains(&a.get()) && objects.contains(&b.get()));
+            assert_eq!(unsafe { b.get().as_ref() }.next, Some(a.get()));
+            (a.get(), b.get())
+        };
+        assert!(live(&gc).contains(&a) && live(&gc).contains(&b));
+
+        gc.collect(&roots);
+        assert!(live(&gc).is_empty());
+    }
+
+    #[test]
+    fn pinned_objects_stay() {
+        let mut gc = ImmixGc::<TestAllocator, DefaultReclamation>::new();
+
+        // Two blocks of four nodes, where only the first node of each stays reachable
+        let nodes: Vec<_> = (0..8).map(|_| gc.alloc(Node::new(None)).unwrap()).collect();
+        let (a, b) = (nodes[0], nodes[4]);
+        unsafe { (*a.as_ptr()).next = Some(b) };
+        let mut roots = ApplicationRoots::new();
+        roots.add(a);
+        gc.collect(&roots);
+
+        // Both blocks are evacuated, but the pinned node stays where it is
+        gc.pin(a);
+        gc.collect_evacuating(&mut roots, 0.5);
+        assert_eq!(roots.iter().next().unwrap().cast::<Node>(), a);
+        let moved_b = unsafe { a.as_ref() }.next.unwrap();
+        assert_ne!(moved_b, b);
+
+        gc.unpin(a);
+        gc.collect_evacuating(&mut roots, 0.5);
+        assert_ne!(roots.iter().next().unwrap().cast::<Node>(), a);
+    }
+
+    #[test]
+    fn remember_old_to_young() {
+        let mut gc = ImmixGc::<TestAllocator, DefaultReclamation>::new();
+        let mut old = gc.alloc(Node::new(None)).unwrap();
+        let young = gc.alloc(Node::new(None)).unwrap();
+        unsafe { old.as_mut() }.mark();
+
+        // Only old objects referencing young ones are remembered, once each
+        gc.write_barrier(young, old);
+        assert_eq!(gc.remembered().count(), 0);
+        unsafe { old.as_mut() }.next = Some(young);
+        gc.write_barrier(old, young);
+        gc.write_barrier(old, young);
+        let remembered: Vec<_> = gc.remembered().collect();
+        assert_eq!(remembered.len(), 1);
+        assert_eq!(remembered[0].cast::<Node>(), old);
+
+        gc.collect(&ApplicationRoots::new());
+        assert_eq!(gc.remembered().count(), 0);
+    }
+
+    #[test]
+    fn minor_collect_young() {
+        let mut gc = ImmixGc::<TestAllocator, DefaultReclamation>::new();
+        let mut old = gc.alloc(Node::new(None)).unwrap();
+        let mut roots = ApplicationRoots::new();
+        roots.add(old);
+        gc.collect(&roots);
+        assert!(unsafe { old.as_ref() }.is_marked());
+
+        // The old node takes the first line, and the line after it is conservatively marked
+        let garbage = gc.alloc(Node::new(None)).unwrap();
+        let young = gc.alloc(Node::new(None)).unwrap();
+        unsafe { old.as_mut() }.next = Some(young);
+        gc.write_barrier(old, young);
+
+        // Old objects survive without being reachable, and keep the young node alive
+        gc.minor_collect(&ApplicationRoots::new());
+        assert!(unsafe { young.as_ref() }.is_marked());
+        assert_eq!(gc.remembered().count(), 0);
+        assert_eq!(gc.alloc(Node::new(None)).unwrap(), garbage);
+        assert_eq!(unsafe { old.as_ref() }.next, Some(young));
+    }
+
+    #[test]
+    fn mark_reachable() {
+        let mut gc = ImmixGc::<TestAllocator, DefaultReclamation>::new();
+        let mut alloc = |next| {
+            gc.alloc(Node::new(next))
+                .expect("Could not allocate object!")
+        };
+
+        // Each node takes a line of its own: `unreachable` is on the first, and `a` and `b` are
+        // on the next two, referencing each other
+        let unreachable = alloc(None);
+        let b = alloc(None);
+        let a = alloc(Some(b));
+        unsafe { (*b.as_ptr()).next = Some(a) };
+
+        let mut roots = ApplicationRoots::new();
+        roots.add(a);
+        gc.mark(&roots);
+
+        // Only the line of the unreachable node can be reused before the end of the block
+        let reused = gc.alloc(Node::new(None)).unwrap();
+        assert_eq!(reused, unreachable);
+        let next = gc.alloc(Node::new(None)).unwrap();
+        assert!(next != a && next != b);
+        assert_eq!(unsafe { b.as_ref() }.next, Some(a));
+
+        // Reachable objects keep their mark bit until the next collection
+        assert!(unsafe { a.as_ref().is_marked() && b.as_ref().is_marked() });
+        assert!(unsafe { !unreachable.as_ref().is_marked() });
+    }
+
+    #[test]
+    fn drop_unreachable() {
+        use alloc::rc::Rc;
+
+        /// Counts how many times it was dropped in `drops`
+        struct Owner {
+            drops: Rc<core::cell::Cell<usize>>,
+            marked: bool,
+            forwarded: Option<NonNull<u8>>,
+        }
+
+        impl ObjectHeader for Owner {
+            gc_state!();
+        }
+
+        impl Drop for Owner {
+            fn drop(&mut self) {
+                self.drops.set(self.drops.get() + 1);
+            }
+        }
+
+        let drops = Rc::new(core::cell::Cell::new(0));
+        let mut gc = ImmixGc::<TestAllocator, DefaultReclamation>::new();
+        let mut alloc = || {
+            gc.alloc(Owner {
+                drops: drops.clone(),
+                marked: false,
+                forwarded: None,
+            })
+            .unwrap()
+        };
+        let (old, young) = (alloc(), alloc());
+        let mut roots = ApplicationRoots::new();
+        roots.add(old);
+        roots.add(young);
+        gc.collect(&roots);
+        assert_eq!(drops.get(), 0);
+
+        // Objects are dropped once they are no longer reachable, whether they are old or young
+        roots.remove(young);
+        gc.minor_collect(&roots);
+        assert_eq!(drops.get(), 0);
+        gc.collect(&roots);
+        assert_eq!(drops.get(), 1);
+
+        // Evacuated objects are only dropped once, from where they moved
+        gc.collect_evacuating(&mut roots, 0.0);
+        assert_ne!(roots.iter().next().unwrap().cast::<Owner>(), old);
+        assert_eq!(drops.get(), 1);
+        gc.alloc(Owner {
+            drops: drops.clone(),
+            marked: false,
+            forwarded: None,
+        })
+        .unwrap();
+        drop(gc);
+        assert_eq!(drops.get(), 3);
+    }
+
+    #[test]
+    fn collect_past_threshold() {
+        struct Eager;
+        impl ReclamationPolicy for Eager {
+            const FIRST_GC_BYTES: usize = 256;
+        }
+
+        let mut gc = ImmixGc::<TestAllocator, Eager>::new();
+        let size = Node::new(None).size();
+        let roots = ApplicationRoots::new();
+        for _ in 0..256 / size {
+            gc.alloc_or_collect(Node::new(None), &roots).unwrap();
+        }
+        assert_eq!(gc.stats().collections_run, 0);
+        assert!(!gc.needs_collection());
+
+        // Every node is dead, so only the one allocated after the collection is left
+        gc.alloc_or_collect(Node::new(None), &roots).unwrap();
+        let stats = gc.stats();
+        assert_eq!(stats.collections_run, 1);
+        assert_eq!(stats.bytes_allocated, size);
+        assert_eq!(stats.next_gc, 256);
+
+        // The threshold grows with the bytes surviving a collection
+        let large = gc
+            .alloc(Node {
+                len: 1000,
+                ..Node::new(None)
+            })
+            .unwrap();
+        assert!(gc.needs_collection());
+        let mut roots = ApplicationRoots::new();
+        roots.add(large);
+        gc.collect(&roots);
+        let stats = gc.stats();
+        assert_eq!(stats.bytes_allocated, size + 1000);
+        assert_eq!(stats.next_gc, 2 * stats.bytes_allocated);
+    }
+
+    #[test]
+    fn alloc_large_objects() {
+        let mut gc = ImmixGc::<TestAllocator, DefaultReclamation>::new();
+        let large = |next| Node {
+            len: 4 * TestAllocator::BLOCK_SIZE_BYTES,
+            ..Node::new(next)
+        };
+        let small = gc.alloc(Node::new(None)).unwrap();
+        let a = gc
+            .alloc(large(Some(small)))
+            .expect("Could not allocate large object!");
+        let b = gc
+            .alloc(large(None))
+            .expect("Could not allocate large object!");
+        assert_ne!(a, b);
+        assert_eq!(gc.large_objects.len(), 2);
+
+        // Large objects are traced like any other, and freed once they are unreachable
+        let mut roots = ApplicationRoots::new();
+        roots.add(a);
+        gc.collect(&roots);
+        assert_eq!(gc.large_objects.len(), 1);
+        assert_eq!(unsafe { a.as_ref() }.next, Some(small));
+        assert_ne!(gc.alloc(Node::new(None)).unwrap(), small);
+    }
+
+    #[test]
+    fn evacuate_fragmented_blocks() {
+        let mut gc = ImmixGc::<TestAllocator, DefaultReclamation>::new();
+
+        // Two blocks of four nodes, where only the first node of each stays reachable
+        let nodes: Vec<_> = (0..8).map(|_| gc.alloc(Node::new(None)).unwrap()).collect();
+        let (a, b) = (nodes[0], nodes[4]);
+        unsafe { (*a.as_ptr()).next = Some(b) };
+        let mut roots = ApplicationRoots::new();
+        roots.add(a);
+        gc.collect(&roots);
+
+        // Both blocks are now three quarters free, so their nodes move to a single new block
+        gc.collect_evacuating(&mut roots, 0.5);
+        let moved_a = roots.iter().next().unwrap().cast::<Node>();
+        let moved_b = unsafe { moved_a.as_ref() }.next.unwrap();
+        assert!(moved_a != a && moved_b != b);
+        let block_of =
+            |node: NonNull<Node>| node.as_ptr() as usize / TestAllocator::BLOCK_SIZE_BYTES;
+        assert_eq!(block_of(moved_a), block_of(moved_b));
+        assert_eq!(unsafe { moved_b.as_ref() }.next, None);
+        assert!(unsafe { moved_a.as_ref().forwarded().is_none() });
+
+        // Without enough free lines, nothing moves
+        gc.collect_evacuating(&mut roots, 0.9);
+        assert_eq!(roots.iter().next().unwrap().cast::<Node>(), moved_a);
+        assert_eq!(unsafe { moved_a.as_ref() }.next, Some(moved_b));
     }
 }
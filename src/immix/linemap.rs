@@ -1,3 +1,5 @@
+#[cfg(test)]
+use alloc::string::String;
 use bit_vec::BitVec;
 
 /// Type that marks used lines within a block
@@ -19,13 +21,15 @@ impl LineMap {
     }
 
     /// Set the line `line` as unused
+    #[cfg(test)]
     pub fn set_unused(&mut self, line: usize) {
         self.0.set(line, false);
     }
 
-    /// Set each line in the range from start..end as used
+    /// Set each line in the range from start..end as used. Debug builds check that none of them
+    /// was used already, catching objects allocated twice
     pub fn set_range_used(&mut self, start: usize, end: usize) {
-        assert!(
+        debug_assert!(
             self.0.iter().skip(start).take(end - start).all(|x| !x),
             "Set already used line as used!"
         );
@@ -35,9 +39,16 @@ impl LineMap {
         }
     }
 
-    /// Set each line in the range from start..end as unused
+    /// Set every line as unused at once, whatever state each line was in
+    pub fn clear(&mut self) {
+        self.0.clear()
+    }
+
+    /// Set each line in the range from start..end as unused. Debug builds check that all of them
+    /// were used, catching objects freed twice
+    #[cfg(test)]
     pub fn set_range_unused(&mut self, start: usize, end: usize) {
-        assert!(
+        debug_assert!(
             self.0.iter().skip(start).take(end - start).all(|x| x),
             "Set already unused line as unused!"
         );
@@ -59,34 +70,74 @@ impl LineMap {
 
     /// Returns the next used line, or 1 past the end if no lines are used
     pub fn find_next_used(&self, line: usize) -> usize {
-        line + self.0.iter().skip(line).take_while(|x| !x).count()
+        self.find_next(line, 0)
     }
 
-    /// Returns the next unused line, or 1 past the end if no lines are used
+    /// Returns the next unused line, or 1 past the end if every line is used
     pub fn find_next_unused(&self, line: usize) -> usize {
-        line + self.0.iter().skip(line).take_while(|x| *x).count()
+        self.find_next(line, u32::MAX)
+    }
+
+    /// Returns the first line from `line` whose bit is set once xor-ed with `flip`, scanning a
+    /// word of the map at a time
+    fn find_next(&self, line: usize, flip: u32) -> usize {
+        let len = self.0.len();
+        if line >= len {
+            return line;
+        }
+
+        let storage = self.0.storage();
+        let mut index = line / u32::BITS as usize;
+        // The lines before `line` in its word are skipped
+        let mut word = (storage[index] ^ flip) & (u32::MAX << (line % u32::BITS as usize));
+        while word == 0 {
+            index += 1;
+            if index == storage.len() {
+                return len;
+            }
+            word = storage[index] ^ flip;
+        }
+        // The bits past the end of the map are unused, so flipped ones can be found there
+        (index * u32::BITS as usize + word.trailing_zeros() as usize).min(len)
+    }
+
+    /// Every hole in the map, a maximal run of unused lines, as the range `(start, end_exclusive)`
+    /// of its lines, in line order
+    pub fn holes(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let mut line = 0;
+        core::iter::from_fn(move || {
+            let start = self.find_next_unused(line);
+            if start >= self.len() {
+                return None;
+            }
+            line = self.find_next_used(start);
+            Some((start, line))
+        })
+    }
+
+    /// Returns the number of used lines
+    pub fn count_used(&self) -> usize {
+        self.0.iter().filter(|used| *used).count()
     }
 
     pub fn len(&self) -> usize {
         self.0.len()
     }
 
+    /// Each line as `1` if it is used and `0` otherwise
+    #[cfg(test)]
     pub fn print(&self) -> String {
         self.0
             .iter()
             .map(|used| if used { "1" } else { "0" })
             .fold(String::new(), |r, s| r + s)
-            .to_owned()
-    }
-
-    pub fn dump(&self) {
-        println!("{}", self.print());
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use alloc::vec::Vec;
 
     #[test]
     fn used_unused() {
@@ -133,6 +184,52 @@ mod test {
         assert_eq!(next_used_line, 128);
     }
 
+    #[test]
+    fn next_across_words() {
+        // Runs of lines starting and ending on both sides of word boundaries
+        let mut map = LineMap::new(5000);
+        for (start, end) in [(31, 33), (63, 130), (200, 1024), (4095, 4096), (4990, 5000)] {
+            map.set_range_used(start, end);
+        }
+
+        let next = |line: usize, used: bool| {
+            (line..map.len())
+                .find(|&i| map.is_used(i) == used)
+                .unwrap_or(map.len())
+        };
+        for line in 0..map.len() {
+            assert_eq!(map.find_next_used(line), next(line, true), "line {}", line);
+            assert_eq!(
+                map.find_next_unused(line),
+                next(line, false),
+                "line {}",
+                line
+            );
+        }
+        assert_eq!(map.find_next_unused(4990), 5000);
+        assert_eq!(map.find_next_used(5000), 5000);
+    }
+
+    #[test]
+    fn holes() {
+        let mut map = LineMap::new(100);
+        assert_eq!(map.holes().collect::<Vec<_>>(), [(0, 100)]);
+
+        for (start, end) in [(0, 3), (4, 6), (9, 40), (64, 65), (99, 100)] {
+            map.set_range_used(start, end);
+        }
+        assert_eq!(
+            map.holes().collect::<Vec<_>>(),
+            [(3, 4), (6, 9), (40, 64), (65, 99)]
+        );
+
+        let holes: Vec<_> = map.holes().collect();
+        for (start, end) in holes {
+            map.set_range_used(start, end);
+        }
+        assert_eq!(map.holes().next(), None);
+    }
+
     #[test]
     fn entire_block_unused() {
         let mut map = LineMap::new(10);
@@ -141,11 +238,35 @@ mod test {
 
         map.set_range_unused(0, 3);
         assert!(!map.entire_block_unused());
+        assert_eq!(map.print(), "0001111111");
 
         map.set_range_unused(3, 10);
         assert!(map.entire_block_unused());
     }
 
+    #[test]
+    fn clear() {
+        let mut map = LineMap::new(100);
+        map.set_range_used(10, 20);
+        map.set_used(50);
+        map.set_used(99);
+        assert!(!map.entire_block_unused());
+
+        map.clear();
+        assert!(map.entire_block_unused());
+        assert_eq!(map.len(), 100);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Set already unused line as unused!")]
+    fn double_free() {
+        let mut map = LineMap::new(10);
+        map.set_range_used(0, 4);
+        map.set_range_unused(2, 4);
+        map.set_range_unused(0, 3);
+    }
+
     #[test]
     fn entire_block_used() {
         let mut map = LineMap::new(10);
@@ -1,4 +1,5 @@
-use std::ptr::NonNull;
+use alloc::vec::Vec;
+use core::ptr::NonNull;
 
 #[derive(Debug, PartialEq)]
 pub enum AllocError {
@@ -6,6 +7,15 @@ pub enum AllocError {
     BadAlignment,
 }
 
+impl core::fmt::Display for AllocError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            AllocError::OutOfMemory => write!(f, "Out of memory"),
+            AllocError::BadAlignment => write!(f, "Bad alignment"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Block {
     ptr: BlockPtr,
@@ -18,11 +28,7 @@ pub type BlockResult = Result<Block, AllocError>;
 
 impl Block {
     pub fn new(size: BlockSize) -> BlockResult {
-        Ok(internal::alloc_block(size)?)
-    }
-
-    pub fn into_ptr_mut(self) -> BlockPtr {
-        self.ptr
+        internal::alloc_block(size)
     }
 
     pub fn size(&self) -> BlockSize {
@@ -34,6 +40,53 @@ impl Block {
     }
 }
 
+/// Blocks released by the collector, kept to be reused rather than returned to the global
+/// allocator, up to a cap
+pub struct BlockPool {
+    blocks: Vec<Block>,
+    cap: usize,
+    /// Number of blocks requested from the global allocator
+    allocated: usize,
+}
+
+impl BlockPool {
+    pub fn new(cap: usize) -> Self {
+        BlockPool {
+            blocks: Vec::new(),
+            cap,
+            allocated: 0,
+        }
+    }
+
+    /// Take a pooled block of `size` bytes, or allocate a new one if there is none. The contents of
+    /// a pooled block are left as they were when it was released
+    pub fn take(&mut self, size: BlockSize) -> BlockResult {
+        match self.blocks.iter().position(|block| block.size == size) {
+            Some(index) => Ok(self.blocks.swap_remove(index)),
+            None => {
+                let block = Block::new(size)?;
+                self.allocated += 1;
+                Ok(block)
+            }
+        }
+    }
+
+    /// Keep `block` to be taken again, or deallocate it if the pool is full
+    pub fn release(&mut self, block: Block) {
+        if self.blocks.len() < self.cap {
+            #[cfg(feature = "zero-on-free")]
+            unsafe {
+                core::ptr::write_bytes(block.as_ptr(), 0, block.size)
+            };
+            self.blocks.push(block);
+        }
+    }
+
+    pub fn allocated(&self) -> usize {
+        self.allocated
+    }
+}
+
 impl Drop for Block {
     fn drop(&mut self) {
         internal::dealloc_block(self)
@@ -42,23 +95,37 @@ impl Drop for Block {
 
 mod internal {
     use super::*;
-    use std::alloc::{alloc, dealloc, Layout};
+    use alloc::alloc::{alloc, dealloc, Layout};
+
+    /// Blocks at least this large are mapped from the OS, as size-aligned allocations of several
+    /// megabytes can fail or fragment the heap of the global allocator
+    #[cfg(all(unix, feature = "mmap"))]
+    const MMAP_THRESHOLD: BlockSize = 1024 * 1024;
 
     pub fn alloc_block(size: BlockSize) -> BlockResult {
         if !size.is_power_of_two() {
             return Err(AllocError::BadAlignment);
         }
 
+        #[cfg(all(unix, feature = "mmap"))]
+        if size >= MMAP_THRESHOLD {
+            return mmap::alloc_block(size);
+        }
+
         let ptr = unsafe { alloc(Layout::from_size_align_unchecked(size, size)) };
 
-        if let Some(ptr) = NonNull::new(ptr) {
-            return Ok(Block { ptr, size });
+        match NonNull::new(ptr) {
+            Some(ptr) => Ok(Block { ptr, size }),
+            None => Err(AllocError::OutOfMemory),
         }
-
-        return Err(AllocError::OutOfMemory);
     }
 
     pub fn dealloc_block(block: &mut Block) {
+        #[cfg(all(unix, feature = "mmap"))]
+        if block.size >= MMAP_THRESHOLD {
+            return mmap::dealloc_block(block);
+        }
+
         let size = block.size;
         unsafe {
             dealloc(
@@ -67,6 +134,50 @@ mod internal {
             )
         };
     }
+
+    #[cfg(all(unix, feature = "mmap"))]
+    mod mmap {
+        use super::*;
+        use libc::{c_void, MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+
+        /// Map a block of `size` bytes aligned to its size. The OS only aligns mappings to pages,
+        /// so twice the size is mapped and the excess on either side of the block is unmapped
+        pub fn alloc_block(size: BlockSize) -> BlockResult {
+            let mapped_size = size.checked_mul(2).ok_or(AllocError::OutOfMemory)?;
+            let mapped = unsafe {
+                libc::mmap(
+                    core::ptr::null_mut(),
+                    mapped_size,
+                    PROT_READ | PROT_WRITE,
+                    MAP_PRIVATE | MAP_ANONYMOUS,
+                    -1,
+                    0,
+                )
+            };
+            if mapped == MAP_FAILED {
+                return Err(AllocError::OutOfMemory);
+            }
+
+            let start = mapped as usize;
+            let block_start = start.next_multiple_of(size);
+            let (block_end, end) = (block_start + size, start + mapped_size);
+            unsafe {
+                if block_start > start {
+                    libc::munmap(mapped, block_start - start);
+                }
+                if end > block_end {
+                    libc::munmap(block_end as *mut c_void, end - block_end);
+                }
+            }
+
+            let ptr = NonNull::new(block_start as *mut u8).ok_or(AllocError::OutOfMemory)?;
+            Ok(Block { ptr, size })
+        }
+
+        pub fn dealloc_block(block: &mut Block) {
+            unsafe { libc::munmap(block.ptr.as_ptr().cast::<c_void>(), block.size) };
+        }
+    }
 }
 
 #[cfg(test)]
@@ -105,4 +216,19 @@ mod test {
     fn test_16m() {
         assert!(alloc_dealloc(16 * 1024 * 1024).is_ok())
     }
+
+    #[test]
+    #[cfg(all(unix, feature = "mmap"))]
+    fn test_mapped_block() {
+        let size = 4 * 1024 * 1024;
+        let block = Block::new(size).expect("Could not map block!");
+        unsafe {
+            block.as_ptr().write(1);
+            block.as_ptr().add(size - 1).write(2);
+            assert_eq!(
+                block.as_ptr().read() + block.as_ptr().add(size - 1).read(),
+                3
+            );
+        }
+    }
 }
@@ -1,9 +1,19 @@
 mod blocklist;
 mod bump_alloc;
+mod handle;
 mod header;
+#[allow(clippy::module_inception)]
 mod immix;
 mod linemap;
 mod memory;
 mod policy;
 mod roots;
+#[cfg(test)]
 mod test_allocator;
+
+pub use handle::{Handle, HandleScope};
+pub use header::ObjectHeader;
+pub use immix::{DefaultReclamation, GcStats, ImmixGc, RuntimeImmix, StickyImmix};
+pub use memory::AllocError;
+pub use policy::{AllocationPolicy, BlockGeometry, ReclamationPolicy, RuntimeAllocationPolicy};
+pub use roots::ApplicationRoots;
@@ -1,9 +1,118 @@
-/// Defines the allocation strategy of the Immix allocator/garbage collector
-pub trait AllocationPolicy {
+/// Defines the allocation strategy of the Immix allocator/garbage collector, fixed at compile time
+pub trait AllocationPolicy: Copy + Default {
     const BLOCK_SIZE_BYTES: usize;
     const LINE_SIZE_BYTES: usize;
     const LINES_PER_BLOCK: usize = Self::BLOCK_SIZE_BYTES / Self::LINE_SIZE_BYTES;
+    /// Objects larger than this are allocated in a block of their own rather than in the lines of
+    /// a shared block
+    const LARGE_OBJECT_BYTES: usize = Self::BLOCK_SIZE_BYTES;
+    /// Number of blocks the allocator can have at once. Allocations needing more fail with
+    /// `AllocError::OutOfMemory`
+    const MAX_BLOCKS: usize = usize::MAX;
+    /// Sizes of the slots small objects are packed into, smallest first. Each is a power of two
+    /// smaller than a line, which is split into slots of that size, so objects no larger than a
+    /// slot share lines instead of taking a whole one
+    const SIZE_CLASSES: &'static [usize] = &[];
+}
+
+/// Sizes the allocator lays its blocks out with. Every `AllocationPolicy` provides its constants
+/// through this trait, which compile down to them, while `RuntimeAllocationPolicy` reads the sizes
+/// it was created with
+pub trait BlockGeometry: Copy {
+    fn block_size(&self) -> usize;
+    fn line_size(&self) -> usize;
+    fn lines_per_block(&self) -> usize {
+        self.block_size() / self.line_size()
+    }
+    /// See `AllocationPolicy::LARGE_OBJECT_BYTES`
+    fn large_object_bytes(&self) -> usize;
+    /// See `AllocationPolicy::MAX_BLOCKS`
+    fn max_blocks(&self) -> usize;
+    /// See `AllocationPolicy::SIZE_CLASSES`
+    fn size_classes(&self) -> &'static [usize] {
+        &[]
+    }
+}
+
+impl<A: AllocationPolicy> BlockGeometry for A {
+    fn block_size(&self) -> usize {
+        A::BLOCK_SIZE_BYTES
+    }
+
+    fn line_size(&self) -> usize {
+        A::LINE_SIZE_BYTES
+    }
+
+    fn lines_per_block(&self) -> usize {
+        A::LINES_PER_BLOCK
+    }
+
+    fn large_object_bytes(&self) -> usize {
+        A::LARGE_OBJECT_BYTES
+    }
+
+    fn max_blocks(&self) -> usize {
+        A::MAX_BLOCKS
+    }
+
+    fn size_classes(&self) -> &'static [usize] {
+        A::SIZE_CLASSES
+    }
+}
+
+/// Allocation strategy with the block and line sizes chosen when the collector is created, for
+/// embedders tuning them without recompiling. Objects larger than a block get one of their own
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RuntimeAllocationPolicy {
+    block_size: usize,
+    line_size: usize,
+}
+
+impl RuntimeAllocationPolicy {
+    /// Both sizes must be powers of two, with lines no larger than the blocks
+    pub fn new(block_size: usize, line_size: usize) -> Self {
+        assert!(
+            block_size.is_power_of_two() && line_size.is_power_of_two() && line_size <= block_size,
+            "Bad geometry of {} byte lines in {} byte blocks",
+            line_size,
+            block_size
+        );
+        RuntimeAllocationPolicy {
+            block_size,
+            line_size,
+        }
+    }
+}
+
+impl BlockGeometry for RuntimeAllocationPolicy {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn line_size(&self) -> usize {
+        self.line_size
+    }
+
+    fn large_object_bytes(&self) -> usize {
+        self.block_size
+    }
+
+    fn max_blocks(&self) -> usize {
+        usize::MAX
+    }
 }
 
 /// Defines the reclamation strategy of the Immix allocator/garbage collector
-pub trait ReclamationPolicy {}
+pub trait ReclamationPolicy {
+    /// Number of empty blocks kept after a collection to allocate from, rather than returned to
+    /// the global allocator
+    const MAX_FREE_BLOCKS: usize = 8;
+    /// Number of blocks released by a collection, including the blocks of large objects, kept to
+    /// be reused rather than returned to the global allocator
+    const MAX_POOLED_BLOCKS: usize = 4;
+    /// Bytes of objects that can be allocated before the first collection
+    const FIRST_GC_BYTES: usize = 1024 * 1024;
+    /// How much the heap may grow past the bytes that survived a collection before the next one,
+    /// like clox's `GC_HEAP_GROW_FACTOR`
+    const HEAP_GROW_FACTOR: usize = 2;
+}
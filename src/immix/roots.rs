@@ -1,10 +1,142 @@
-use super::bump_alloc::ManagedPtr;
+use super::handle::HandleScope;
+use super::header::ObjectHeader;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::ptr::NonNull;
 
 /// Stores the pointers to the objects allocated in the block list. These pointers are searched
 /// transitively to find the lines in each block that are not used. When a used line is found it is
-/// marked as such in the line map. After tracing is complete, unused blocks are returned to the
-/// block list for allocation (right now we don't need to do this step since we don't have separate
-/// used/unused lists).
-struct ApplicationRoots {
-    roots: Vec<ManagedPtr>,
+/// marked as such in the line map. After tracing is complete, the block list files each block as
+/// free, recyclable or full by the lines left used, and allocates from the free and recyclable
+/// ones.
+///
+/// Roots are kept as trait objects rather than `ManagedPtr`s, since tracing needs to know the type
+/// of each object to find the objects it references.
+#[derive(Default)]
+pub struct ApplicationRoots {
+    roots: Vec<NonNull<dyn ObjectHeader>>,
+    /// Objects rooted by the handles of the live `HandleScope`s. The slots of the dropped handles
+    /// are emptied, and reused by the next handles
+    handles: RefCell<Vec<Option<NonNull<dyn ObjectHeader>>>>,
+}
+
+impl ApplicationRoots {
+    pub fn new() -> Self {
+        ApplicationRoots {
+            roots: Vec::new(),
+            handles: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Open a scope rooting the objects of the handles created in it until it is dropped. Roots
+    /// can still be collected from while scopes are alive, but not evacuated
+    pub fn scope(&self) -> HandleScope<'_> {
+        HandleScope::new(self)
+    }
+
+    /// Keep `root`, and every object reachable from it, alive across collections. Objects are
+    /// only added once, however many times they are rooted
+    pub fn add(&mut self, root: NonNull<dyn ObjectHeader>) {
+        if self.position(root).is_none() {
+            self.roots.push(root);
+        }
+    }
+
+    /// Stop keeping `root` alive. Does nothing if it isn't a root
+    pub fn remove(&mut self, root: NonNull<dyn ObjectHeader>) {
+        if let Some(index) = self.position(root) {
+            self.roots.swap_remove(index);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.roots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.roots.is_empty()
+    }
+
+    /// The roots, including the objects of the live handles
+    pub fn iter(&self) -> impl Iterator<Item = NonNull<dyn ObjectHeader>> + '_ {
+        let handles: Vec<_> = self.handles.borrow().iter().flatten().copied().collect();
+        self.roots.iter().copied().chain(handles)
+    }
+
+    /// The roots, which can be updated to where the collector moved them. No scope can be alive,
+    /// so there are no handles
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut NonNull<dyn ObjectHeader>> + '_ {
+        self.roots.iter_mut()
+    }
+
+    /// Root `object` in an empty handle slot, returning the slot
+    pub(super) fn push_handle(&self, object: NonNull<dyn ObjectHeader>) -> usize {
+        let mut handles = self.handles.borrow_mut();
+        match handles.iter().position(Option::is_none) {
+            Some(slot) => {
+                handles[slot] = Some(object);
+                slot
+            }
+            None => {
+                handles.push(Some(object));
+                handles.len() - 1
+            }
+        }
+    }
+
+    pub(super) fn handle(&self, slot: usize) -> NonNull<dyn ObjectHeader> {
+        self.handles.borrow()[slot].expect("Handle outlived its scope!")
+    }
+
+    pub(super) fn release_handle(&self, slot: usize) {
+        self.handles.borrow_mut()[slot] = None;
+    }
+
+    /// Index of `root`, compared by address
+    fn position(&self, root: NonNull<dyn ObjectHeader>) -> Option<usize> {
+        self.roots
+            .iter()
+            .position(|other| other.cast::<u8>() == root.cast::<u8>())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::immix::test_allocator::gc_state;
+
+    struct Object {
+        marked: bool,
+        forwarded: Option<NonNull<u8>>,
+    }
+
+    impl ObjectHeader for Object {
+        gc_state!();
+    }
+
+    #[test]
+    fn add_remove_roots() {
+        let mut objects: Vec<_> = (0..3)
+            .map(|_| Object {
+                marked: false,
+                forwarded: None,
+            })
+            .collect();
+        let ptrs: Vec<NonNull<dyn ObjectHeader>> =
+            objects.iter_mut().map(|o| NonNull::from(o) as _).collect();
+
+        let mut roots = ApplicationRoots::new();
+        assert!(roots.is_empty());
+        for ptr in ptrs.iter() {
+            roots.add(*ptr);
+        }
+        roots.add(ptrs[1]);
+        assert_eq!(roots.len(), 3);
+
+        roots.remove(ptrs[0]);
+        roots.remove(ptrs[0]);
+        let left: Vec<_> = roots.iter().map(|root| root.cast::<u8>()).collect();
+        assert_eq!(left.len(), 2);
+        assert!(left.contains(&ptrs[1].cast::<u8>()) && left.contains(&ptrs[2].cast::<u8>()));
+    }
 }
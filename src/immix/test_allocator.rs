@@ -1,5 +1,33 @@
 use crate::immix::policy::AllocationPolicy;
 
+/// Implements the collector state of `ObjectHeader` for test objects with `marked` and
+/// `forwarded` fields
+macro_rules! gc_state {
+    () => {
+        fn mark(&mut self) {
+            self.marked = true;
+        }
+
+        fn is_marked(&self) -> bool {
+            self.marked
+        }
+
+        fn unmark(&mut self) {
+            self.marked = false;
+        }
+
+        fn forwarded(&self) -> Option<core::ptr::NonNull<u8>> {
+            self.forwarded
+        }
+
+        fn forward(&mut self, to: core::ptr::NonNull<u8>) {
+            self.forwarded = Some(to);
+        }
+    };
+}
+pub(crate) use gc_state;
+
+#[derive(Clone, Copy, Default)]
 pub struct TestAllocator;
 impl AllocationPolicy for TestAllocator {
     const BLOCK_SIZE_BYTES: usize = 256;
@@ -1,4 +1,16 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+// The test harness needs `std` even when the library doesn't
+#[cfg(all(test, not(feature = "std")))]
+extern crate std;
+
 pub mod bytecode;
+pub mod compiler;
+#[cfg(feature = "capi")]
+pub mod ffi;
 pub mod immix;
 pub mod object;
+pub mod scanner;
+pub mod value;
 pub mod vm;
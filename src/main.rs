@@ -1,17 +1,87 @@
-use librlox::*;
+use librlox::compiler;
+use librlox::vm::{InterpretResult, VM};
+use std::io::{self, BufRead, Write};
 
 fn main() {
-    let mut instrs = bytecode::Chunk::new();
-    let id = instrs.add_constant(1.2);
-    instrs.write(
-        bytecode::BcInstr::LoadConst {
-            dest: bytecode::Register::ret(),
-            id,
-        },
-        123,
-    );
-    instrs.write(bytecode::BcInstr::Ret, 123);
-
-    let mut vm = vm::VM::new();
-    vm.interpret(instrs);
+    let args: Vec<String> = std::env::args().collect();
+    match args.len() {
+        1 => repl(),
+        2 => run_file(&args[1]),
+        _ => {
+            eprintln!("Usage: rlox [path]");
+            std::process::exit(64);
+        }
+    }
+}
+
+/// Compile and run the file at `path`, exiting with status 65 if it doesn't compile, 70 if it
+/// fails at runtime and 74 if it can't be read
+fn run_file(path: &str) {
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Could not read file \"{}\": {}", path, e);
+            std::process::exit(74);
+        }
+    };
+
+    if let Err(status) = run(&mut VM::new(), &source) {
+        std::process::exit(status);
+    }
+}
+
+/// Compile and run each line read from stdin in the same VM, printing its value, until the end of
+/// the input
+fn repl() {
+    let mut vm = VM::new();
+    let mut stdin = io::stdin().lock();
+    loop {
+        print!("> ");
+        io::stdout().flush().expect("Could not write to stdout!");
+
+        let mut line = String::new();
+        match stdin.read_line(&mut line) {
+            Ok(0) => {
+                println!();
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Could not read from stdin: {}", e);
+                return;
+            }
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        // Errors are reported by `run`, and the REPL carries on with the next line
+        let _ = run(&mut vm, &line);
+    }
+}
+
+/// Compile and run `source` in `vm`, printing its value. Errors are printed to stderr, returning
+/// the exit status for them
+fn run(vm: &mut VM, source: &str) -> Result<(), i32> {
+    let chunk = compiler::compile(source).map_err(|errors| {
+        for e in errors {
+            eprintln!("{}", e);
+        }
+        65
+    })?;
+
+    match vm.interpret(chunk) {
+        InterpretResult::Ok => {
+            println!("{}", vm.result());
+            Ok(())
+        }
+        InterpretResult::RuntimeErr(e) => {
+            eprintln!("{}", e);
+            Err(70)
+        }
+        result => {
+            eprintln!("{:?}", result);
+            Err(70)
+        }
+    }
 }
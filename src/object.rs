@@ -1,3 +1,407 @@
+use crate::bytecode::Chunk;
+use crate::immix::{AllocError, ObjectHeader, StickyImmix};
+use crate::value::Value;
+use alloc::string::String;
+use core::borrow::Borrow;
+use core::hash::{Hash, Hasher};
+use core::ptr::NonNull;
+
 pub trait RloxObject {
     const TYPE_ID: usize;
 }
+
+/// Kind of a heap-allocated object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjType {
+    String,
+    Function,
+    Native,
+}
+
+/// Header shared by all heap-allocated objects. Every object is `repr(C)` with the header as its
+/// first field, so a pointer to an object is also a pointer to its header
+#[repr(C)]
+pub struct ObjHeader {
+    obj_type: ObjType,
+    /// Set by the collector on the objects it reaches while tracing
+    marked: bool,
+    /// Where the collector moved the object, if it did
+    forwarded: Option<NonNull<u8>>,
+}
+
+/// Implements the tag and collector state of `ObjectHeader` for an object with the ones in its
+/// `ObjHeader`
+macro_rules! header_gc_state {
+    () => {
+        fn obj_type(&self) -> Option<ObjType> {
+            Some(self.header.obj_type)
+        }
+
+        fn mark(&mut self) {
+            self.header.marked = true;
+        }
+
+        fn is_marked(&self) -> bool {
+            self.header.marked
+        }
+
+        fn unmark(&mut self) {
+            self.header.marked = false;
+        }
+
+        fn forwarded(&self) -> Option<NonNull<u8>> {
+            self.header.forwarded
+        }
+
+        fn forward(&mut self, to: NonNull<u8>) {
+            self.header.forwarded = Some(to);
+        }
+    };
+}
+
+impl ObjHeader {
+    fn new(obj_type: ObjType) -> Self {
+        ObjHeader {
+            obj_type,
+            marked: false,
+            forwarded: None,
+        }
+    }
+
+    pub fn obj_type(&self) -> ObjType {
+        self.obj_type
+    }
+
+    /// Record that the object was evacuated to `new`, so references to it can be updated. This is
+    /// the slot `ObjectHeader::forward` stores to
+    pub fn set_forwarded(&mut self, new: NonNull<ObjHeader>) {
+        self.forwarded = Some(new.cast::<u8>());
+    }
+
+    /// Where the object was evacuated to, if it was
+    pub fn forwarding_address(&self) -> Option<NonNull<ObjHeader>> {
+        self.forwarded.map(NonNull::cast::<ObjHeader>)
+    }
+
+    /// The object `obj` is the header of, as the collector sees it: with the type its tag names,
+    /// so the collector can size and trace it
+    pub(crate) fn object(obj: NonNull<ObjHeader>) -> NonNull<dyn ObjectHeader> {
+        match unsafe { obj.as_ref() }.obj_type {
+            ObjType::String => obj.cast::<ObjString>() as NonNull<dyn ObjectHeader>,
+            ObjType::Function => obj.cast::<ObjFunction>() as NonNull<dyn ObjectHeader>,
+            ObjType::Native => obj.cast::<ObjNative>() as NonNull<dyn ObjectHeader>,
+        }
+    }
+}
+
+impl ObjHeader {
+    /// The contents of the object if it is a string
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self.obj_type {
+            // The tag guarantees that this header is the start of an `ObjString`
+            ObjType::String => {
+                let s = unsafe { &*(self as *const ObjHeader as *const ObjString) };
+                Some(s.as_str())
+            }
+            _ => None,
+        }
+    }
+
+    /// The object as a native function, if it is one
+    pub(crate) fn as_native(&self) -> Option<&ObjNative> {
+        match self.obj_type {
+            ObjType::Native => Some(unsafe { &*(self as *const ObjHeader as *const ObjNative) }),
+            _ => None,
+        }
+    }
+
+    /// The object as a function, if it is one
+    pub(crate) fn as_function(&self) -> Option<&ObjFunction> {
+        match self.obj_type {
+            ObjType::Function => {
+                Some(unsafe { &*(self as *const ObjHeader as *const ObjFunction) })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl dyn ObjectHeader {
+    /// The object as a string, if its tag says it is one
+    pub fn as_string(&self) -> Option<&ObjString> {
+        match self.obj_type() {
+            Some(ObjType::String) => Some(unsafe { &*(self as *const _ as *const ObjString) }),
+            _ => None,
+        }
+    }
+
+    /// The object as a function, if its tag says it is one
+    pub fn as_function(&self) -> Option<&ObjFunction> {
+        match self.obj_type() {
+            Some(ObjType::Function) => Some(unsafe { &*(self as *const _ as *const ObjFunction) }),
+            _ => None,
+        }
+    }
+}
+
+impl core::fmt::Display for ObjHeader {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.obj_type {
+            ObjType::String => write!(f, "{}", self.as_str().unwrap()),
+            ObjType::Function => write!(f, "{}", self.as_function().unwrap()),
+            ObjType::Native => write!(f, "<native fn>"),
+        }
+    }
+}
+
+/// Strings are quoted to tell them apart from other values
+impl core::fmt::Debug for ObjHeader {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.obj_type {
+            ObjType::String => write!(f, "{:?}", self.as_str().unwrap()),
+            ObjType::Function => write!(f, "{}", self.as_function().unwrap()),
+            ObjType::Native => write!(f, "<native fn>"),
+        }
+    }
+}
+
+/// Immutable string. The UTF-8 bytes of the string are stored inline, directly after the struct
+#[repr(C)]
+#[derive(Debug)]
+pub struct ObjString {
+    header: ObjHeader,
+    len: usize,
+}
+
+impl ObjString {
+    /// Allocate a copy of `s` in `gc`, failing if `gc` is out of memory
+    pub fn new(gc: &mut StickyImmix, s: &str) -> Result<NonNull<ObjString>, AllocError> {
+        let obj = gc.alloc(ObjString {
+            header: ObjHeader::new(ObjType::String),
+            len: s.len(),
+        })?;
+
+        // `alloc` reserved `size()` bytes, which includes room for the contents after the struct
+        unsafe {
+            core::ptr::copy_nonoverlapping(s.as_ptr(), obj.as_ptr().add(1).cast::<u8>(), s.len());
+        }
+        Ok(obj)
+    }
+
+    pub fn as_str(&self) -> &str {
+        unsafe {
+            let bytes =
+                core::slice::from_raw_parts((self as *const ObjString).add(1).cast(), self.len);
+            core::str::from_utf8_unchecked(bytes)
+        }
+    }
+}
+
+/// Entry of a string intern table, which hashes and compares the contents of the string rather
+/// than its address
+#[derive(Clone, Copy)]
+pub(crate) struct InternedStr(pub NonNull<ObjString>);
+
+impl InternedStr {
+    fn as_str(&self) -> &str {
+        // Interned strings are kept alive by the collector for as long as the table
+        unsafe { self.0.as_ref() }.as_str()
+    }
+}
+
+impl PartialEq for InternedStr {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for InternedStr {}
+
+impl Hash for InternedStr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+impl Borrow<str> for InternedStr {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl ObjectHeader for ObjString {
+    fn size(&self) -> usize {
+        core::mem::size_of::<ObjString>() + self.len
+    }
+
+    header_gc_state!();
+}
+
+/// Function compiled to bytecode. The collector drops the function when it frees it, which
+/// releases the buffers of its chunk
+#[repr(C)]
+pub struct ObjFunction {
+    header: ObjHeader,
+    pub arity: u8,
+    pub chunk: Chunk,
+    /// `None` for the top-level script
+    name: Option<NonNull<ObjString>>,
+}
+
+impl ObjFunction {
+    /// Allocate a function running `chunk` in `gc`, failing if `gc` is out of memory
+    pub fn new(
+        gc: &mut StickyImmix,
+        name: Option<&str>,
+        arity: u8,
+        chunk: Chunk,
+    ) -> Result<NonNull<ObjFunction>, AllocError> {
+        let name = name.map(|name| ObjString::new(gc, name)).transpose()?;
+        gc.alloc(ObjFunction {
+            header: ObjHeader::new(ObjType::Function),
+            arity,
+            chunk,
+            name,
+        })
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.map(|name| unsafe { name.as_ref() }.as_str())
+    }
+}
+
+impl ObjectHeader for ObjFunction {
+    fn trace(
+        &mut self,
+        visit: &mut dyn FnMut(NonNull<dyn ObjectHeader>) -> NonNull<dyn ObjectHeader>,
+    ) {
+        if let Some(name) = self.name {
+            self.name = Some(visit(name).cast::<ObjString>());
+        }
+        self.chunk
+            .trace_constants(|obj| visit(ObjHeader::object(obj)).cast::<ObjHeader>());
+    }
+
+    header_gc_state!();
+}
+
+impl core::fmt::Display for ObjFunction {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+/// Signature of the Rust functions callable from Lox. Receives the arguments of the call, and
+/// either returns the result or the message of a runtime error. The VM may collect garbage
+/// between instructions, and natives can't reach it to root objects, so objects among the
+/// arguments must not be kept past the call
+pub type NativeFn = fn(&[Value]) -> Result<Value, String>;
+
+/// Function implemented in Rust
+#[repr(C)]
+pub struct ObjNative {
+    header: ObjHeader,
+    pub arity: u8,
+    pub function: NativeFn,
+}
+
+impl ObjNative {
+    /// Allocate a native function in `gc`, failing if `gc` is out of memory
+    pub fn new(
+        gc: &mut StickyImmix,
+        arity: u8,
+        function: NativeFn,
+    ) -> Result<NonNull<ObjNative>, AllocError> {
+        gc.alloc(ObjNative {
+            header: ObjHeader::new(ObjType::Native),
+            arity,
+            function,
+        })
+    }
+}
+
+impl ObjectHeader for ObjNative {
+    header_gc_state!();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn alloc_strings() {
+        let mut gc = StickyImmix::new();
+        let empty = ObjString::new(&mut gc, "").unwrap();
+        let hello = ObjString::new(&mut gc, "hello").unwrap();
+        assert_ne!(empty, hello);
+
+        let (empty, hello) = unsafe { (empty.as_ref(), hello.as_ref()) };
+        assert_eq!(empty.as_str(), "");
+        assert_eq!(hello.as_str(), "hello");
+        assert_eq!(hello.header.obj_type(), ObjType::String);
+        assert_eq!(hello.header.to_string(), "hello");
+    }
+
+    #[test]
+    fn string_header() {
+        let mut gc = StickyImmix::new();
+        let mut hello = ObjString::new(&mut gc, "hello").unwrap();
+        let hello = unsafe { hello.as_mut() };
+        assert_eq!(hello.size(), core::mem::size_of::<ObjString>() + 5);
+
+        assert!(!hello.is_marked());
+        hello.mark();
+        assert!(hello.is_marked());
+        hello.unmark();
+        assert!(!hello.is_marked());
+    }
+
+    #[test]
+    fn forwarding_address() {
+        let mut gc = StickyImmix::new();
+        let mut from = ObjString::new(&mut gc, "from").unwrap();
+        let to = ObjString::new(&mut gc, "to").unwrap().cast::<ObjHeader>();
+
+        let from = unsafe { from.as_mut() };
+        assert_eq!(from.header.forwarding_address(), None);
+        from.header.set_forwarded(to);
+        assert_eq!(from.header.forwarding_address(), Some(to));
+        assert_eq!(from.forwarded(), Some(to.cast::<u8>()));
+    }
+
+    #[test]
+    fn alloc_functions() {
+        let mut gc = StickyImmix::new();
+        let script = ObjFunction::new(&mut gc, None, 0, Chunk::new()).unwrap();
+        let add = ObjFunction::new(&mut gc, Some("add"), 2, Chunk::new()).unwrap();
+
+        let (script, add) = unsafe { (script.as_ref(), add.as_ref()) };
+        assert_eq!(script.to_string(), "<script>");
+        assert_eq!(add.to_string(), "<fn add>");
+        assert_eq!(add.arity, 2);
+        assert_eq!(add.header.obj_type(), ObjType::Function);
+        assert!(add.header.as_function().is_some());
+        assert!(add.header.as_str().is_none());
+    }
+
+    #[test]
+    fn downcast_objects() {
+        let mut gc = StickyImmix::new();
+        let hello: NonNull<dyn ObjectHeader> = ObjString::new(&mut gc, "hello").unwrap();
+        let add: NonNull<dyn ObjectHeader> =
+            ObjFunction::new(&mut gc, Some("add"), 2, Chunk::new()).unwrap();
+
+        let (hello, add) = unsafe { (hello.as_ref(), add.as_ref()) };
+        assert_eq!(hello.obj_type(), Some(ObjType::String));
+        assert_eq!(add.obj_type(), Some(ObjType::Function));
+
+        assert_eq!(hello.as_string().map(ObjString::as_str), Some("hello"));
+        assert_eq!(add.as_function().and_then(ObjFunction::name), Some("add"));
+        assert!(hello.as_function().is_none());
+        assert!(add.as_string().is_none());
+    }
+}
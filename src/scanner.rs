@@ -0,0 +1,348 @@
+/// Kind of a token in Lox source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    // Single-character tokens
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    Comma,
+    Dot,
+    Minus,
+    Plus,
+    Semicolon,
+    Slash,
+    Star,
+    Percent,
+
+    // One or two character tokens
+    Bang,
+    BangEqual,
+    Equal,
+    EqualEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+
+    // Literals
+    Identifier,
+    String,
+    Number,
+
+    // Keywords
+    And,
+    Class,
+    Else,
+    False,
+    For,
+    Fun,
+    If,
+    Nil,
+    Or,
+    Print,
+    Return,
+    Super,
+    This,
+    True,
+    Var,
+    While,
+
+    /// The source could not be scanned. The lexeme of the token is the error message
+    Error,
+    Eof,
+}
+
+/// Token scanned from the source. The lexeme borrows from the source, except for `Error` tokens
+/// where it is the error message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub lexeme: &'a str,
+    pub line: usize,
+    /// Offset in the source of the first byte of the token
+    pub start: usize,
+}
+
+/// Splits Lox source into tokens, scanning them on demand. Cloning it gives a scanner that can look
+/// ahead without moving this one
+#[derive(Clone)]
+pub struct Scanner<'a> {
+    source: &'a str,
+    /// Offset of the first byte of the token being scanned
+    start: usize,
+    current: usize,
+    line: usize,
+}
+
+impl<'a> Scanner<'a> {
+    pub fn new(source: &'a str) -> Self {
+        Scanner {
+            source,
+            start: 0,
+            current: 0,
+            line: 1,
+        }
+    }
+
+    /// Scan the next token. Once the end of the source is reached, every call returns an `Eof`
+    /// token
+    pub fn next_token(&mut self) -> Token<'a> {
+        self.skip_whitespace();
+        self.start = self.current;
+
+        let c = match self.advance() {
+            Some(c) => c,
+            None => return self.make_token(TokenKind::Eof),
+        };
+
+        let kind = match c {
+            b'(' => TokenKind::LeftParen,
+            b')' => TokenKind::RightParen,
+            b'{' => TokenKind::LeftBrace,
+            b'}' => TokenKind::RightBrace,
+            b',' => TokenKind::Comma,
+            b'.' => TokenKind::Dot,
+            b'-' => TokenKind::Minus,
+            b'+' => TokenKind::Plus,
+            b';' => TokenKind::Semicolon,
+            b'/' => TokenKind::Slash,
+            b'*' => TokenKind::Star,
+            b'%' => TokenKind::Percent,
+            b'!' => self.either(b'=', TokenKind::BangEqual, TokenKind::Bang),
+            b'=' => self.either(b'=', TokenKind::EqualEqual, TokenKind::Equal),
+            b'<' => self.either(b'=', TokenKind::LessEqual, TokenKind::Less),
+            b'>' => self.either(b'=', TokenKind::GreaterEqual, TokenKind::Greater),
+            b'"' => return self.string(),
+            c if c.is_ascii_digit() => return self.number(),
+            c if is_alpha(c) => return self.identifier(),
+            _ => return self.error_token("Unexpected character."),
+        };
+        self.make_token(kind)
+    }
+
+    fn string(&mut self) -> Token<'a> {
+        while let Some(c) = self.peek() {
+            if c == b'"' {
+                break;
+            }
+            if c == b'\n' {
+                self.line += 1;
+            }
+            self.current += 1;
+        }
+
+        if self.advance().is_none() {
+            return self.error_token("Unterminated string.");
+        }
+        self.make_token(TokenKind::String)
+    }
+
+    fn number(&mut self) -> Token<'a> {
+        self.skip_digits();
+
+        // The fractional part needs a digit after the dot, so `1.` is a number followed by a dot
+        if self.peek() == Some(b'.') && self.peek_next().is_some_and(|c| c.is_ascii_digit()) {
+            self.current += 1;
+            self.skip_digits();
+        }
+        self.make_token(TokenKind::Number)
+    }
+
+    fn identifier(&mut self) -> Token<'a> {
+        while self
+            .peek()
+            .is_some_and(|c| is_alpha(c) || c.is_ascii_digit())
+        {
+            self.current += 1;
+        }
+
+        let kind = match &self.source[self.start..self.current] {
+            "and" => TokenKind::And,
+            "class" => TokenKind::Class,
+            "else" => TokenKind::Else,
+            "false" => TokenKind::False,
+            "for" => TokenKind::For,
+            "fun" => TokenKind::Fun,
+            "if" => TokenKind::If,
+            "nil" => TokenKind::Nil,
+            "or" => TokenKind::Or,
+            "print" => TokenKind::Print,
+            "return" => TokenKind::Return,
+            "super" => TokenKind::Super,
+            "this" => TokenKind::This,
+            "true" => TokenKind::True,
+            "var" => TokenKind::Var,
+            "while" => TokenKind::While,
+            _ => TokenKind::Identifier,
+        };
+        self.make_token(kind)
+    }
+
+    /// Skip whitespace and comments, counting the lines skipped
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            match c {
+                b' ' | b'\r' | b'\t' => self.current += 1,
+                b'\n' => {
+                    self.line += 1;
+                    self.current += 1;
+                }
+                b'/' if self.peek_next() == Some(b'/') => {
+                    while self.peek().is_some_and(|c| c != b'\n') {
+                        self.current += 1;
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn skip_digits(&mut self) {
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.current += 1;
+        }
+    }
+
+    /// Returns `matched` and consumes the next character if it is `expected`, otherwise returns
+    /// `single`
+    fn either(&mut self, expected: u8, matched: TokenKind, single: TokenKind) -> TokenKind {
+        if self.peek() == Some(expected) {
+            self.current += 1;
+            matched
+        } else {
+            single
+        }
+    }
+
+    fn advance(&mut self) -> Option<u8> {
+        let c = self.peek()?;
+        self.current += 1;
+        Some(c)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.source.as_bytes().get(self.current).copied()
+    }
+
+    fn peek_next(&self) -> Option<u8> {
+        self.source.as_bytes().get(self.current + 1).copied()
+    }
+
+    fn make_token(&self, kind: TokenKind) -> Token<'a> {
+        Token {
+            kind,
+            lexeme: &self.source[self.start..self.current],
+            line: self.line,
+            start: self.start,
+        }
+    }
+
+    fn error_token(&mut self, message: &'static str) -> Token<'a> {
+        // Skip the rest of a multi-byte character, so the next token starts on a boundary
+        while !self.source.is_char_boundary(self.current) {
+            self.current += 1;
+        }
+        Token {
+            kind: TokenKind::Error,
+            lexeme: message,
+            line: self.line,
+            start: self.start,
+        }
+    }
+}
+
+fn is_alpha(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_'
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn scan(source: &str) -> Vec<Token<'_>> {
+        let mut scanner = Scanner::new(source);
+        let mut tokens = Vec::new();
+        loop {
+            let token = scanner.next_token();
+            tokens.push(token);
+            if token.kind == TokenKind::Eof {
+                return tokens;
+            }
+        }
+    }
+
+    fn kinds(source: &str) -> Vec<TokenKind> {
+        scan(source).iter().map(|token| token.kind).collect()
+    }
+
+    #[test]
+    fn scan_print() {
+        let lexemes: Vec<_> = scan("print 1 + 2;")
+            .iter()
+            .map(|token| (token.kind, token.lexeme))
+            .collect();
+        assert_eq!(
+            lexemes,
+            [
+                (TokenKind::Print, "print"),
+                (TokenKind::Number, "1"),
+                (TokenKind::Plus, "+"),
+                (TokenKind::Number, "2"),
+                (TokenKind::Semicolon, ";"),
+                (TokenKind::Eof, ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_literals_and_operators() {
+        use TokenKind::*;
+        assert_eq!(
+            kinds("var _x1 = 1.5 >= 2. != \"a\" // comment\n or nil;"),
+            [
+                Var,
+                Identifier,
+                Equal,
+                Number,
+                GreaterEqual,
+                Number,
+                Dot,
+                BangEqual,
+                String,
+                Or,
+                Nil,
+                Semicolon,
+                Eof
+            ]
+        );
+
+        let tokens = scan("\"multi\nline\" orchid");
+        assert_eq!(tokens[0].lexeme, "\"multi\nline\"");
+        assert_eq!((tokens[1].kind, tokens[1].line), (Identifier, 2));
+    }
+
+    #[test]
+    fn scan_errors() {
+        let tokens = scan("1\n@ \"open");
+        assert_eq!(
+            tokens[1],
+            Token {
+                kind: TokenKind::Error,
+                lexeme: "Unexpected character.",
+                line: 2,
+                start: 2
+            }
+        );
+        assert_eq!(tokens[2].kind, TokenKind::Error);
+        assert_eq!(tokens[2].lexeme, "Unterminated string.");
+        assert_eq!(tokens[3].kind, TokenKind::Eof);
+
+        // Scanning carries on after characters that are not ASCII
+        assert_eq!(
+            kinds("é+"),
+            [TokenKind::Error, TokenKind::Plus, TokenKind::Eof]
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use crate::object::ObjHeader;
+use core::ptr::NonNull;
+
+#[cfg(feature = "nan-boxing")]
+pub mod nanbox;
+
+/// Represents all values in rlox
+#[derive(Clone, Copy)]
+pub enum Value {
+    Number(f64),
+    Int(i64),
+    Bool(bool),
+    Nil,
+    /// Object allocated by the garbage collector
+    Obj(NonNull<ObjHeader>),
+}
+
+impl Value {
+    /// Lox truthiness: `nil` and `false` are falsey, everything else is truthy
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    /// The opposite of `is_falsey`, for the conditions that continue on a truthy value
+    pub fn is_truthy(&self) -> bool {
+        !self.is_falsey()
+    }
+
+    /// Returns the contained number, or `None` if this value is not a number. Integers are not
+    /// converted
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Returns the contained integer, or `None` if this value is not an integer
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+/// Lox equality: numbers compare by value, so `NaN != NaN` and integers equal the floats they
+/// convert to. Objects compare by identity. Values of different types are never equal
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (*self, *other) {
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Int(a), Value::Number(b)) | (Value::Number(b), Value::Int(a)) => a as f64 == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Obj(a), Value::Obj(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Formats values the way Lox prints them: integral numbers have no trailing `.0`, booleans are
+/// lowercase and strings are printed without quotes
+impl core::fmt::Display for Value {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Nil => write!(f, "nil"),
+            // Objects are kept alive by the collector for as long as they are reachable
+            Value::Obj(obj) => write!(f, "{}", unsafe { obj.as_ref() }),
+        }
+    }
+}
+
+/// Formats values with their type, using the Lox formatting of the contents
+impl core::fmt::Debug for Value {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Value::Number(n) => write!(f, "Number({})", n),
+            Value::Int(n) => write!(f, "Int({})", n),
+            Value::Bool(b) => write!(f, "Bool({})", b),
+            Value::Nil => write!(f, "Nil"),
+            Value::Obj(obj) => write!(f, "Obj({:?})", unsafe { obj.as_ref() }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::immix::StickyImmix;
+    use crate::object::ObjString;
+    use alloc::{format, string::ToString};
+
+    #[test]
+    fn equality() {
+        let mut gc = StickyImmix::new();
+        let a = Value::Obj(ObjString::new(&mut gc, "a").unwrap().cast());
+        let other_a = Value::Obj(ObjString::new(&mut gc, "a").unwrap().cast());
+
+        let equal = [
+            (Value::Nil, Value::Nil),
+            (Value::Bool(true), Value::Bool(true)),
+            (Value::Number(1.5), Value::Number(1.5)),
+            (Value::Number(0.0), Value::Number(-0.0)),
+            (Value::Int(3), Value::Int(3)),
+            (Value::Int(3), Value::Number(3.0)),
+            (Value::Number(3.0), Value::Int(3)),
+            (a, a),
+        ];
+        for (lhs, rhs) in equal {
+            assert_eq!(lhs, rhs);
+        }
+
+        let not_equal = [
+            (Value::Bool(true), Value::Number(1.0)),
+            (Value::Bool(false), Value::Nil),
+            (Value::Number(0.0), Value::Nil),
+            (Value::Number(f64::NAN), Value::Number(f64::NAN)),
+            (Value::Bool(true), Value::Bool(false)),
+            (Value::Int(3), Value::Number(3.5)),
+            (Value::Int(1), Value::Bool(true)),
+            // Strings are only equal by identity until they are interned
+            (a, other_a),
+        ];
+        for (lhs, rhs) in not_equal {
+            assert_ne!(lhs, rhs);
+        }
+    }
+
+    #[test]
+    fn truthiness() {
+        let mut gc = StickyImmix::new();
+        let empty = Value::Obj(ObjString::new(&mut gc, "").unwrap().cast());
+
+        // Only `nil` and `false` are falsey: zero, NaN and the empty string are all truthy
+        let cases = [
+            (Value::Nil, false),
+            (Value::Bool(false), false),
+            (Value::Bool(true), true),
+            (Value::Bool(Value::Bool(false) == Value::Bool(false)), true),
+            (Value::Number(0.0), true),
+            (Value::Number(-0.0), true),
+            (Value::Number(f64::NAN), true),
+            (Value::Number(1.5), true),
+            (Value::Int(0), true),
+            (Value::Int(-1), true),
+            (empty, true),
+        ];
+        for (value, truthy) in cases {
+            assert_eq!(value.is_truthy(), truthy, "{:?}", value);
+            assert_eq!(value.is_falsey(), !truthy, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn display_values() {
+        assert_eq!(Value::Number(4.0).to_string(), "4");
+        assert_eq!(Value::Number(4.5).to_string(), "4.5");
+        assert_eq!(Value::Number(-0.25).to_string(), "-0.25");
+        assert_eq!(Value::Int(-12).to_string(), "-12");
+        assert_eq!(Value::Bool(true).to_string(), "true");
+        assert_eq!(Value::Bool(false).to_string(), "false");
+        assert_eq!(Value::Nil.to_string(), "nil");
+
+        let mut gc = StickyImmix::new();
+        let s = Value::Obj(ObjString::new(&mut gc, "hi").unwrap().cast());
+        assert_eq!(s.to_string(), "hi");
+    }
+
+    #[test]
+    fn debug_values() {
+        assert_eq!(format!("{:?}", Value::Number(4.0)), "Number(4)");
+        assert_eq!(format!("{:?}", Value::Int(4)), "Int(4)");
+        assert_eq!(format!("{:?}", Value::Bool(true)), "Bool(true)");
+        assert_eq!(format!("{:?}", Value::Nil), "Nil");
+
+        let mut gc = StickyImmix::new();
+        let s = Value::Obj(ObjString::new(&mut gc, "hi").unwrap().cast());
+        assert_eq!(format!("{:?}", s), "Obj(\"hi\")");
+    }
+}
@@ -0,0 +1,216 @@
+//! NaN-boxed representation of `Value`s. Every double that is not a quiet NaN is stored as-is, and
+//! all other values are encoded in the unused payload bits of a quiet NaN, so any value fits in 8
+//! bytes.
+
+/// Bits that are set for every quiet NaN, plus the Intel "QNaN floating-point indefinite" bit. A
+/// double with all of these set is not a number but a boxed value
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+
+use crate::object::ObjHeader;
+use core::ptr::NonNull;
+
+/// Set together with `QNAN` for object pointers, which are stored in the low 48 bits
+const OBJ_TAG: u64 = 0xfffc_0000_0000_0000;
+
+/// Set together with `QNAN` for integers, whose 48-bit payload is stored in the low bits
+const INT_TAG: u64 = 0xfffd_0000_0000_0000;
+const PAYLOAD: u64 = 0x0000_ffff_ffff_ffff;
+
+const TAG_NIL: u64 = 1;
+const TAG_FALSE: u64 = 2;
+const TAG_TRUE: u64 = 3;
+
+/// NaN-boxed value
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Value(u64);
+
+impl Value {
+    pub const NIL: Value = Value(QNAN | TAG_NIL);
+
+    /// Range of integers that fit in the payload of a boxed value
+    pub const INT_MIN: i64 = -(1 << 47);
+    pub const INT_MAX: i64 = (1 << 47) - 1;
+
+    pub fn from_number(n: f64) -> Self {
+        // Any NaN produced by arithmetic could collide with the boxed encodings, so store them all
+        // as the canonical NaN
+        if n.is_nan() {
+            Value(f64::NAN.to_bits())
+        } else {
+            Value(n.to_bits())
+        }
+    }
+
+    /// Box the integer `n`, or `None` if it doesn't fit in 48 bits
+    pub fn from_int(n: i64) -> Option<Self> {
+        (Value::INT_MIN..=Value::INT_MAX)
+            .contains(&n)
+            .then_some(Value(INT_TAG | (n as u64 & PAYLOAD)))
+    }
+
+    pub fn from_obj(obj: NonNull<ObjHeader>) -> Self {
+        let addr = obj.as_ptr() as u64;
+        debug_assert_eq!(addr & !PAYLOAD, 0, "Object pointers must fit in 48 bits");
+        Value(OBJ_TAG | addr)
+    }
+
+    pub fn from_bool(b: bool) -> Self {
+        Value(QNAN | if b { TAG_TRUE } else { TAG_FALSE })
+    }
+
+    pub fn is_number(&self) -> bool {
+        self.0 & QNAN != QNAN
+    }
+
+    pub fn is_int(&self) -> bool {
+        self.0 & !PAYLOAD == INT_TAG
+    }
+
+    pub fn is_obj(&self) -> bool {
+        self.0 & !PAYLOAD == OBJ_TAG
+    }
+
+    pub fn is_bool(&self) -> bool {
+        self.0 | 1 == QNAN | TAG_TRUE
+    }
+
+    pub fn is_nil(&self) -> bool {
+        *self == Value::NIL
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        self.is_number().then(|| f64::from_bits(self.0))
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        // Shift the payload to the top of the word so it is sign extended on the way back down
+        self.is_int().then_some(((self.0 << 16) as i64) >> 16)
+    }
+
+    pub fn as_obj(&self) -> Option<NonNull<ObjHeader>> {
+        if self.is_obj() {
+            NonNull::new((self.0 & PAYLOAD) as *mut ObjHeader)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        self.is_bool().then_some(self.0 == QNAN | TAG_TRUE)
+    }
+}
+
+impl From<super::Value> for Value {
+    fn from(v: super::Value) -> Self {
+        match v {
+            super::Value::Number(n) => Value::from_number(n),
+            super::Value::Int(n) => Value::from_int(n).expect("Integer is too large to be boxed"),
+            super::Value::Bool(b) => Value::from_bool(b),
+            super::Value::Nil => Value::NIL,
+            super::Value::Obj(obj) => Value::from_obj(obj),
+        }
+    }
+}
+
+impl From<Value> for super::Value {
+    fn from(v: Value) -> Self {
+        if let Some(n) = v.as_number() {
+            super::Value::Number(n)
+        } else if let Some(n) = v.as_int() {
+            super::Value::Int(n)
+        } else if let Some(obj) = v.as_obj() {
+            super::Value::Obj(obj)
+        } else if let Some(b) = v.as_bool() {
+            super::Value::Bool(b)
+        } else {
+            super::Value::Nil
+        }
+    }
+}
+
+impl core::fmt::Debug for Value {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        super::Value::from(*self).fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn size_of_value() {
+        assert_eq!(core::mem::size_of::<Value>(), 8);
+    }
+
+    #[test]
+    fn round_trip_numbers() {
+        for n in [
+            0.0,
+            -0.0,
+            1.5,
+            -1e300,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::MIN_POSITIVE,
+        ] {
+            let boxed = Value::from_number(n);
+            assert!(boxed.is_number());
+            assert!(!boxed.is_nil() && !boxed.is_bool());
+            assert_eq!(boxed.as_number().map(f64::to_bits), Some(n.to_bits()));
+        }
+
+        // Every NaN is still a number, even ones whose bits overlap the boxed encodings
+        let nan = Value::from_number(f64::from_bits(QNAN | TAG_TRUE));
+        assert!(nan.as_number().unwrap().is_nan());
+        assert_eq!(nan.as_bool(), None);
+    }
+
+    #[test]
+    fn round_trip_ints() {
+        for n in [0, 1, -1, Value::INT_MIN, Value::INT_MAX] {
+            let boxed = Value::from_int(n).unwrap();
+            assert!(boxed.is_int());
+            assert_eq!(boxed.as_int(), Some(n));
+            assert_eq!(boxed.as_number(), None);
+        }
+
+        assert_eq!(Value::from_int(Value::INT_MAX + 1), None);
+        assert_eq!(Value::from_int(i64::MIN), None);
+        assert_eq!(Value::from_number(1.0).as_int(), None);
+    }
+
+    #[test]
+    fn round_trip_immediates() {
+        assert_eq!(Value::from_bool(true).as_bool(), Some(true));
+        assert_eq!(Value::from_bool(false).as_bool(), Some(false));
+        assert_eq!(Value::from_bool(false).as_number(), None);
+        assert!(Value::NIL.is_nil());
+        assert_eq!(Value::NIL.as_bool(), None);
+        assert_eq!(Value::NIL.as_number(), None);
+    }
+
+    #[test]
+    fn round_trip_objects() {
+        let mut header = 0u64;
+        let obj = NonNull::from(&mut header).cast::<ObjHeader>();
+        let boxed = Value::from_obj(obj);
+        assert!(boxed.is_obj());
+        assert!(!boxed.is_int() && !boxed.is_number() && !boxed.is_nil());
+        assert_eq!(boxed.as_obj(), Some(obj));
+        assert_eq!(Value::from_int(0).unwrap().as_obj(), None);
+    }
+
+    #[test]
+    fn round_trip_enum() {
+        for v in [
+            super::super::Value::Number(3.25),
+            super::super::Value::Int(-42),
+            super::super::Value::Bool(true),
+            super::super::Value::Bool(false),
+            super::super::Value::Nil,
+        ] {
+            assert_eq!(super::super::Value::from(Value::from(v)), v);
+        }
+    }
+}
@@ -1,95 +1,1132 @@
-use crate::bytecode::{BcInstr, Chunk, Register, Value};
-use std::cell::RefCell;
-use std::mem::MaybeUninit;
+use crate::bytecode::{wide_constant_id, BcInstr, Chunk, ConstantId, Register};
+use crate::immix::{AllocError, ApplicationRoots, GcStats, ObjectHeader, StickyImmix};
+use crate::object::{InternedStr, NativeFn, ObjFunction, ObjHeader, ObjNative, ObjString};
+use crate::value::Value;
+use alloc::{
+    boxed::Box,
+    format,
+    rc::Rc,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::cell::RefCell;
+#[cfg(not(feature = "std"))]
+use core::fmt::Write;
+use core::ptr::NonNull;
+use hashbrown::{HashMap, HashSet};
+#[cfg(feature = "std")]
+use std::io::Write;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
 
 const STACK_MAX: usize = 256;
-const REGISTER_MAX: usize = 16;
+/// Number of registers addressable by a single function
+pub const REGISTER_MAX: usize = 16;
+/// Number of spill slots of a single function
+pub const SPILL_MAX: usize = STACK_MAX;
+
+/// Representation of a value in the register stack
+#[cfg(feature = "nan-boxing")]
+type Slot = crate::value::nanbox::Value;
+#[cfg(not(feature = "nan-boxing"))]
+type Slot = Value;
+
+#[cfg(feature = "nan-boxing")]
+const NIL_SLOT: Slot = Slot::NIL;
+#[cfg(not(feature = "nan-boxing"))]
+const NIL_SLOT: Slot = Value::Nil;
+
+/// Range of integers a register can hold. NaN-boxed registers only have room for 48 bits
+#[cfg(feature = "nan-boxing")]
+pub const INT_MIN: i64 = Slot::INT_MIN;
+#[cfg(feature = "nan-boxing")]
+pub const INT_MAX: i64 = Slot::INT_MAX;
+#[cfg(not(feature = "nan-boxing"))]
+pub const INT_MIN: i64 = i64::MIN;
+#[cfg(not(feature = "nan-boxing"))]
+pub const INT_MAX: i64 = i64::MAX;
+
+/// Destination of printed values and of the execution trace
+#[cfg(feature = "std")]
+pub type Sink = Box<dyn std::io::Write>;
+/// Destination of printed values and of the execution trace. Without `std` there is no stdout, so
+/// output is discarded unless the VM is given a sink
+#[cfg(not(feature = "std"))]
+pub type Sink = Box<dyn core::fmt::Write>;
+
+#[cfg(feature = "std")]
+type SinkError = std::io::Error;
+#[cfg(not(feature = "std"))]
+type SinkError = core::fmt::Error;
+
+#[cfg(feature = "std")]
+fn stdout() -> Sink {
+    Box::new(std::io::stdout())
+}
+
+#[cfg(not(feature = "std"))]
+fn stdout() -> Sink {
+    struct Discard;
+
+    impl Write for Discard {
+        fn write_str(&mut self, _: &str) -> core::fmt::Result {
+            Ok(())
+        }
+    }
+
+    Box::new(Discard)
+}
+
+/// Result of an integer operation, failing if it overflowed or doesn't fit in a register
+fn checked_int(n: Option<i64>) -> Result<Value, String> {
+    n.filter(|n| (INT_MIN..=INT_MAX).contains(n))
+        .map(Value::Int)
+        .ok_or_else(|| "Integer overflow".to_string())
+}
+
+/// Number of bits to shift by for `Shl` and `Shr`. Shifting by 64 bits or more would drop every
+/// bit, so it is an error like a negative amount rather than being masked
+fn shift_amount(b: i64) -> Result<u32, String> {
+    u32::try_from(b)
+        .ok()
+        .filter(|&b| b < i64::BITS)
+        .ok_or_else(|| "Shift amount out of range".to_string())
+}
+
+/// `a` raised to the power `b`. `f64::powf` needs `std`, so `libm` stands in for it without
+fn powf(a: f64, b: f64) -> f64 {
+    #[cfg(feature = "std")]
+    return a.powf(b);
+    #[cfg(not(feature = "std"))]
+    return libm::pow(a, b);
+}
+
+/// `a * b + c` rounded once, with the hardware FMA if there is one. `libm` computes it in
+/// software without `std`
+fn mul_add(a: f64, b: f64, c: f64) -> f64 {
+    #[cfg(feature = "std")]
+    return a.mul_add(b, c);
+    #[cfg(not(feature = "std"))]
+    return libm::fma(a, b, c);
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum InterpretResult {
     Ok,
     CompileErr,
-    RuntimeErr,
+    RuntimeErr(RuntimeError),
+    /// The program ran out of its instruction budget
+    LimitExceeded,
+    /// Execution stopped before running the instruction of the script at this offset, which has a
+    /// breakpoint
+    BreakpointHit(usize),
 }
 
-pub struct VM {
-    stack: RefCell<[Value; STACK_MAX]>,
-    chunk: Chunk,
+/// Error that stopped the execution of a program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeError {
+    pub message: String,
+    /// Source line of the instruction that failed
+    pub line: usize,
+    /// The line each function being called was running, from the one that failed out to the
+    /// script, one per line: `[line 10] in foo()` or `[line 3] in script`. Empty if the program
+    /// failed to load
+    pub trace: String,
+}
+
+impl RuntimeError {
+    /// Error raised while loading a program, before any of it ran
+    fn load(err: AllocError) -> InterpretResult {
+        InterpretResult::RuntimeErr(RuntimeError {
+            message: err.to_string(),
+            line: 0,
+            trace: String::new(),
+        })
+    }
+}
+
+impl core::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{}\n{}", self.message, self.trace)
+    }
+}
+
+/// Name of a global variable, stored as a string constant in `chunk`
+fn global_name(chunk: &Chunk, name: u16) -> Result<&str, String> {
+    let id = name as ConstantId;
+    chunk
+        .string(id)
+        .or_else(|| match chunk.constant(id)? {
+            Value::Obj(obj) => unsafe { obj.as_ref() }.as_str(),
+            _ => None,
+        })
+        .ok_or_else(|| format!("Invalid global variable name 0x{:X}", name))
+}
+
+/// Activation of a function
+struct CallFrame {
+    function: NonNull<ObjFunction>,
+    /// Index of the next instruction to run in the chunk of the function
     ip: usize,
+    /// Index in the stack of the first register of the function
+    base: usize,
+    /// Index in `VM::spills` of the first spill slot of the function
+    spills: usize,
+}
+
+impl CallFrame {
+    fn chunk(&self) -> &Chunk {
+        // Functions are kept alive by the heap for as long as they are running
+        unsafe { &self.function.as_ref().chunk }
+    }
+}
+
+pub struct VM {
+    /// Registers of the program, all initialized to nil. Each call frame addresses the
+    /// `REGISTER_MAX` registers starting at its base
+    stack: RefCell<Box<[Slot]>>,
+
+    /// Functions currently running. The last one is executing
+    frames: Vec<CallFrame>,
+
+    /// Values spilled out of the registers, in the spill slots of each running function. Grows as
+    /// the slots are used, and each function's slots are dropped when it returns
+    spills: Vec<Slot>,
+
+    /// Global variables, by name
+    globals: HashMap<String, Value>,
+
+    /// Value of the register passed to the last `Ret`
+    result: Value,
+
+    /// Destination of the `Print` instruction
+    sink: Sink,
+
+    /// Whether the registers and each instruction are written to `trace_out` before it executes
+    trace: bool,
+
+    /// Destination of the execution trace
+    trace_out: Sink,
+
+    /// Whether the executed instructions are counted in `counts`
+    profile: bool,
+
+    /// Number of times each kind of instruction was executed, indexed by `BcInstr::opcode`
+    counts: [u64; BcInstr::COUNT],
+
+    /// Number of times each instruction of the script ran, by offset, if coverage is recorded.
+    /// Instructions of the functions it calls are not counted
+    coverage: Option<Vec<u64>>,
+
+    /// Number of instructions the program may still execute, if it is limited
+    fuel: Option<u64>,
+
+    /// Offsets of the instructions of the script to stop at. Functions it calls never stop, as
+    /// their offsets would match the breakpoints too
+    breakpoints: HashSet<usize>,
+
+    /// Offset of the breakpoint execution stopped at, so resuming runs past it
+    paused_at: Option<usize>,
+
+    /// Canonical copy of every string, so equal strings are the same object
+    strings: HashSet<InternedStr>,
+
+    /// Heap of all the objects used by the program
+    gc: StickyImmix,
+    /// Roots of the handle scopes embedders open with `handle_roots`
+    handle_roots: Rc<ApplicationRoots>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
-    pub fn with_chunk(chunk: Chunk) -> Self {
+    /// Create a VM ready to run `chunk`, failing if there is no memory for it
+    pub fn with_chunk(chunk: Chunk) -> Result<Self, AllocError> {
+        let mut vm = VM::new();
+        vm.load_program(chunk)?;
+        Ok(vm)
+    }
+
+    pub fn new() -> Self {
         VM {
-            stack: RefCell::new(unsafe { MaybeUninit::uninit().assume_init() }),
-            chunk,
-            ip: 0,
+            stack: RefCell::new(vec![NIL_SLOT; STACK_MAX].into_boxed_slice()),
+            frames: Vec::new(),
+            spills: Vec::new(),
+            globals: HashMap::new(),
+            result: Value::Nil,
+            sink: stdout(),
+            trace: false,
+            trace_out: stdout(),
+            profile: false,
+            counts: [0; BcInstr::COUNT],
+            coverage: None,
+            fuel: None,
+            breakpoints: HashSet::new(),
+            paused_at: None,
+            strings: HashSet::new(),
+            gc: StickyImmix::new(),
+            handle_roots: Rc::new(ApplicationRoots::new()),
         }
     }
 
-    pub fn new() -> Self {
-        VM::with_chunk(Chunk::new())
+    /// Create a VM that writes printed values to `sink` instead of stdout
+    pub fn with_sink(sink: Sink) -> Self {
+        VM { sink, ..VM::new() }
+    }
+
+    /// Enable or disable tracing of the execution
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    /// Enable or disable counting the executed instructions. Enabling it resets the counts
+    pub fn set_profile(&mut self, profile: bool) {
+        if profile {
+            self.counts = [0; BcInstr::COUNT];
+        }
+        self.profile = profile;
+    }
+
+    /// Number of times each kind of instruction was executed while profiling, most executed
+    /// first. Instructions that never ran are left out
+    pub fn profile_report(&self) -> Vec<(&'static str, u64)> {
+        let mut report: Vec<_> = BcInstr::NAMES
+            .iter()
+            .zip(self.counts)
+            .filter(|&(_, count)| count > 0)
+            .map(|(&name, count)| (name, count))
+            .collect();
+        report.sort_by_key(|&(_, count)| core::cmp::Reverse(count));
+        report
+    }
+
+    /// Enable or disable counting how many times each instruction of the script runs. The counts
+    /// are reset whenever a program is loaded
+    pub fn set_coverage(&mut self, coverage: bool) {
+        let len = self
+            .frames
+            .first()
+            .map_or(0, |script| script.chunk().instrs().len());
+        self.coverage = coverage.then(|| vec![0; len]);
     }
 
-    pub fn load_program(&mut self, chunk: Chunk) {
-        self.chunk = chunk;
-        self.ip = 0;
+    /// Number of times each instruction of the script ran, by offset, while recording coverage
+    pub fn coverage(&self) -> &[u64] {
+        self.coverage.as_deref().unwrap_or(&[])
+    }
+
+    /// Write the execution trace to `out` instead of stdout
+    pub fn set_trace_out(&mut self, out: Sink) {
+        self.trace_out = out;
+    }
+
+    /// Prepare to run `chunk` as the top-level script, failing if there is no memory for it
+    pub fn load_program(&mut self, chunk: Chunk) -> Result<(), AllocError> {
+        let len = chunk.instrs().len();
+        let script = ObjFunction::new(&mut self.gc, None, 0, chunk)?;
+        if let Some(coverage) = &mut self.coverage {
+            *coverage = vec![0; len];
+        }
+        self.frames.clear();
+        self.spills.clear();
+        self.paused_at = None;
+        self.frames.push(CallFrame {
+            function: script,
+            ip: 0,
+            base: 0,
+            spills: 0,
+        });
+        Ok(())
     }
 
     pub fn interpret(&mut self, chunk: Chunk) -> InterpretResult {
-        self.load_program(chunk);
+        self.fuel = None;
+        if let Err(err) = self.load_program(chunk) {
+            return RuntimeError::load(err);
+        }
+        self.run()
+    }
+
+    /// Run `chunk`, stopping with `InterpretResult::LimitExceeded` if it doesn't finish within
+    /// `max_steps` instructions. Meant for running untrusted code
+    pub fn interpret_with_limit(&mut self, chunk: Chunk, max_steps: u64) -> InterpretResult {
+        self.fuel = Some(max_steps);
+        if let Err(err) = self.load_program(chunk) {
+            return RuntimeError::load(err);
+        }
+        self.run()
+    }
+
+    /// Run `chunk` `iters` times and return the total time it took, or the result of the first
+    /// run that didn't finish successfully
+    #[cfg(feature = "std")]
+    pub fn run_bench(&mut self, chunk: Chunk, iters: u32) -> Result<Duration, InterpretResult> {
+        self.fuel = None;
+        self.load_program(chunk).map_err(RuntimeError::load)?;
+        let script = self.frame().function;
+
+        let start = Instant::now();
+        for _ in 0..iters {
+            self.frames.clear();
+            self.spills.clear();
+            self.frames.push(CallFrame {
+                function: script,
+                ip: 0,
+                base: 0,
+                spills: 0,
+            });
+            match self.run() {
+                InterpretResult::Ok => {}
+                result => return Err(result),
+            }
+        }
+        Ok(start.elapsed())
+    }
+
+    /// Continue running the program after a breakpoint. Does nothing once the program finished
+    pub fn resume(&mut self) -> InterpretResult {
+        if self.frames.is_empty() {
+            return InterpretResult::Ok;
+        }
         self.run()
     }
 
+    /// Execute a single instruction, returning the result of the program if it finished, or
+    /// right away if it already had
+    pub fn step_public(&mut self) -> Option<InterpretResult> {
+        if self.frames.is_empty() {
+            return Some(InterpretResult::Ok);
+        }
+        self.step()
+    }
+
+    /// Offset of the next instruction to run in the running function, if any is running
+    pub fn current_ip(&self) -> Option<usize> {
+        self.frames.last().map(|frame| frame.ip)
+    }
+
+    /// Snapshot of the registers of the running function. Copied out rather than borrowed, as with
+    /// NaN-boxing the registers hold packed slots rather than `Value`s
+    pub fn registers(&self) -> Vec<Value> {
+        (0..REGISTER_MAX)
+            .map(|r| self.load(Register::new(r as u8)))
+            .collect()
+    }
+
+    /// Stop execution with `InterpretResult::BreakpointHit` before running the instruction of the
+    /// script at `ip`. Breakpoints only apply to the script, not to the functions it calls
+    pub fn set_breakpoint(&mut self, ip: usize) {
+        self.breakpoints.insert(ip);
+    }
+
+    /// Allocate a copy of `s` on the heap of the VM, failing if the heap is out of memory
+    pub fn alloc_string(&mut self, s: &str) -> Result<Value, AllocError> {
+        if let Some(interned) = self.strings.get(s) {
+            return Ok(Value::Obj(interned.0.cast::<ObjHeader>()));
+        }
+
+        let obj = ObjString::new(&mut self.gc, s)?;
+        self.strings.insert(InternedStr(obj));
+        Ok(Value::Obj(obj.cast::<ObjHeader>()))
+    }
+
+    /// Define the global `name` as the Rust function `function`, so Lox code can call it with
+    /// `arity` arguments. Fails if the heap is out of memory
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: u8,
+        function: NativeFn,
+    ) -> Result<(), AllocError> {
+        let native = ObjNative::new(&mut self.gc, arity, function)?;
+        self.globals
+            .insert(name.to_string(), Value::Obj(native.cast::<ObjHeader>()));
+        Ok(())
+    }
+
+    /// Allocate a function named `name` running `chunk` on the heap of the VM, failing if the heap
+    /// is out of memory
+    pub fn alloc_function(
+        &mut self,
+        name: &str,
+        arity: u8,
+        chunk: Chunk,
+    ) -> Result<Value, AllocError> {
+        let function = ObjFunction::new(&mut self.gc, Some(name), arity, chunk)?;
+        Ok(Value::Obj(function.cast::<ObjHeader>()))
+    }
+
+    /// Counters of the collector owning every object of the program
+    pub fn heap_stats(&self) -> GcStats {
+        self.gc.stats()
+    }
+
+    /// Roots that every collection of the VM keeps alive, for embedders holding objects across
+    /// calls into it. Objects given a handle by a `HandleScope` opened on them survive until the
+    /// scope is dropped. Scopes borrow the returned roots rather than the VM, so the VM can keep
+    /// running while they are alive
+    pub fn handle_roots(&self) -> Rc<ApplicationRoots> {
+        Rc::clone(&self.handle_roots)
+    }
+
+    /// Collect garbage if enough was allocated since the last collection for it to be due.
+    ///
+    /// This is a safepoint: collection only happens here, between instructions, where every
+    /// object the program uses is in a register, a spill slot, a global or the constant pool of a
+    /// running function. Objects allocated in the middle of an instruction are stored before it
+    /// ends, so they are never missed. Embedders holding pointers to objects across a safepoint
+    /// must root them in a `HandleScope` opened on `handle_roots`, as they may be freed otherwise.
+    /// Native functions can't reach the VM, so they must not keep objects past their call
+    pub fn maybe_collect(&mut self) {
+        if self.gc.needs_collection() {
+            self.collect_garbage();
+        }
+    }
+
+    /// Free every object the program can no longer reach. The objects in the registers of the
+    /// running functions, the functions themselves, the globals, the last result and the handles
+    /// of `handle_roots` are the roots. Interned strings are only kept if they are reachable
+    /// otherwise
+    pub fn collect_garbage(&mut self) {
+        let top = self
+            .frames
+            .last()
+            .map_or(0, |frame| frame.base + REGISTER_MAX);
+        let stack = self.stack.borrow();
+        #[allow(clippy::useless_conversion)]
+        let registers = stack[..top]
+            .iter()
+            .chain(&self.spills)
+            .map(|&slot| Value::from(slot));
+
+        let mut roots = ApplicationRoots::new();
+        for value in registers
+            .chain(self.globals.values().copied())
+            .chain(core::iter::once(self.result))
+        {
+            if let Value::Obj(obj) = value {
+                roots.add(ObjHeader::object(obj));
+            }
+        }
+        for frame in self.frames.iter() {
+            roots.add(frame.function);
+        }
+        for handle in self.handle_roots.iter() {
+            roots.add(handle);
+        }
+        drop(stack);
+
+        self.gc.mark(&roots);
+        self.strings
+            .retain(|interned| unsafe { interned.0.as_ref() }.is_marked());
+        self.gc.sweep();
+    }
+
+    /// The value returned by the last `Ret` executed
+    pub fn result(&self) -> Value {
+        self.result
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("No function is running!")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("No function is running!")
+    }
+
+    /// Chunk of the running function
+    fn chunk(&self) -> &Chunk {
+        self.frame().chunk()
+    }
+
+    /// Index in the stack of `r` in the window of the running function
+    fn slot_index(&self, r: Register) -> usize {
+        self.frames.last().map_or(0, |frame| frame.base) + r.num()
+    }
+
+    // `Slot` is `Value` itself unless values are NaN-boxed
+    #[allow(clippy::useless_conversion)]
     fn load(&self, r: Register) -> Value {
-        self.stack.borrow()[r.num()]
+        self.stack.borrow()[self.slot_index(r)].into()
+    }
+
+    /// Load the number in `r`, promoting integers to floats, or `None` if it holds another type of
+    /// value. Avoids converting the whole register to a `Value` on the arithmetic paths
+    fn load_number(&self, r: Register) -> Option<f64> {
+        let slot = self.stack.borrow()[self.slot_index(r)];
+        slot.as_number().or_else(|| slot.as_int().map(|n| n as f64))
+    }
+
+    /// Load the integer in `r`, or `None` if it holds another type of value
+    fn load_int(&self, r: Register) -> Option<i64> {
+        self.stack.borrow()[self.slot_index(r)].as_int()
     }
 
+    #[allow(clippy::useless_conversion)]
     fn store(&self, dest: Register, v: Value) {
-        self.stack.borrow_mut()[dest.num()] = v;
+        self.stack.borrow_mut()[self.slot_index(dest)] = v.into();
+    }
+
+    /// The interned copy of `obj` if it is a string, interning it if there is none yet
+    fn intern(&mut self, obj: NonNull<ObjHeader>) -> Value {
+        // Constants are kept alive by the collector for as long as their chunk
+        if let Some(s) = unsafe { obj.as_ref() }.as_str() {
+            match self.strings.get(s) {
+                Some(interned) => return Value::Obj(interned.0.cast::<ObjHeader>()),
+                None => {
+                    self.strings.insert(InternedStr(obj.cast::<ObjString>()));
+                }
+            }
+        }
+        Value::Obj(obj)
+    }
+
+    /// Store `a + b` in `dest`: the sum of two numbers, which stays an integer if both are, or the
+    /// concatenation of two strings
+    fn add(&mut self, dest: Register, a: Value, b: Value) -> Result<(), String> {
+        let number = |v: Value| v.as_number().or_else(|| v.as_int().map(|n| n as f64));
+        let s = match (a, b) {
+            (Value::Int(a), Value::Int(b)) => {
+                return checked_int(a.checked_add(b)).map(|v| self.store(dest, v))
+            }
+            _ if number(a).is_some() && number(b).is_some() => {
+                self.store(dest, Value::Number(number(a).unwrap() + number(b).unwrap()));
+                return Ok(());
+            }
+            // Objects are kept alive by the collector for as long as they are reachable
+            (Value::Obj(a), Value::Obj(b)) => unsafe {
+                match (a.as_ref().as_str(), b.as_ref().as_str()) {
+                    (Some(a), Some(b)) => [a, b].concat(),
+                    _ => return Err("Operands must be two numbers or two strings".to_string()),
+                }
+            },
+            _ => return Err("Operands must be two numbers or two strings".to_string()),
+        };
+
+        // Storing the string straight away roots it before anything else can allocate
+        let s = self.alloc_string(&s).map_err(|err| err.to_string())?;
+        self.store(dest, s);
+        Ok(())
+    }
+
+    /// Move the instruction pointer `offset` instructions from the current one, failing if the
+    /// target is not an instruction in the chunk
+    fn jump(&mut self, offset: isize) -> Result<(), String> {
+        match self.frame().ip.checked_add_signed(offset) {
+            Some(target) if target < self.chunk().instrs().len() => {
+                self.frame_mut().ip = target;
+                Ok(())
+            }
+            _ => Err("Jump out of bounds".to_string()),
+        }
+    }
+
+    /// Call the function in `callee`. Bytecode functions run in a new frame whose registers start
+    /// at `callee`, with every register past the arguments nil, while natives run immediately and
+    /// store their result in `callee`
+    fn call(&mut self, callee: Register, arg_count: u8) -> Result<(), String> {
+        // The arguments have to be within the registers of the caller
+        if callee.num() + arg_count as usize >= REGISTER_MAX {
+            return Err("Too many arguments".to_string());
+        }
+
+        let obj = match self.load(callee) {
+            Value::Obj(obj) => unsafe { obj.as_ref() },
+            _ => return Err("Can only call functions".to_string()),
+        };
+
+        let check_arity = |arity: u8| {
+            if arity == arg_count {
+                Ok(())
+            } else {
+                Err(format!(
+                    "Expected {} arguments but got {}",
+                    arity, arg_count
+                ))
+            }
+        };
+
+        if let Some(native) = obj.as_native() {
+            check_arity(native.arity)?;
+            let args: Vec<Value> = (1..=arg_count)
+                .map(|i| self.load(Register::new(callee.num() as u8 + i)))
+                .collect();
+            let result = (native.function)(&args)?;
+            self.store(callee, result);
+            return Ok(());
+        }
+
+        let function = obj.as_function().ok_or("Can only call functions")?;
+        check_arity(function.arity)?;
+        let function = NonNull::from(function);
+
+        let base = self.slot_index(callee);
+        if base + REGISTER_MAX > STACK_MAX {
+            return Err("Stack overflow".to_string());
+        }
+
+        // Registers past the arguments may still hold objects left by a call that returned. Only the
+        // window of the running function is a root, so they may have been freed since
+        let first_local = base + 1 + arg_count as usize;
+        self.stack.borrow_mut()[first_local..base + REGISTER_MAX].fill(NIL_SLOT);
+
+        self.frames.push(CallFrame {
+            function,
+            ip: 0,
+            base,
+            spills: self.spills.len(),
+        });
+        Ok(())
+    }
+
+    /// Save `src` in the spill slot `slot` of the running function
+    fn spill(&mut self, slot: u16, src: Register) -> Result<(), String> {
+        if slot as usize >= SPILL_MAX {
+            return Err("Spill slot out of range".to_string());
+        }
+        let index = self.frame().spills + slot as usize;
+        if self.spills.len() <= index {
+            self.spills.resize(index + 1, NIL_SLOT);
+        }
+        self.spills[index] = self.stack.borrow()[self.slot_index(src)];
+        Ok(())
+    }
+
+    /// Load the value saved in the spill slot `slot` of the running function into `dest`
+    fn unspill(&mut self, dest: Register, slot: u16) -> Result<(), String> {
+        let index = self.frame().spills + slot as usize;
+        let value = *self.spills.get(index).ok_or("Spill slot is empty")?;
+        self.stack.borrow_mut()[self.slot_index(dest)] = value;
+        Ok(())
+    }
+
+    fn define_global(&mut self, name: u16, src: Register) -> Result<(), String> {
+        let value = self.load(src);
+        let name = global_name(self.chunk(), name)?.to_string();
+        self.globals.insert(name, value);
+        Ok(())
+    }
+
+    fn get_global(&mut self, dest: Register, name: u16) -> Result<(), String> {
+        let name = global_name(self.chunk(), name)?;
+        match self.globals.get(name) {
+            Some(&value) => {
+                self.store(dest, value);
+                Ok(())
+            }
+            None => Err(format!("Undefined variable '{}'", name)),
+        }
+    }
+
+    fn set_global(&mut self, name: u16, src: Register) -> Result<(), String> {
+        let value = self.load(src);
+        let frame = self.frames.last().expect("No function is running!");
+        let name = global_name(frame.chunk(), name)?;
+        match self.globals.get_mut(name) {
+            Some(global) => {
+                *global = value;
+                Ok(())
+            }
+            None => Err(format!("Undefined variable '{}'", name)),
+        }
+    }
+
+    /// The constant `id` of the running chunk, failing if the chunk has no such constant
+    fn constant(&mut self, id: ConstantId) -> Result<Value, String> {
+        if let Some(s) = self.chunk().string(id) {
+            // Allocating borrows the VM mutably while `s` borrows the chunk, so only copy the string
+            // out of the chunk when it isn't interned yet
+            return match self.strings.get(s) {
+                Some(interned) => Ok(Value::Obj(interned.0.cast::<ObjHeader>())),
+                None => {
+                    let s = s.to_string();
+                    self.alloc_string(&s).map_err(|err| err.to_string())
+                }
+            };
+        }
+
+        match self.chunk().constant(id) {
+            Some(Value::Int(n)) => checked_int(Some(n)),
+            Some(Value::Obj(obj)) => Ok(self.intern(obj)),
+            Some(constant) => Ok(constant),
+            None => Err(format!("Undefined constant 0x{:X}", id)),
+        }
+    }
+
+    /// Store `op` applied to the integers in `a` and `b` into `dest`, for the bitwise instructions
+    fn bitwise_op(
+        &mut self,
+        dest: Register,
+        a: Register,
+        b: Register,
+        op: impl FnOnce(i64, i64) -> Result<i64, String>,
+    ) -> Result<(), String> {
+        match (self.load_int(a), self.load_int(b)) {
+            (Some(a), Some(b)) => checked_int(Some(op(a, b)?)).map(|v| self.store(dest, v)),
+            _ => Err("Operands must be integers".to_string()),
+        }
+    }
+
+    /// Add `delta` to the number in `dest`, for `Inc` and `Dec`. Integers stay integers, failing
+    /// on overflow, as in `Add`
+    fn step_by(&mut self, dest: Register, delta: i64) -> Result<(), String> {
+        match self.load_int(dest) {
+            Some(n) => checked_int(n.checked_add(delta)).map(|v| self.store(dest, v)),
+            None => match self.load_number(dest) {
+                Some(n) => {
+                    self.store(dest, Value::Number(n + delta as f64));
+                    Ok(())
+                }
+                None => Err("Operand must be a number".to_string()),
+            },
+        }
+    }
+
+    /// Add the product of the integers `a` and `b` to `dest`, for `MulAdd`. The product overflows
+    /// as in `Mul`, and is only rounded along with the sum when added to a float
+    fn mul_add_ints(&mut self, dest: Register, a: i64, b: i64) -> Result<(), String> {
+        let product = checked_int(a.checked_mul(b))?;
+        match (self.load_int(dest), self.load_number(dest)) {
+            (None, Some(c)) => {
+                self.store(dest, Value::Number(mul_add(a as f64, b as f64, c)));
+                Ok(())
+            }
+            _ => self.add(dest, self.load(dest), product),
+        }
+    }
+
+    /// Store the constant `id` into `dest`, failing if the chunk has no such constant
+    fn load_constant(&mut self, dest: Register, id: ConstantId) -> Result<(), String> {
+        let constant = self.constant(id)?;
+        self.store(dest, constant);
+        Ok(())
     }
 
+    /// Whether anything has to happen between instructions, so `run` can't take the fast path
+    fn is_instrumented(&self) -> bool {
+        self.fuel.is_some()
+            || self.trace
+            || self.profile
+            || self.coverage.is_some()
+            || !self.breakpoints.is_empty()
+    }
+
+    /// Execute a single instruction, honoring the instruction budget, profiling and tracing
     fn step(&mut self) -> Option<InterpretResult> {
-        let ip = self.ip;
-        self.ip += 1;
+        self.maybe_collect();
+        self.paused_at = None;
+        if let Some(fuel) = &mut self.fuel {
+            if *fuel == 0 {
+                return Some(InterpretResult::LimitExceeded);
+            }
+            *fuel -= 1;
+        }
 
-        #[cfg(debug_assertions)]
-        {
-            println!();
-            for r in 0..REGISTER_MAX {
-                println!("[{}]", self.stack.borrow()[r]);
+        let ip = self.frame().ip;
+        if self.profile {
+            let opcode = self.chunk().instrs()[ip].opcode();
+            self.counts[opcode] += 1;
+        }
+        if let (Some(coverage), [_script]) = (&mut self.coverage, &self.frames[..]) {
+            coverage[ip] += 1;
+        }
+
+        if self.trace {
+            if let Err(e) = self.trace_instr(ip) {
+                self.frame_mut().ip += 1;
+                return Some(self.runtime_error(ip, format!("Could not trace: {}", e)));
             }
-            println!("{}", self.chunk.dump_instr(ip));
         }
 
+        self.dispatch(true)
+    }
+
+    /// Execute instructions until the program finishes or fails, returning its result. Otherwise
+    /// returns `None` after the first instruction if `single` is set, or once a collection is due,
+    /// so the caller can collect before the next instruction
+    #[inline(always)]
+    fn dispatch(&mut self, single: bool) -> Option<InterpretResult> {
         macro_rules! binary_op {
+            ($kind:path, $op: tt, $dest:ident, $a:ident, $b:ident) => {
+                match (self.load_number($a), self.load_number($b)) {
+                    (Some(a), Some(b)) => {
+                        self.store($dest, $kind(a $op b));
+                        Ok(())
+                    }
+                    _ => Err("Operands must be numbers".to_string()),
+                }
+            };
+        }
+
+        // Integer operands stay integers, failing on overflow, unless either is a float
+        macro_rules! arith_op {
+            ($checked:ident, $op: tt, $dest:ident, $a:ident, $b:ident) => {
+                match (self.load_int($a), self.load_int($b)) {
+                    (Some(a), Some(b)) => checked_int(a.$checked(b)).map(|v| self.store($dest, v)),
+                    _ => binary_op!(Value::Number, $op, $dest, $a, $b),
+                }
+            };
+        }
+
+        macro_rules! compare_op {
             ($op: tt, $dest:ident, $a:ident, $b:ident) => {
-                {
-                    let a = self.load($a);
-                    let b = self.load($b);
-                    self.store($dest, a $op b);
+                match (self.load_int($a), self.load_int($b)) {
+                    (Some(a), Some(b)) => {
+                        self.store($dest, Value::Bool(a $op b));
+                        Ok(())
+                    }
+                    _ => binary_op!(Value::Bool, $op, $dest, $a, $b),
                 }
             };
         }
 
-        match &self.chunk.instrs()[ip] {
-            &BcInstr::Ret => return Some(InterpretResult::Ok),
-            &BcInstr::Add { dest, a, b } => binary_op!(+, dest, a, b),
-            &BcInstr::Sub { dest, a, b } => binary_op!(-, dest, a, b),
-            &BcInstr::Mul { dest, a, b } => binary_op!(*, dest, a, b),
-            &BcInstr::Div { dest, a, b } => binary_op!(/, dest, a, b),
-            &BcInstr::Neg { dest, a } => self.store(dest, -self.load(a)),
-            &BcInstr::LoadConst { dest, id } => self.store(dest, self.chunk.constant(id)),
+        loop {
+            let frame = self.frame_mut();
+            let ip = frame.ip;
+            frame.ip += 1;
+
+            let result = match self.chunk().instrs()[ip] {
+                BcInstr::Ret { src } => {
+                    self.result = self.load(src);
+                    if self.frames.len() == 1 {
+                        self.frames.pop();
+                        self.spills.clear();
+                        return Some(InterpretResult::Ok);
+                    }
+
+                    // The first register of the callee is the register of the caller holding it
+                    self.store(Register::ret(), self.result);
+                    let callee = self.frames.pop().unwrap();
+                    self.spills.truncate(callee.spills);
+                    Ok(())
+                }
+                BcInstr::Call { callee, arg_count } => self.call(callee, arg_count),
+                BcInstr::DefineGlobal { name, src } => self.define_global(name, src),
+                BcInstr::GetGlobal { dest, name } => self.get_global(dest, name),
+                BcInstr::SetGlobal { name, src } => self.set_global(name, src),
+                BcInstr::Add { dest, a, b } => self.add(dest, self.load(a), self.load(b)),
+                BcInstr::AddConst { dest, a, id } => self
+                    .constant(id as ConstantId)
+                    .and_then(|b| self.add(dest, self.load(a), b)),
+                BcInstr::MulAdd { dest, a, b } => match (self.load_int(a), self.load_int(b)) {
+                    (Some(a), Some(b)) => self.mul_add_ints(dest, a, b),
+                    _ => match (
+                        self.load_number(a),
+                        self.load_number(b),
+                        self.load_number(dest),
+                    ) {
+                        (Some(a), Some(b), Some(c)) => {
+                            self.store(dest, Value::Number(mul_add(a, b, c)));
+                            Ok(())
+                        }
+                        _ => Err("Operands must be numbers".to_string()),
+                    },
+                },
+                BcInstr::Sub { dest, a, b } => arith_op!(checked_sub, -, dest, a, b),
+                BcInstr::Mul { dest, a, b } => arith_op!(checked_mul, *, dest, a, b),
+                // Division always produces a float, even between two integers. A zero divisor is an
+                // error, including `0 / 0`, rather than a silent infinity or NaN
+                BcInstr::Div { dest, a, b } => match (self.load_number(a), self.load_number(b)) {
+                    (Some(_), Some(0.0)) => Err("Division by zero".to_string()),
+                    (Some(a), Some(b)) => {
+                        self.store(dest, Value::Number(a / b));
+                        Ok(())
+                    }
+                    _ => Err("Operands must be numbers".to_string()),
+                },
+                // Modulo follows C's `fmod` (what clox would use): the result takes the sign of the
+                // dividend, so `-7 % 3 == -1`. A zero divisor is an error rather than a silent NaN.
+                BcInstr::Mod { dest, a, b } => match (self.load_int(a), self.load_int(b)) {
+                    (Some(_), Some(0)) => Err("Division by zero".to_string()),
+                    // A remainder is never larger than its operands. `INT_MIN % -1` only overflows
+                    // on the way to 0, which `wrapping_rem` returns
+                    (Some(a), Some(b)) => {
+                        self.store(dest, Value::Int(a.wrapping_rem(b)));
+                        Ok(())
+                    }
+                    _ => match (self.load_number(a), self.load_number(b)) {
+                        (Some(_), Some(0.0)) => Err("Division by zero".to_string()),
+                        (Some(a), Some(b)) => {
+                            self.store(dest, Value::Number(a % b));
+                            Ok(())
+                        }
+                        _ => Err("Operands must be numbers".to_string()),
+                    },
+                },
+                // A real base and exponent giving NaN, like a negative base with a fractional
+                // exponent, is an error rather than a NaN leaking into the program
+                BcInstr::Pow { dest, a, b } => match (self.load_number(a), self.load_number(b)) {
+                    (Some(a), Some(b)) => match powf(a, b) {
+                        n if n.is_nan() && !a.is_nan() && !b.is_nan() => {
+                            Err("Result of exponentiation is not a real number".to_string())
+                        }
+                        n => {
+                            self.store(dest, Value::Number(n));
+                            Ok(())
+                        }
+                    },
+                    _ => Err("Operands must be numbers".to_string()),
+                },
+                BcInstr::BitAnd { dest, a, b } => self.bitwise_op(dest, a, b, |a, b| Ok(a & b)),
+                BcInstr::BitOr { dest, a, b } => self.bitwise_op(dest, a, b, |a, b| Ok(a | b)),
+                BcInstr::BitXor { dest, a, b } => self.bitwise_op(dest, a, b, |a, b| Ok(a ^ b)),
+                BcInstr::Shl { dest, a, b } => self.bitwise_op(dest, a, b, |a, b| {
+                    let shift = shift_amount(b)?;
+                    // Bits shifted out of the integer would be lost
+                    Some(a << shift)
+                        .filter(|shifted| shifted >> shift == a)
+                        .ok_or_else(|| "Integer overflow".to_string())
+                }),
+                BcInstr::Shr { dest, a, b } => {
+                    self.bitwise_op(dest, a, b, |a, b| Ok(a >> shift_amount(b)?))
+                }
+                BcInstr::Lt { dest, a, b } => compare_op!(<, dest, a, b),
+                BcInstr::Le { dest, a, b } => compare_op!(<=, dest, a, b),
+                BcInstr::Gt { dest, a, b } => compare_op!(>, dest, a, b),
+                BcInstr::Ge { dest, a, b } => compare_op!(>=, dest, a, b),
+                // Equality is defined between values of any type, so comparing a number with a
+                // non-number is simply not equal rather than an error
+                BcInstr::Eq { dest, a, b } => {
+                    self.store(dest, Value::Bool(self.load(a) == self.load(b)));
+                    Ok(())
+                }
+                BcInstr::Ne { dest, a, b } => {
+                    self.store(dest, Value::Bool(self.load(a) != self.load(b)));
+                    Ok(())
+                }
+                BcInstr::Neg { dest, a } => match self.load_int(a) {
+                    Some(n) => checked_int(n.checked_neg()).map(|v| self.store(dest, v)),
+                    None => match self.load_number(a) {
+                        Some(n) => {
+                            self.store(dest, Value::Number(-n));
+                            Ok(())
+                        }
+                        None => Err("Operand must be a number".to_string()),
+                    },
+                },
+                BcInstr::Inc { dest } => self.step_by(dest, 1),
+                BcInstr::Dec { dest } => self.step_by(dest, -1),
+                BcInstr::Spill { slot, src } => self.spill(slot, src),
+                BcInstr::Unspill { dest, slot } => self.unspill(dest, slot),
+                BcInstr::Print { src } => {
+                    let v = self.load(src);
+                    writeln!(self.sink, "{}", v).map_err(|e| format!("Could not print: {}", e))
+                }
+                BcInstr::Jump { offset } => self.jump(offset as isize),
+                BcInstr::JumpIfFalse { cond, offset } => {
+                    if self.load(cond).is_falsey() {
+                        self.jump(offset as isize)
+                    } else {
+                        Ok(())
+                    }
+                }
+                BcInstr::Loop { offset } => self.jump(-(offset as isize)),
+                BcInstr::Not { dest, a } => {
+                    self.store(dest, Value::Bool(self.load(a).is_falsey()));
+                    Ok(())
+                }
+                BcInstr::LoadConst { dest, id } => self.load_constant(dest, id as ConstantId),
+                BcInstr::LoadConstWide { dest, hi } => match self.chunk().instrs().get(ip + 1) {
+                    Some(&BcInstr::ExtraArg { lo }) => {
+                        self.frame_mut().ip += 1;
+                        self.load_constant(dest, wide_constant_id(hi, lo))
+                    }
+                    _ => Err("Missing operand of wide instruction".to_string()),
+                },
+                // Operands are consumed by the instruction they belong to, so reaching one is an error
+                BcInstr::ExtraArg { .. } => Err("Unexpected instruction operand".to_string()),
+                BcInstr::Move { dest, src } => {
+                    self.store(dest, self.load(src));
+                    Ok(())
+                }
+                BcInstr::LoadTrue { dest } => {
+                    self.store(dest, Value::Bool(true));
+                    Ok(())
+                }
+                BcInstr::LoadFalse { dest } => {
+                    self.store(dest, Value::Bool(false));
+                    Ok(())
+                }
+                BcInstr::LoadNil { dest } => {
+                    self.store(dest, Value::Nil);
+                    Ok(())
+                }
+            };
+
+            if let Err(message) = result {
+                return Some(self.runtime_error(ip, message));
+            }
+            // Only allocating makes a collection due, which has to wait until between instructions
+            if single || self.gc.needs_collection() {
+                return None;
+            }
         }
+    }
+
+    /// Error raised by the instruction at `ip` of the running function
+    fn runtime_error(&self, ip: usize, message: String) -> InterpretResult {
+        let trace: Vec<String> = self
+            .frames
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(depth, frame)| {
+                // Callers are stopped on the call, the instruction before their `ip`
+                let ip = if depth == 0 { ip } else { frame.ip - 1 };
+                let line = frame.chunk().get_line(ip);
+                match unsafe { frame.function.as_ref() }.name() {
+                    Some(name) => format!("[line {}] in {}()", line, name),
+                    None => format!("[line {}] in script", line),
+                }
+            })
+            .collect();
 
-        None
+        InterpretResult::RuntimeErr(RuntimeError {
+            message,
+            line: self.chunk().get_line(ip),
+            trace: trace.join("\n"),
+        })
+    }
+
+    /// Write the registers of the running function and the instruction at `ip` to `trace_out`
+    fn trace_instr(&mut self, ip: usize) -> Result<(), SinkError> {
+        writeln!(self.trace_out)?;
+        for r in 0..REGISTER_MAX {
+            let v = self.load(Register::new(r as u8));
+            writeln!(self.trace_out, "[{}]", v)?;
+        }
+        let instr = self.chunk().dump_instr(ip);
+        writeln!(self.trace_out, "{}", instr)
     }
 
     fn run(&mut self) -> InterpretResult {
+        // Without anything to do between instructions, go straight from one handler to the next,
+        // only leaving the handlers to collect garbage
+        if !self.is_instrumented() {
+            loop {
+                self.maybe_collect();
+                if let Some(ir) = self.dispatch(false) {
+                    return ir;
+                }
+            }
+        }
+
         loop {
-            match self.step() {
-                Some(ir) => return ir,
-                None => {}
+            let ip = self.frame().ip;
+            let in_script = self.frames.len() == 1;
+            if in_script && self.breakpoints.contains(&ip) && self.paused_at != Some(ip) {
+                self.paused_at = Some(ip);
+                return InterpretResult::BreakpointHit(ip);
+            }
+
+            if let Some(ir) = self.step() {
+                return ir;
             }
         }
     }
@@ -98,41 +1135,1680 @@ impl VM {
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::rc::Rc;
 
-    #[test]
-    fn negate_value() {
-        let mut program = Chunk::new();
+    const RET: BcInstr = BcInstr::Ret {
+        src: Register::ret(),
+    };
+
+    /// Sink that can be handed to the VM while the test keeps a handle to read what was written
+    #[derive(Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl SharedBuf {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    impl Write for SharedBuf {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            self.0.borrow_mut().extend_from_slice(s.as_bytes());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn negate_value() {
+        let mut program = Chunk::new();
 
         let ret = Register::ret();
 
-        let id = program.add_constant(10.11);
-        program.write(BcInstr::LoadConst { dest: ret, id }, 0);
+        let id = program.add_constant(Value::Number(10.11));
+        program.write_load_const(ret, id, 0);
         program.write(BcInstr::Neg { dest: ret, a: ret }, 0);
         program.write(BcInstr::Neg { dest: ret, a: ret }, 0);
-        program.write(BcInstr::Ret, 1);
+        program.write(BcInstr::Ret { src: ret }, 1);
 
         let mut vm = VM::new();
-        vm.load_program(program);
+        vm.load_program(program).unwrap();
 
         // LoadConst
         let result = vm.step();
         assert_eq!(result, None);
-        assert_eq!(vm.load(ret), 10.11);
+        assert_eq!(vm.load(ret), Value::Number(10.11));
 
         let result = vm.step();
         // Neg
         assert_eq!(result, None);
-        assert_eq!(vm.load(ret), -10.11);
+        assert_eq!(vm.load(ret), Value::Number(-10.11));
 
         // Neg
         let result = vm.step();
         assert_eq!(result, None);
-        assert_eq!(vm.load(ret), 10.11);
+        assert_eq!(vm.load(ret), Value::Number(10.11));
 
         // Ret
         let result = vm.step();
         assert_eq!(result, Some(InterpretResult::Ok));
-        assert_eq!(vm.load(ret), 10.11);
+        assert_eq!(vm.result(), Value::Number(10.11));
+    }
+
+    #[test]
+    fn return_any_register() {
+        let src = Register::new(7);
+        let mut program = Chunk::new();
+        program.write(BcInstr::LoadTrue { dest: src }, 0);
+        program.write(
+            BcInstr::LoadNil {
+                dest: Register::ret(),
+            },
+            0,
+        );
+        program.write(BcInstr::Ret { src }, 0);
+
+        let mut vm = VM::new();
+        assert_eq!(vm.interpret(program), InterpretResult::Ok);
+        assert_eq!(vm.result(), Value::Bool(true));
+    }
+
+    #[test]
+    fn arithmetic_requires_numbers() {
+        let mut program = Chunk::new();
+        let (dest, a, b) = (Register::ret(), Register::new(1), Register::new(2));
+        program.write(BcInstr::Sub { dest, a, b }, 0);
+        program.write(RET, 0);
+
+        let mut vm = VM::new();
+        vm.load_program(program).unwrap();
+        vm.store(a, Value::Number(1.0));
+        vm.store(b, Value::Bool(true));
+
+        assert_eq!(
+            vm.step(),
+            Some(runtime_error("Operands must be numbers", 0))
+        );
+    }
+
+    #[test]
+    fn intern_strings() {
+        let mut vm = VM::new();
+        let hello = vm.alloc_string("hello").unwrap();
+        assert_eq!(vm.alloc_string("hello").unwrap(), hello);
+        assert_ne!(vm.alloc_string("world").unwrap(), hello);
+
+        // Constants allocated elsewhere and concatenations resolve to the interned copy
+        let (r0, r1, r2) = (Register::new(0), Register::new(1), Register::new(2));
+        let mut gc = StickyImmix::new();
+        let mut program = Chunk::new();
+        let id = program.add_constant(Value::Obj(ObjString::new(&mut gc, "hello").unwrap().cast()));
+        program.write_load_const(r0, id, 0);
+        let id = program.add_constant(Value::Obj(ObjString::new(&mut gc, "hel").unwrap().cast()));
+        program.write_load_const(r1, id, 0);
+        let id = program.add_constant(Value::Obj(ObjString::new(&mut gc, "lo").unwrap().cast()));
+        program.write_load_const(r2, id, 0);
+        program.write(
+            BcInstr::Add {
+                dest: r1,
+                a: r1,
+                b: r2,
+            },
+            0,
+        );
+        program.write(RET, 0);
+
+        assert_eq!(vm.interpret(program), InterpretResult::Ok);
+        assert_eq!(vm.registers()[..2], [hello, hello]);
+    }
+
+    #[test]
+    fn collect_keeps_registers() {
+        let mut vm = VM::new();
+        let mut program = Chunk::new();
+        program.write(RET, 0);
+        vm.load_program(program).unwrap();
+
+        // The register is the only reference to the string, as interning doesn't keep it alive
+        let kept = vm.alloc_string("kept").unwrap();
+        vm.store(Register::new(1), kept);
+        let garbage = vm.alloc_string("garbage").unwrap();
+        vm.collect_garbage();
+
+        let mut objects = Vec::new();
+        vm.gc
+            .walk_heap(|object| objects.push(object.cast::<ObjHeader>()));
+        let (Value::Obj(kept_obj), Value::Obj(garbage_obj)) = (kept, garbage) else {
+            unreachable!()
+        };
+        assert!(objects.contains(&kept_obj) && !objects.contains(&garbage_obj));
+
+        assert_eq!(vm.alloc_string("kept").unwrap(), kept);
+        vm.alloc_string("garbage").unwrap();
+        assert_eq!(vm.registers()[1].to_string(), "kept");
+        assert_eq!(vm.gc.verify_heap(), Ok(()));
+    }
+
+    #[test]
+    fn collect_keeps_handles() {
+        let mut vm = VM::new();
+        let roots = vm.handle_roots();
+        let Value::Obj(obj) = vm.alloc_string("kept").unwrap() else {
+            unreachable!()
+        };
+        let live = |vm: &VM| {
+            let mut objects = Vec::new();
+            vm.gc
+                .walk_heap(|object| objects.push(object.cast::<ObjHeader>()));
+            objects.contains(&obj)
+        };
+
+        // The handle keeps the string alive while the VM runs, until its scope is dropped
+        {
+            let scope = roots.scope();
+            let kept = scope.handle(obj.cast::<ObjString>());
+            assert_eq!(
+                vm.interpret(crate::compiler::compile("1 + 2").unwrap()),
+                InterpretResult::Ok
+            );
+            vm.collect_garbage();
+            assert!(live(&vm));
+            assert_eq!(unsafe { kept.get().as_ref() }.as_str(), "kept");
+        }
+        vm.collect_garbage();
+        assert!(!live(&vm));
+    }
+
+    #[test]
+    fn add_strings() {
+        let (dest, a, b) = (Register::ret(), Register::new(1), Register::new(2));
+        let add = BcInstr::Add { dest, a, b };
+        assert_eq!(
+            eval_binary(add, Value::Number(1.5), Value::Int(2)),
+            Ok(Value::Number(3.5))
+        );
+
+        let mut vm = VM::new();
+        let (lhs, rhs) = (
+            vm.alloc_string("foo").unwrap(),
+            vm.alloc_string("bar").unwrap(),
+        );
+        let mut program = Chunk::new();
+        program.write(add, 0);
+        program.write(RET, 0);
+        vm.load_program(program).unwrap();
+        vm.store(a, lhs);
+        vm.store(b, rhs);
+        assert_eq!(vm.run(), InterpretResult::Ok);
+        assert_eq!(vm.result().to_string(), "foobar");
+
+        let mixed = [
+            (lhs, Value::Number(1.0)),
+            (Value::Int(1), rhs),
+            (Value::Bool(true), Value::Nil),
+        ];
+        for (lhs, rhs) in mixed {
+            assert_eq!(
+                eval_binary(add, lhs, rhs),
+                Err("Operands must be two numbers or two strings".to_string())
+            );
+        }
+    }
+
+    /// Error raised by the script itself, outside of any function
+    fn runtime_error(message: &str, line: usize) -> InterpretResult {
+        InterpretResult::RuntimeErr(RuntimeError {
+            message: message.to_string(),
+            line,
+            trace: format!("[line {}] in script", line),
+        })
+    }
+
+    fn eval_binary(instr: BcInstr, lhs: Value, rhs: Value) -> Result<Value, String> {
+        let mut program = Chunk::new();
+        program.write(instr, 0);
+        program.write(RET, 0);
+
+        let mut vm = VM::new();
+        vm.load_program(program).unwrap();
+        vm.store(Register::new(1), lhs);
+        vm.store(Register::new(2), rhs);
+        match vm.run() {
+            InterpretResult::Ok => Ok(vm.result()),
+            InterpretResult::RuntimeErr(err) => Err(err.message),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn integer_arithmetic() {
+        let (dest, a, b) = (Register::ret(), Register::new(1), Register::new(2));
+        let add = BcInstr::Add { dest, a, b };
+
+        assert_eq!(
+            eval_binary(add, Value::Int(2), Value::Int(3)),
+            Ok(Value::Int(5))
+        );
+        assert_eq!(
+            eval_binary(BcInstr::Mul { dest, a, b }, Value::Int(-4), Value::Int(3)),
+            Ok(Value::Int(-12))
+        );
+        assert_eq!(
+            eval_binary(BcInstr::Div { dest, a, b }, Value::Int(7), Value::Int(2)),
+            Ok(Value::Number(3.5))
+        );
+        assert_eq!(
+            eval_binary(BcInstr::Mod { dest, a, b }, Value::Int(-7), Value::Int(3)),
+            Ok(Value::Int(-1))
+        );
+        assert_eq!(
+            eval_binary(BcInstr::Mod { dest, a, b }, Value::Int(7), Value::Int(0)),
+            Err("Division by zero".to_string())
+        );
+        assert_eq!(
+            eval_binary(BcInstr::Lt { dest, a, b }, Value::Int(1), Value::Int(2)),
+            Ok(Value::Bool(true))
+        );
+    }
+
+    #[test]
+    fn fused_multiply_add() {
+        let (ret, a, b) = (Register::ret(), Register::new(1), Register::new(2));
+        let run = |fused: bool, x: Value, y: Value, z: Value| {
+            let mut program = Chunk::new();
+            if fused {
+                program.write(BcInstr::MulAdd { dest: ret, a, b }, 0);
+            } else {
+                program.write(BcInstr::Mul { dest: a, a, b }, 0);
+                program.write(
+                    BcInstr::Add {
+                        dest: ret,
+                        a: ret,
+                        b: a,
+                    },
+                    0,
+                );
+            }
+            program.write(RET, 0);
+
+            let mut vm = VM::new();
+            vm.load_program(program).unwrap();
+            vm.store(a, x);
+            vm.store(b, y);
+            vm.store(ret, z);
+            assert_eq!(vm.run(), InterpretResult::Ok);
+            vm.result()
+        };
+
+        // 0.1 * 10 rounds to exactly 1 on its own, while the fused product keeps the error of
+        // 0.1. The difference is intended
+        let (x, y, z) = (Value::Number(0.1), Value::Number(10.0), Value::Number(-1.0));
+        assert_eq!(run(false, x, y, z), Value::Number(0.0));
+        assert_eq!(run(true, x, y, z), Value::Number(5.551115123125783e-17));
+
+        // Integer products are exact, so both forms agree
+        let (x, y) = (Value::Int(6), Value::Int(7));
+        for z in [Value::Int(-2), Value::Number(0.5)] {
+            assert_eq!(run(true, x, y, z), run(false, x, y, z));
+        }
+        assert_eq!(run(true, x, y, Value::Int(-2)), Value::Int(40));
+
+        // Past 2^53 an integer product is rounded when promoted, so adding it to a float rounds
+        // twice unless fused. NaN-boxed integers are too narrow for such products
+        #[cfg(not(feature = "nan-boxing"))]
+        {
+            let big = Value::Int((1 << 27) + 1);
+            let product = (1_i64 << 54) + (1 << 28) + 1;
+            let z = Value::Number(1.5);
+            assert_eq!(run(false, big, big, z), Value::Number(product as f64));
+            assert_eq!(run(true, big, big, z), Value::Number((product + 3) as f64));
+        }
+    }
+
+    #[test]
+    fn exponentiation() {
+        let (dest, a, b) = (Register::ret(), Register::new(1), Register::new(2));
+        let pow = BcInstr::Pow { dest, a, b };
+        assert_eq!(
+            eval_binary(pow, Value::Number(2.0), Value::Number(10.0)),
+            Ok(Value::Number(1024.0))
+        );
+        assert_eq!(
+            eval_binary(pow, Value::Int(2), Value::Int(-1)),
+            Ok(Value::Number(0.5))
+        );
+        // Like `f64::powf`, anything to the power 0 is 1, including 0 and NaN
+        assert_eq!(
+            eval_binary(pow, Value::Number(0.0), Value::Number(0.0)),
+            Ok(Value::Number(1.0))
+        );
+        assert_eq!(
+            eval_binary(pow, Value::Number(f64::NAN), Value::Int(0)),
+            Ok(Value::Number(1.0))
+        );
+
+        assert_eq!(
+            eval_binary(pow, Value::Number(-8.0), Value::Number(1.0 / 3.0)),
+            Err("Result of exponentiation is not a real number".to_string())
+        );
+        // NaN operands are not the fault of the exponentiation, so they carry on as in `Add`
+        let nan = eval_binary(pow, Value::Number(f64::NAN), Value::Number(2.0));
+        assert!(nan.unwrap().as_number().unwrap().is_nan());
+        assert_eq!(
+            eval_binary(pow, Value::Bool(true), Value::Number(2.0)),
+            Err("Operands must be numbers".to_string())
+        );
+    }
+
+    #[test]
+    fn bitwise_ops() {
+        let (dest, a, b) = (Register::ret(), Register::new(1), Register::new(2));
+        let eval = |instr, lhs, rhs| eval_binary(instr, Value::Int(lhs), Value::Int(rhs));
+
+        assert_eq!(
+            eval(BcInstr::BitAnd { dest, a, b }, 0b1100, 0b1010),
+            Ok(Value::Int(0b1000))
+        );
+        assert_eq!(
+            eval(BcInstr::BitOr { dest, a, b }, 0b1100, 0b1010),
+            Ok(Value::Int(0b1110))
+        );
+        assert_eq!(
+            eval(BcInstr::BitXor { dest, a, b }, 0b1100, 0b1010),
+            Ok(Value::Int(0b0110))
+        );
+        assert_eq!(
+            eval(BcInstr::BitAnd { dest, a, b }, -1, 6),
+            Ok(Value::Int(6))
+        );
+
+        let shl = BcInstr::Shl { dest, a, b };
+        let shr = BcInstr::Shr { dest, a, b };
+        assert_eq!(eval(shl, 3, 4), Ok(Value::Int(48)));
+        assert_eq!(eval(shl, -1, 0), Ok(Value::Int(-1)));
+        assert_eq!(eval(shr, 48, 4), Ok(Value::Int(3)));
+        // Right shifts keep the sign
+        assert_eq!(eval(shr, -16, 2), Ok(Value::Int(-4)));
+        assert_eq!(eval(shr, -1, 63), Ok(Value::Int(-1)));
+
+        // Shift amounts are not masked
+        let out_of_range = Err("Shift amount out of range".to_string());
+        assert_eq!(eval(shl, 1, 64), out_of_range);
+        assert_eq!(eval(shr, 1, -1), out_of_range);
+        assert_eq!(eval(shl, INT_MAX, 1), Err("Integer overflow".to_string()));
+    }
+
+    #[test]
+    fn bitwise_ops_need_integers() {
+        let (dest, a, b) = (Register::ret(), Register::new(1), Register::new(2));
+        let not_ints = Err("Operands must be integers".to_string());
+        for instr in [
+            BcInstr::BitAnd { dest, a, b },
+            BcInstr::BitOr { dest, a, b },
+            BcInstr::BitXor { dest, a, b },
+            BcInstr::Shl { dest, a, b },
+            BcInstr::Shr { dest, a, b },
+        ] {
+            // Numbers are rejected even when they hold a whole value
+            assert_eq!(
+                eval_binary(instr, Value::Number(4.0), Value::Int(1)),
+                not_ints
+            );
+            assert_eq!(
+                eval_binary(instr, Value::Int(4), Value::Number(1.0)),
+                not_ints
+            );
+            assert_eq!(eval_binary(instr, Value::Int(4), Value::Nil), not_ints);
+        }
+    }
+
+    #[test]
+    fn integer_overflow() {
+        let (dest, a, b) = (Register::ret(), Register::new(1), Register::new(2));
+        assert_eq!(
+            eval_binary(
+                BcInstr::Add { dest, a, b },
+                Value::Int(INT_MAX),
+                Value::Int(1)
+            ),
+            Err("Integer overflow".to_string())
+        );
+        assert_eq!(
+            eval_binary(
+                BcInstr::Sub { dest, a, b },
+                Value::Int(INT_MIN),
+                Value::Int(1)
+            ),
+            Err("Integer overflow".to_string())
+        );
+        assert_eq!(
+            eval_binary(
+                BcInstr::Mul { dest, a, b },
+                Value::Int(INT_MAX),
+                Value::Int(2)
+            ),
+            Err("Integer overflow".to_string())
+        );
+
+        // Only `+`, `-` and `*` overflow, as the remainder is 0
+        assert_eq!(
+            eval_binary(
+                BcInstr::Mod { dest, a, b },
+                Value::Int(INT_MIN),
+                Value::Int(-1)
+            ),
+            Ok(Value::Int(0))
+        );
+    }
+
+    #[test]
+    fn mixed_int_float_arithmetic() {
+        let (dest, a, b) = (Register::ret(), Register::new(1), Register::new(2));
+        assert_eq!(
+            eval_binary(
+                BcInstr::Add { dest, a, b },
+                Value::Int(1),
+                Value::Number(0.5)
+            ),
+            Ok(Value::Number(1.5))
+        );
+        assert_eq!(
+            eval_binary(
+                BcInstr::Sub { dest, a, b },
+                Value::Number(0.5),
+                Value::Int(2)
+            ),
+            Ok(Value::Number(-1.5))
+        );
+        assert_eq!(
+            eval_binary(
+                BcInstr::Ge { dest, a, b },
+                Value::Number(2.5),
+                Value::Int(2)
+            ),
+            Ok(Value::Bool(true))
+        );
+    }
+
+    #[test]
+    fn divide_by_zero() {
+        let (dest, a, b) = (Register::ret(), Register::new(1), Register::new(2));
+        let divide = |lhs: f64| {
+            let mut program = Chunk::new();
+            let id = program.add_constant(Value::Number(lhs));
+            program.write_load_const(a, id, 1);
+            let id = program.add_constant(Value::Number(0.0));
+            program.write_load_const(b, id, 1);
+            program.write(BcInstr::Div { dest, a, b }, 2);
+            program.write(RET, 3);
+
+            VM::new().interpret(program)
+        };
+
+        assert_eq!(divide(1.0), runtime_error("Division by zero", 2));
+        assert_eq!(divide(0.0), runtime_error("Division by zero", 2));
+        match divide(1.0) {
+            InterpretResult::RuntimeErr(err) => {
+                assert_eq!(err.to_string(), "Division by zero\n[line 2] in script")
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn registers_start_nil() {
+        let vm = VM::new();
+        for r in 0..REGISTER_MAX {
+            assert_eq!(vm.load(Register::new(r as u8)), Value::Nil);
+        }
+    }
+
+    #[test]
+    fn call_function() {
+        let mut vm = VM::new();
+
+        let mut body = Chunk::new();
+        let id = body.add_constant(Value::Number(42.0));
+        body.write_load_const(Register::new(1), id, 1);
+        body.write(
+            BcInstr::Ret {
+                src: Register::new(1),
+            },
+            1,
+        );
+        let answer = vm.alloc_function("answer", 0, body).unwrap();
+
+        let (callee, r0) = (Register::new(2), Register::ret());
+        let mut program = Chunk::new();
+        let id = program.add_constant(Value::Number(1.0));
+        program.write_load_const(r0, id, 2);
+        let id = program.add_constant(answer);
+        program.write_load_const(callee, id, 2);
+        program.write(
+            BcInstr::Call {
+                callee,
+                arg_count: 0,
+            },
+            2,
+        );
+        program.write(
+            BcInstr::Add {
+                dest: r0,
+                a: r0,
+                b: callee,
+            },
+            3,
+        );
+        program.write(RET, 3);
+
+        assert_eq!(vm.interpret(program), InterpretResult::Ok);
+        assert_eq!(vm.result(), Value::Number(43.0));
+    }
+
+    #[test]
+    fn call_clears_stale_registers() {
+        let mut vm = VM::new();
+        let (r14, r15) = (Register::new(14), Register::new(15));
+
+        // `leak` leaves a string in its last register, above the window of the script
+        let mut leak = Chunk::new();
+        let id = leak.add_string("stale");
+        leak.write_load_const(r15, id, 1);
+        leak.write(RET, 1);
+        let leak = vm.alloc_function("leak", 0, leak).unwrap();
+
+        // `peek` starts one register higher, so its `%r14` is the register `leak` wrote
+        let mut peek = Chunk::new();
+        peek.write(BcInstr::Ret { src: r14 }, 2);
+        let peek = vm.alloc_function("peek", 0, peek).unwrap();
+
+        let (r1, r2) = (Register::new(1), Register::new(2));
+        let mut program = Chunk::new();
+        for (callee, function) in [(r1, leak), (r2, peek)] {
+            let id = program.add_constant(function);
+            program.write_load_const(callee, id, 3);
+            program.write(
+                BcInstr::Call {
+                    callee,
+                    arg_count: 0,
+                },
+                3,
+            );
+        }
+        program.write(BcInstr::Ret { src: r2 }, 4);
+
+        // Collect before every instruction, which frees the string once `leak` returned
+        vm.load_program(program).unwrap();
+        let result = loop {
+            vm.collect_garbage();
+            if let Some(result) = vm.step_public() {
+                break result;
+            }
+        };
+
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(vm.result(), Value::Nil);
+        assert_eq!(vm.gc.verify_heap(), Ok(()));
+    }
+
+    #[test]
+    fn call_with_arguments() {
+        let mut vm = VM::new();
+
+        let (r0, r1, r2) = (Register::new(0), Register::new(1), Register::new(2));
+        let mut body = Chunk::new();
+        body.write(
+            BcInstr::Sub {
+                dest: r0,
+                a: r1,
+                b: r2,
+            },
+            1,
+        );
+        body.write(RET, 1);
+        let sub = vm.alloc_function("sub", 2, body).unwrap();
+
+        let (callee, a, b) = (Register::new(3), Register::new(4), Register::new(5));
+        let call = |vm: &mut VM, arg_count| {
+            let mut program = Chunk::new();
+            let id = program.add_constant(sub);
+            program.write_load_const(callee, id, 2);
+            let id = program.add_constant(Value::Number(10.0));
+            program.write_load_const(a, id, 2);
+            let id = program.add_constant(Value::Number(4.0));
+            program.write_load_const(b, id, 2);
+            program.write(BcInstr::Call { callee, arg_count }, 3);
+            program.write(BcInstr::Ret { src: callee }, 4);
+            vm.interpret(program)
+        };
+
+        assert_eq!(call(&mut vm, 2), InterpretResult::Ok);
+        assert_eq!(vm.result(), Value::Number(6.0));
+        assert_eq!(
+            call(&mut vm, 1),
+            runtime_error("Expected 2 arguments but got 1", 3)
+        );
+    }
+
+    #[test]
+    fn allocate_on_heap() {
+        let (r1, r2, ret) = (Register::new(1), Register::new(2), Register::ret());
+        let mut program = Chunk::new();
+        let id = program.add_string("ab");
+        program.write_load_const(r1, id, 0);
+        for (dest, a) in [(r2, r1), (ret, r2)] {
+            program.write(BcInstr::Add { dest, a, b: a }, 0);
+        }
+        program.write(RET, 0);
+
+        let mut vm = VM::new();
+        vm.load_program(program).unwrap();
+        let before = vm.heap_stats().bytes_allocated;
+        assert_eq!(vm.run(), InterpretResult::Ok);
+        assert_eq!(vm.result().to_string(), "abababab");
+
+        // The string constant and both concatenations are objects of the collector
+        let size = |v: Value| match v {
+            Value::Obj(obj) => unsafe { ObjHeader::object(obj).as_ref() }.size(),
+            _ => panic!("{:?} is not an object", v),
+        };
+        let strings = size(vm.load(r1)) + size(vm.load(r2)) + size(vm.load(ret));
+        assert_eq!(vm.heap_stats().bytes_allocated, before + strings);
+        assert_eq!(vm.gc.verify_heap(), Ok(()));
+    }
+
+    #[test]
+    fn collect_at_safepoints() {
+        let (r1, r2, ret) = (Register::new(1), Register::new(2), Register::ret());
+        let mut program = Chunk::new();
+        let id = program.add_string("ab");
+        program.write_load_const(r1, id, 0);
+        program.write(
+            BcInstr::Add {
+                dest: r2,
+                a: r1,
+                b: r1,
+            },
+            0,
+        );
+        program.write(
+            BcInstr::Add {
+                dest: ret,
+                a: r2,
+                b: r1,
+            },
+            0,
+        );
+        program.write(RET, 0);
+
+        // Fill the heap with garbage before every instruction, so a collection runs at each
+        // safepoint while the strings built so far are only held in registers
+        let mut vm = VM::new();
+        vm.load_program(program).unwrap();
+        let mut garbage = 0;
+        let result = loop {
+            while !vm.gc.needs_collection() {
+                vm.alloc_string(&format!("{:04096}", garbage)).unwrap();
+                garbage += 1;
+            }
+            if let Some(result) = vm.step_public() {
+                break result;
+            }
+        };
+
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(vm.gc.stats().collections_run, 4);
+        assert_eq!(vm.gc.verify_heap(), Ok(()));
+        assert_eq!(vm.result().to_string(), "ababab");
+        // The garbage strings were dropped from the intern table along with the heap
+        assert!(vm.strings.len() <= 3);
+    }
+
+    #[test]
+    fn trace_calls() {
+        let mut vm = VM::new();
+        let r1 = Register::new(1);
+
+        // `inner` fails negating nil, called from `outer`, called from the script
+        let mut body = Chunk::new();
+        body.write(BcInstr::LoadNil { dest: r1 }, 20);
+        body.write(BcInstr::Neg { dest: r1, a: r1 }, 21);
+        body.write(RET, 22);
+        let inner = vm.alloc_function("inner", 0, body).unwrap();
+
+        let mut body = Chunk::new();
+        let id = body.add_constant(inner);
+        body.write_load_const(r1, id, 10);
+        body.write(
+            BcInstr::Call {
+                callee: r1,
+                arg_count: 0,
+            },
+            11,
+        );
+        body.write(RET, 12);
+        let outer = vm.alloc_function("outer", 0, body).unwrap();
+
+        let mut program = Chunk::new();
+        let id = program.add_constant(outer);
+        program.write_load_const(r1, id, 2);
+        program.write(
+            BcInstr::Call {
+                callee: r1,
+                arg_count: 0,
+            },
+            3,
+        );
+        program.write(RET, 4);
+
+        let InterpretResult::RuntimeErr(error) = vm.interpret(program) else {
+            panic!("Calling inner should fail");
+        };
+        assert_eq!(error.line, 21);
+        assert_eq!(
+            error.trace,
+            "[line 21] in inner()\n[line 11] in outer()\n[line 3] in script"
+        );
+        assert_eq!(
+            error.to_string(),
+            format!("Operand must be a number\n{}", error.trace)
+        );
+    }
+
+    #[test]
+    fn call_native() {
+        let mut vm = VM::new();
+        vm.define_native("clock", 0, |_| Ok(Value::Number(1.5)))
+            .unwrap();
+        vm.define_native("max", 2, |args| match (args[0], args[1]) {
+            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a.max(b))),
+            _ => Err("max() takes two numbers".to_string()),
+        })
+        .unwrap();
+
+        let (callee, a, b) = (Register::new(1), Register::new(2), Register::new(3));
+        let call = |vm: &mut VM, name: &str, args: &[Value]| {
+            let mut program = Chunk::new();
+            let name = program.add_constant(vm.alloc_string(name).unwrap()) as u16;
+            program.write(BcInstr::GetGlobal { dest: callee, name }, 1);
+            for (r, &arg) in [a, b].iter().zip(args) {
+                let id = program.add_constant(arg);
+                program.write_load_const(*r, id, 1);
+            }
+            let arg_count = args.len() as u8;
+            program.write(BcInstr::Call { callee, arg_count }, 2);
+            program.write(BcInstr::Ret { src: callee }, 2);
+            vm.interpret(program)
+        };
+
+        assert_eq!(call(&mut vm, "clock", &[]), InterpretResult::Ok);
+        assert_eq!(vm.result(), Value::Number(1.5));
+
+        let args = [Value::Number(2.0), Value::Number(3.0)];
+        assert_eq!(call(&mut vm, "max", &args), InterpretResult::Ok);
+        assert_eq!(vm.result(), Value::Number(3.0));
+
+        assert_eq!(
+            call(&mut vm, "clock", &args),
+            runtime_error("Expected 0 arguments but got 2", 2)
+        );
+
+        let args = [Value::Number(2.0), vm.alloc_string("3").unwrap()];
+        assert_eq!(
+            call(&mut vm, "max", &args),
+            runtime_error("max() takes two numbers", 2)
+        );
+    }
+
+    #[test]
+    fn call_too_many_arguments() {
+        let mut vm = VM::new();
+        vm.define_native("ignore", u8::MAX, |_| Ok(Value::Nil))
+            .unwrap();
+
+        let callee = Register::new(1);
+        let mut program = Chunk::new();
+        let name = program.add_constant(vm.alloc_string("ignore").unwrap()) as u16;
+        program.write(BcInstr::GetGlobal { dest: callee, name }, 1);
+        let arg_count = u8::MAX;
+        program.write(BcInstr::Call { callee, arg_count }, 2);
+        program.write(BcInstr::Ret { src: callee }, 2);
+
+        assert_eq!(
+            vm.interpret(program),
+            runtime_error("Too many arguments", 2)
+        );
+    }
+
+    #[test]
+    fn call_non_function() {
+        let mut program = Chunk::new();
+        program.write(
+            BcInstr::LoadTrue {
+                dest: Register::ret(),
+            },
+            0,
+        );
+        program.write(
+            BcInstr::Call {
+                callee: Register::ret(),
+                arg_count: 0,
+            },
+            1,
+        );
+        program.write(RET, 1);
+
+        assert_eq!(
+            VM::new().interpret(program),
+            runtime_error("Can only call functions", 1)
+        );
+    }
+
+    #[test]
+    fn global_variables() {
+        let mut vm = VM::new();
+        let (r0, r1) = (Register::new(0), Register::new(1));
+
+        let mut program = Chunk::new();
+        let x = vm.alloc_string("x").unwrap();
+        let name = program.add_constant(x) as u16;
+        let id = program.add_constant(Value::Number(1.0));
+        program.write_load_const(r1, id, 1);
+        program.write(BcInstr::DefineGlobal { name, src: r1 }, 1);
+        program.write(BcInstr::GetGlobal { dest: r0, name }, 2);
+        program.write(
+            BcInstr::Add {
+                dest: r1,
+                a: r0,
+                b: r0,
+            },
+            3,
+        );
+        program.write(BcInstr::SetGlobal { name, src: r1 }, 3);
+        program.write(RET, 4);
+        assert_eq!(vm.interpret(program), InterpretResult::Ok);
+        assert_eq!(vm.result(), Value::Number(1.0));
+
+        // Globals outlive the program that defined them, and can be redefined
+        let mut program = Chunk::new();
+        let name = program.add_constant(x) as u16;
+        program.write(BcInstr::GetGlobal { dest: r0, name }, 1);
+        program.write(BcInstr::LoadTrue { dest: r1 }, 2);
+        program.write(BcInstr::DefineGlobal { name, src: r1 }, 2);
+        program.write(BcInstr::GetGlobal { dest: r1, name }, 3);
+        program.write(
+            BcInstr::Eq {
+                dest: r0,
+                a: r0,
+                b: r1,
+            },
+            3,
+        );
+        program.write(RET, 4);
+        assert_eq!(vm.interpret(program), InterpretResult::Ok);
+        assert_eq!(vm.result(), Value::Bool(false));
+        assert_eq!(vm.load(r1), Value::Bool(true));
+    }
+
+    #[test]
+    fn undefined_global() {
+        let mut vm = VM::new();
+        let r0 = Register::ret();
+
+        let mut program = Chunk::new();
+        let name = program.add_constant(vm.alloc_string("y").unwrap()) as u16;
+        program.write(BcInstr::GetGlobal { dest: r0, name }, 7);
+        program.write(RET, 7);
+        assert_eq!(
+            vm.interpret(program),
+            runtime_error("Undefined variable 'y'", 7)
+        );
+
+        // Assignment doesn't define the variable
+        let mut program = Chunk::new();
+        let name = program.add_constant(vm.alloc_string("y").unwrap()) as u16;
+        program.write(BcInstr::LoadNil { dest: r0 }, 8);
+        program.write(BcInstr::SetGlobal { name, src: r0 }, 9);
+        program.write(RET, 9);
+        assert_eq!(
+            vm.interpret(program),
+            runtime_error("Undefined variable 'y'", 9)
+        );
+    }
+
+    #[test]
+    fn load_immediates() {
+        let (r0, r1, r2) = (Register::new(0), Register::new(1), Register::new(2));
+        let mut program = Chunk::new();
+        program.write(BcInstr::LoadTrue { dest: r0 }, 0);
+        program.write(BcInstr::LoadFalse { dest: r1 }, 0);
+        program.write(BcInstr::LoadNil { dest: r2 }, 0);
+        program.write(RET, 0);
+
+        let mut vm = VM::new();
+        assert_eq!(vm.interpret(program), InterpretResult::Ok);
+        assert_eq!(vm.load(r0), Value::Bool(true));
+        assert_eq!(vm.load(r1), Value::Bool(false));
+        assert_eq!(vm.load(r2), Value::Nil);
+    }
+
+    #[test]
+    fn load_wide_constant() {
+        let mut program = Chunk::new();
+        let mut last = 0;
+        for i in 0..70_000 {
+            last = program.add_constant(Value::Number(i as f64));
+        }
+        assert!(last > u16::MAX as ConstantId);
+
+        program.write_load_const(Register::ret(), last, 0);
+        program.write(RET, 0);
+        assert!(matches!(program.instrs()[0], BcInstr::LoadConstWide { .. }));
+
+        let mut vm = VM::new();
+        assert_eq!(vm.interpret(program), InterpretResult::Ok);
+        assert_eq!(vm.result(), Value::Number(69_999.0));
+    }
+
+    #[test]
+    fn load_missing_constant() {
+        let mut program = Chunk::new();
+        program.write(
+            BcInstr::LoadConst {
+                dest: Register::ret(),
+                id: 3,
+            },
+            0,
+        );
+        program.write(RET, 0);
+
+        let mut vm = VM::new();
+        assert_eq!(
+            vm.interpret(program),
+            runtime_error("Undefined constant 0x3", 0)
+        );
+    }
+
+    #[test]
+    fn move_value() {
+        let (dest, src) = (Register::ret(), Register::new(5));
+        let mut program = Chunk::new();
+        let id = program.add_constant(Value::Number(42.0));
+        program.write_load_const(src, id, 0);
+        program.write(BcInstr::Move { dest, src }, 0);
+        program.write(RET, 0);
+
+        let mut vm = VM::new();
+        assert_eq!(vm.interpret(program), InterpretResult::Ok);
+        assert_eq!(vm.load(dest), Value::Number(42.0));
+        assert_eq!(vm.load(src), Value::Number(42.0));
+    }
+
+    #[test]
+    fn print_to_sink() {
+        let r = Register::new(1);
+        let mut program = Chunk::new();
+        let id = program.add_constant(Value::Number(2.5));
+        program.write_load_const(r, id, 0);
+        program.write(BcInstr::Print { src: r }, 0);
+        program.write(BcInstr::LoadTrue { dest: r }, 1);
+        program.write(BcInstr::Print { src: r }, 1);
+        program.write(BcInstr::LoadNil { dest: r }, 2);
+        program.write(BcInstr::Print { src: r }, 2);
+        program.write(RET, 3);
+
+        let out = SharedBuf::default();
+        let mut vm = VM::with_sink(Box::new(out.clone()));
+        assert_eq!(vm.interpret(program), InterpretResult::Ok);
+        assert_eq!(out.contents(), "2.5\ntrue\nnil\n");
+    }
+
+    #[test]
+    #[cfg(not(feature = "std"))]
+    fn interpret_without_std() {
+        let mut vm = VM::new();
+        let chunk = crate::compiler::compile("-(1 + 2) * 3").unwrap();
+        assert_eq!(vm.interpret(chunk), InterpretResult::Ok);
+        assert_eq!(vm.result().to_string(), "-9");
+
+        // Printed values go to the `core::fmt::Write` sink
+        let r = Register::new(1);
+        let mut program = Chunk::new();
+        program.write(BcInstr::LoadTrue { dest: r }, 0);
+        program.write(BcInstr::Print { src: r }, 0);
+        program.write(RET, 0);
+        let out = SharedBuf::default();
+        let mut vm = VM::with_sink(Box::new(out.clone()));
+        assert_eq!(vm.interpret(program), InterpretResult::Ok);
+        assert_eq!(out.contents(), "true\n");
+    }
+
+    #[test]
+    fn print_string() {
+        let out = SharedBuf::default();
+        let mut vm = VM::with_sink(Box::new(out.clone()));
+
+        let mut program = Chunk::new();
+        let hello = vm.alloc_string("hello, world").unwrap();
+        let id = program.add_constant(hello);
+        assert_eq!(program.add_constant(hello), id);
+        program.write_load_const(Register::ret(), id, 0);
+        program.write(
+            BcInstr::Print {
+                src: Register::ret(),
+            },
+            0,
+        );
+        program.write(RET, 0);
+
+        assert_eq!(vm.interpret(program), InterpretResult::Ok);
+        assert_eq!(vm.result(), hello);
+        assert_eq!(out.contents(), "hello, world\n");
+    }
+
+    #[test]
+    fn increment_register() {
+        let (r1, ret) = (Register::new(1), Register::ret());
+        let mut program = Chunk::new();
+        let id = program.add_constant(Value::Number(0.5));
+        program.write_load_const(ret, id, 0);
+        for _ in 0..5 {
+            program.write(BcInstr::Inc { dest: ret }, 0);
+        }
+        program.write(BcInstr::Dec { dest: ret }, 0);
+        program.write(RET, 0);
+        let mut vm = VM::new();
+        assert_eq!(vm.interpret(program), InterpretResult::Ok);
+        assert_eq!(vm.result(), Value::Number(4.5));
+
+        // Integers stay integers, as in `Add` and `Sub`
+        let step = |vm: &mut VM, n: i64, instr: BcInstr| {
+            let mut program = Chunk::new();
+            let id = program.add_constant(Value::Int(n));
+            program.write_load_const(ret, id, 0);
+            program.write(instr, 1);
+            program.write(RET, 1);
+            vm.interpret(program)
+        };
+        assert_eq!(
+            step(&mut vm, 41, BcInstr::Inc { dest: ret }),
+            InterpretResult::Ok
+        );
+        assert_eq!(vm.result(), Value::Int(42));
+        assert_eq!(
+            step(&mut vm, 41, BcInstr::Dec { dest: ret }),
+            InterpretResult::Ok
+        );
+        assert_eq!(vm.result(), Value::Int(40));
+        assert_eq!(
+            step(&mut vm, INT_MAX, BcInstr::Inc { dest: ret }),
+            runtime_error("Integer overflow", 1)
+        );
+        assert_eq!(
+            step(&mut vm, INT_MIN, BcInstr::Dec { dest: ret }),
+            runtime_error("Integer overflow", 1)
+        );
+
+        let mut program = Chunk::new();
+        program.write(BcInstr::LoadTrue { dest: r1 }, 0);
+        program.write(BcInstr::Inc { dest: r1 }, 1);
+        program.write(RET, 1);
+        assert_eq!(
+            vm.interpret(program),
+            runtime_error("Operand must be a number", 1)
+        );
+    }
+
+    #[test]
+    fn load_string_constants() {
+        let (r1, ret) = (Register::new(1), Register::ret());
+        let mut program = Chunk::new();
+        let name = program.add_string("greeting") as u16;
+        let id = program.add_string("hello");
+        program.write_load_const(r1, id, 0);
+        program.write(BcInstr::DefineGlobal { name, src: r1 }, 0);
+        program.write(BcInstr::GetGlobal { dest: ret, name }, 0);
+        program.write(RET, 0);
+
+        // Loads of the string section are interned, so they share the object of equal strings
+        let mut vm = VM::new();
+        let hello = vm.alloc_string("hello").unwrap();
+        assert_eq!(vm.interpret(program), InterpretResult::Ok);
+        assert_eq!(vm.result(), hello);
+    }
+
+    #[test]
+    fn trace_to_writer() {
+        let (out, trace) = (SharedBuf::default(), SharedBuf::default());
+        let mut vm = VM::with_sink(Box::new(out.clone()));
+        vm.set_trace_out(Box::new(trace.clone()));
+
+        let mut program = Chunk::new();
+        program.write(
+            BcInstr::LoadTrue {
+                dest: Register::ret(),
+            },
+            0,
+        );
+        program.write(
+            BcInstr::Print {
+                src: Register::ret(),
+            },
+            0,
+        );
+        program.write(RET, 0);
+
+        vm.set_trace(true);
+        assert_eq!(vm.interpret(program), InterpretResult::Ok);
+
+        // Printed values still go to the sink
+        assert_eq!(out.contents(), "true\n");
+        let nil_registers = "[nil]\n".repeat(REGISTER_MAX - 1);
+        assert_eq!(
+            trace.contents(),
+            format!(
+                "\n[nil]\n{0}0x0 LOAD %r0 <= true\n\
+                 \n[true]\n{0}0x1 PRINT %r0\n\
+                 \n[true]\n{0}0x2 RET %r0\n",
+                nil_registers
+            )
+        );
+
+        // Nothing is written once tracing is turned off
+        let mut program = Chunk::new();
+        program.write(RET, 0);
+        vm.set_trace(false);
+        let len = trace.contents().len();
+        assert_eq!(vm.interpret(program), InterpretResult::Ok);
+        assert_eq!(trace.contents().len(), len);
+    }
+
+    #[test]
+    fn not_value() {
+        let (dest, a) = (Register::ret(), Register::new(1));
+        let not = |v: Value| {
+            let mut program = Chunk::new();
+            program.write(BcInstr::Not { dest, a }, 0);
+            program.write(RET, 0);
+
+            let mut vm = VM::new();
+            vm.load_program(program).unwrap();
+            vm.store(a, v);
+            assert_eq!(vm.run(), InterpretResult::Ok);
+            vm.load(dest)
+        };
+
+        assert_eq!(not(Value::Nil), Value::Bool(true));
+        assert_eq!(not(Value::Bool(false)), Value::Bool(true));
+        assert_eq!(not(Value::Bool(true)), Value::Bool(false));
+        assert_eq!(not(Value::Number(0.0)), Value::Bool(false));
+    }
+
+    #[test]
+    fn modulo() {
+        let (dest, a, b) = (Register::ret(), Register::new(1), Register::new(2));
+        let modulo = |lhs: f64, rhs: f64| {
+            let mut program = Chunk::new();
+            program.write(BcInstr::Mod { dest, a, b }, 0);
+            program.write(RET, 0);
+
+            let mut vm = VM::new();
+            vm.load_program(program).unwrap();
+            vm.store(a, Value::Number(lhs));
+            vm.store(b, Value::Number(rhs));
+            match vm.run() {
+                InterpretResult::Ok => vm.load(dest).as_number(),
+                _ => None,
+            }
+        };
+
+        assert_eq!(modulo(7.0, 3.0), Some(1.0));
+        assert_eq!(modulo(-7.0, 3.0), Some(-1.0));
+        assert_eq!(modulo(7.0, -3.0), Some(1.0));
+        assert_eq!(modulo(5.5, 2.0), Some(1.5));
+        assert_eq!(modulo(5.0, 0.0), None);
+        assert_eq!(modulo(5.0, -0.0), None);
+    }
+
+    #[test]
+    fn loop_to_zero() {
+        let mut vm = VM::new();
+        assert_eq!(vm.interpret(countdown(3.0)), InterpretResult::Ok);
+        assert_eq!(vm.load(Register::ret()), Value::Number(0.0));
+    }
+
+    #[test]
+    fn profile_loop() {
+        let mut vm = VM::new();
+        vm.set_profile(true);
+        assert_eq!(vm.interpret(countdown(3.0)), InterpretResult::Ok);
+        assert_eq!(
+            vm.profile_report(),
+            [
+                ("JumpIfFalse", 4),
+                ("Gt", 4),
+                ("Sub", 3),
+                ("LoadConst", 3),
+                ("Loop", 3),
+                ("Ret", 1),
+            ]
+        );
+
+        // Nothing is counted once profiling is turned off
+        vm.set_profile(false);
+        assert_eq!(vm.interpret(countdown(3.0)), InterpretResult::Ok);
+        assert_eq!(vm.profile_report()[0], ("JumpIfFalse", 4));
+    }
+
+    #[test]
+    fn cover_untaken_branch() {
+        let program = || {
+            let r = Register::new(1);
+            let mut program = Chunk::new();
+            program.write(BcInstr::LoadTrue { dest: r }, 0);
+            program.write(BcInstr::JumpIfFalse { cond: r, offset: 1 }, 0);
+            program.write(BcInstr::Jump { offset: 1 }, 0);
+            program.write(BcInstr::LoadNil { dest: r }, 1);
+            program.write(RET, 2);
+            program
+        };
+
+        let mut vm = VM::new();
+        assert!(vm.coverage().is_empty());
+        vm.set_coverage(true);
+        assert_eq!(vm.interpret(program()), InterpretResult::Ok);
+        assert_eq!(vm.coverage(), [1, 1, 1, 0, 1]);
+
+        // Loading another program starts counting from zero
+        assert_eq!(vm.interpret(countdown(3.0)), InterpretResult::Ok);
+        assert_eq!(vm.coverage().len(), countdown(3.0).instrs().len());
+        assert_eq!(vm.coverage().last(), Some(&1));
+
+        vm.set_coverage(false);
+        assert_eq!(vm.interpret(program()), InterpretResult::Ok);
+        assert!(vm.coverage().is_empty());
+
+        // Coverage can be enabled once the script finished, for the next one
+        vm.set_coverage(true);
+        assert_eq!(vm.interpret(program()), InterpretResult::Ok);
+        assert_eq!(vm.coverage(), [1, 1, 1, 0, 1]);
+    }
+
+    #[test]
+    fn fused_add_matches_unfused() {
+        let (r0, r1, r2) = (Register::new(0), Register::new(1), Register::new(2));
+        let program = |vm: &mut VM| {
+            let mut program = Chunk::new();
+            let strings = [
+                vm.alloc_string("foo").unwrap(),
+                vm.alloc_string("bar").unwrap(),
+            ];
+            let constants = [Value::Int(1), Value::Number(0.5), strings[0], strings[1]];
+            for (dest, constant) in [r0, r0, r1, r1].into_iter().zip(constants) {
+                let id = program.add_constant(constant);
+                program.write_load_const(r2, id, 0);
+                program.write(
+                    BcInstr::Add {
+                        dest,
+                        a: dest,
+                        b: r2,
+                    },
+                    0,
+                );
+            }
+            program.write(RET, 0);
+            program
+        };
+
+        let mut results = Vec::new();
+        for fuse in [false, true] {
+            let mut vm = VM::new();
+            let mut program = program(&mut vm);
+            if fuse {
+                program.fuse();
+                assert_eq!(program.instrs().len(), 5);
+            }
+
+            vm.load_program(program).unwrap();
+            vm.store(r0, Value::Int(2));
+            let empty = vm.alloc_string("").unwrap();
+            vm.store(r1, empty);
+            assert_eq!(vm.run(), InterpretResult::Ok);
+            results.push((vm.load(r0), vm.load(r1).to_string()));
+        }
+        assert_eq!(results[0], (Value::Number(3.5), "foobar".to_string()));
+        assert_eq!(results[0], results[1]);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn run_bench() {
+        let mut vm = VM::new();
+        let fast = vm.run_bench(countdown(1000.0), 50).unwrap();
+        assert_eq!(vm.load(Register::ret()), Value::Number(0.0));
+
+        // Going from one handler to the next beats stepping through the instrumented loop
+        vm.set_profile(true);
+        let stepped = vm.run_bench(countdown(1000.0), 50).unwrap();
+        assert_eq!(vm.profile_report()[0].1, 50 * 1001);
+        assert!(
+            fast < stepped,
+            "{:?} is not faster than {:?}",
+            fast,
+            stepped
+        );
+        vm.set_profile(false);
+
+        let mut program = Chunk::new();
+        program.write(
+            BcInstr::LoadNil {
+                dest: Register::ret(),
+            },
+            0,
+        );
+        program.write(
+            BcInstr::Neg {
+                dest: Register::ret(),
+                a: Register::ret(),
+            },
+            0,
+        );
+        program.write(RET, 0);
+        assert_eq!(
+            vm.run_bench(program, 100),
+            Err(runtime_error("Operand must be a number", 0))
+        );
+    }
+
+    /// Program decrementing `%r0` from `start` until it is no longer positive
+    fn countdown(start: f64) -> Chunk {
+        let (counter, one, zero, cond) = (
+            Register::ret(),
+            Register::new(1),
+            Register::new(2),
+            Register::new(3),
+        );
+
+        let mut program = Chunk::new();
+        let id = program.add_constant(Value::Number(start));
+        program.write_load_const(counter, id, 0);
+        let id = program.add_constant(Value::Number(1.0));
+        program.write_load_const(one, id, 0);
+        let id = program.add_constant(Value::Number(0.0));
+        program.write_load_const(zero, id, 0);
+        program.write(
+            BcInstr::Gt {
+                dest: cond,
+                a: counter,
+                b: zero,
+            },
+            1,
+        );
+        program.write(BcInstr::JumpIfFalse { cond, offset: 2 }, 1);
+        program.write(
+            BcInstr::Sub {
+                dest: counter,
+                a: counter,
+                b: one,
+            },
+            2,
+        );
+        program.write(BcInstr::Loop { offset: 4 }, 2);
+        program.write(RET, 3);
+
+        program
+    }
+
+    #[test]
+    fn jump_if_false_truthiness() {
+        let cond = Register::new(0);
+        let printed = |v: Value| {
+            let mut program = Chunk::new();
+            program.write(BcInstr::JumpIfFalse { cond, offset: 1 }, 0);
+            program.write(BcInstr::Print { src: cond }, 0);
+            program.write(RET, 0);
+
+            let out = SharedBuf::default();
+            let mut vm = VM::with_sink(Box::new(out.clone()));
+            vm.load_program(program).unwrap();
+            vm.store(cond, v);
+            assert_eq!(vm.run(), InterpretResult::Ok);
+            out.contents()
+        };
+
+        assert_eq!(printed(Value::Nil), "");
+        assert_eq!(printed(Value::Bool(false)), "");
+        assert_eq!(printed(Value::Number(0.0)), "0\n");
+        assert_eq!(printed(Value::Int(0)), "0\n");
+        assert_eq!(printed(Value::Bool(true)), "true\n");
+    }
+
+    #[test]
+    fn instruction_limit() {
+        let mut program = Chunk::new();
+        program.write(BcInstr::Loop { offset: 1 }, 0);
+        program.write(RET, 0);
+
+        let mut vm = VM::new();
+        assert_eq!(
+            vm.interpret_with_limit(program, 1000),
+            InterpretResult::LimitExceeded
+        );
+
+        let finite = || {
+            let mut program = Chunk::new();
+            program.write(
+                BcInstr::LoadTrue {
+                    dest: Register::ret(),
+                },
+                0,
+            );
+            program.write(RET, 0);
+            program
+        };
+        assert_eq!(
+            vm.interpret_with_limit(finite(), 1),
+            InterpretResult::LimitExceeded
+        );
+        assert_eq!(vm.interpret_with_limit(finite(), 2), InterpretResult::Ok);
+        assert_eq!(vm.interpret(finite()), InterpretResult::Ok);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let (r0, r1) = (Register::new(0), Register::new(1));
+        let mut program = Chunk::new();
+        let id = program.add_constant(Value::Number(1.0));
+        program.write_load_const(r0, id, 0);
+        let id = program.add_constant(Value::Number(2.0));
+        program.write_load_const(r1, id, 0);
+        program.write(
+            BcInstr::Add {
+                dest: r0,
+                a: r0,
+                b: r1,
+            },
+            0,
+        );
+        program.write(RET, 0);
+
+        let mut vm = VM::new();
+        vm.set_breakpoint(2);
+        assert_eq!(vm.interpret(program), InterpretResult::BreakpointHit(2));
+        assert_eq!(vm.current_ip(), Some(2));
+        assert_eq!(
+            vm.registers()[..2],
+            [Value::Number(1.0), Value::Number(2.0)]
+        );
+
+        // Stepping runs a single instruction, and resuming runs to the end
+        assert_eq!(vm.step_public(), None);
+        assert_eq!(vm.current_ip(), Some(3));
+        assert_eq!(vm.registers()[0], Value::Number(3.0));
+        assert_eq!(vm.resume(), InterpretResult::Ok);
+        assert_eq!(vm.result(), Value::Number(3.0));
+        assert_eq!(vm.current_ip(), None);
+    }
+
+    #[test]
+    fn breakpoints_only_in_script() {
+        let mut vm = VM::new();
+        let mut body = Chunk::new();
+        body.write(
+            BcInstr::LoadNil {
+                dest: Register::ret(),
+            },
+            1,
+        );
+        body.write(
+            BcInstr::LoadNil {
+                dest: Register::ret(),
+            },
+            1,
+        );
+        body.write(RET, 1);
+        let function = vm.alloc_function("f", 0, body).unwrap();
+
+        let callee = Register::new(1);
+        let mut program = Chunk::new();
+        let id = program.add_constant(function);
+        program.write_load_const(callee, id, 2);
+        program.write(
+            BcInstr::Call {
+                callee,
+                arg_count: 0,
+            },
+            2,
+        );
+        program.write(BcInstr::Ret { src: callee }, 3);
+
+        // The function passes through offset 2 first, but only the script stops there
+        vm.set_breakpoint(2);
+        assert_eq!(vm.interpret(program), InterpretResult::BreakpointHit(2));
+        assert_eq!(vm.frames.len(), 1);
+        assert_eq!(vm.resume(), InterpretResult::Ok);
+    }
+
+    #[test]
+    fn resume_past_breakpoint() {
+        let program = || {
+            let mut program = Chunk::new();
+            program.write(
+                BcInstr::LoadTrue {
+                    dest: Register::ret(),
+                },
+                0,
+            );
+            program.write(RET, 0);
+            program
+        };
+
+        let mut vm = VM::new();
+        vm.set_breakpoint(0);
+        vm.set_breakpoint(1);
+        assert_eq!(vm.interpret(program()), InterpretResult::BreakpointHit(0));
+        assert_eq!(vm.resume(), InterpretResult::BreakpointHit(1));
+        assert_eq!(vm.resume(), InterpretResult::Ok);
+        assert_eq!(vm.result(), Value::Bool(true));
+
+        // Once the program finished there is nothing left to run
+        assert_eq!(vm.resume(), InterpretResult::Ok);
+        assert_eq!(vm.step_public(), Some(InterpretResult::Ok));
+
+        // A new program stops at the breakpoint the last one was paused at
+        assert_eq!(vm.interpret(program()), InterpretResult::BreakpointHit(0));
+        assert_eq!(vm.interpret(program()), InterpretResult::BreakpointHit(0));
+    }
+
+    #[test]
+    fn jump_out_of_bounds() {
+        let mut program = Chunk::new();
+        program.write(BcInstr::Jump { offset: 1 }, 0);
+        program.write(RET, 0);
+
+        let mut vm = VM::new();
+        assert_eq!(
+            vm.interpret(program),
+            runtime_error("Jump out of bounds", 0)
+        );
+
+        let mut program = Chunk::new();
+        program.write(BcInstr::Loop { offset: 2 }, 0);
+
+        assert_eq!(
+            vm.interpret(program),
+            runtime_error("Jump out of bounds", 0)
+        );
+    }
+
+    #[test]
+    fn compare_values() {
+        let (dest, a, b) = (Register::ret(), Register::new(1), Register::new(2));
+        let compare = |instr: BcInstr, lhs: Value, rhs: Value| {
+            let mut program = Chunk::new();
+            program.write(instr, 0);
+            program.write(RET, 0);
+
+            let mut vm = VM::new();
+            vm.load_program(program).unwrap();
+            vm.store(a, lhs);
+            vm.store(b, rhs);
+            match vm.run() {
+                InterpretResult::Ok => Some(vm.load(dest)),
+                _ => None,
+            }
+        };
+
+        let (one, two) = (Value::Number(1.0), Value::Number(2.0));
+        assert_eq!(
+            compare(BcInstr::Lt { dest, a, b }, one, two),
+            Some(Value::Bool(true))
+        );
+        assert_eq!(
+            compare(BcInstr::Le { dest, a, b }, two, two),
+            Some(Value::Bool(true))
+        );
+        assert_eq!(
+            compare(BcInstr::Gt { dest, a, b }, one, two),
+            Some(Value::Bool(false))
+        );
+        assert_eq!(
+            compare(BcInstr::Ge { dest, a, b }, one, two),
+            Some(Value::Bool(false))
+        );
+        assert_eq!(
+            compare(BcInstr::Eq { dest, a, b }, one, one),
+            Some(Value::Bool(true))
+        );
+        assert_eq!(
+            compare(BcInstr::Ne { dest, a, b }, one, two),
+            Some(Value::Bool(true))
+        );
+
+        // Equality between different types is never an error
+        let nil = Value::Nil;
+        assert_eq!(
+            compare(BcInstr::Eq { dest, a, b }, one, nil),
+            Some(Value::Bool(false))
+        );
+        assert_eq!(
+            compare(BcInstr::Ne { dest, a, b }, one, nil),
+            Some(Value::Bool(true))
+        );
+
+        // ...but ordering is
+        assert_eq!(compare(BcInstr::Lt { dest, a, b }, one, nil), None);
     }
 
     /*
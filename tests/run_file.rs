@@ -0,0 +1,35 @@
+use std::process::{Command, Output};
+
+/// Run the `rlox` binary on the script `name` in `tests/scripts`
+fn run_script(name: &str) -> Output {
+    let path = format!("{}/tests/scripts/{}", env!("CARGO_MANIFEST_DIR"), name);
+    Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .arg(path)
+        .output()
+        .expect("Could not run rlox!")
+}
+
+#[test]
+fn run_arithmetic() {
+    let output = run_script("arithmetic.lox");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "-9\n");
+}
+
+#[test]
+fn exit_status() {
+    let output = run_script("compile_error.lox");
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "[line 2] Error at end: Expect expression.\n"
+    );
+
+    let output = run_script("runtime_error.lox");
+    assert_eq!(output.status.code(), Some(70));
+    assert!(output.stdout.is_empty());
+
+    let output = run_script("missing.lox");
+    assert_eq!(output.status.code(), Some(74));
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("Could not read file"));
+}